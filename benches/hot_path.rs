@@ -0,0 +1,120 @@
+//! latency of a single `nanolog!` call by the number and type of its arguments, while a consumer
+//! thread keeps draining the ring buffer, and of a `log` crate call through the facade for
+//! comparison (with the `log` feature)
+//!
+//! usage: cargo bench --bench hot_path [--features log]
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use nanolog_rs::setup_nanolog;
+#[cfg(feature = "log")]
+use nanolog_rs_common::facade::log;
+use nanolog_rs_common::sink::{Encoding, WriterSink};
+use nanolog_rs_proc_macro::nanolog;
+use std::hint::black_box;
+
+const RINGBUF_SIZE: usize = 1024 * 1024;
+
+setup_nanolog!(
+    { crate::RINGBUF_SIZE },
+    ::nanolog_rs_common::nanolog_logger::Spin
+);
+
+/// compresses everything logged into the void, as the log reader thread of an application would
+fn spawn_consumer() {
+    nanolog_internal::setup_consumer(WriterSink::new(std::io::sink(), Encoding::Compressed));
+    std::thread::spawn(|| loop {
+        let n = nanolog_internal::LOG_CONSUMER
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .poll();
+        if n == 0 {
+            std::thread::yield_now();
+        }
+    });
+}
+
+fn hot_path(c: &mut Criterion) {
+    spawn_consumer();
+    let mut logger = nanolog_internal::setup_logger();
+    let mut group = c.benchmark_group("nanolog");
+
+    group.bench_function("no args", |b| b.iter(|| nanolog!(&mut logger, "no args")));
+    let mut i = 0i64;
+    group.bench_function("1 int", |b| {
+        b.iter(|| {
+            i += 1;
+            nanolog!(&mut logger, "%d", black_box(i))
+        })
+    });
+    let f = 101.25;
+    group.bench_function("1 float", |b| {
+        b.iter(|| nanolog!(&mut logger, "%f", black_box(f)))
+    });
+    group.bench_function("int float", |b| {
+        b.iter(|| {
+            i += 1;
+            nanolog!(&mut logger, "%d %f", black_box(i), black_box(f))
+        })
+    });
+    group.bench_function("4 ints", |b| {
+        b.iter(|| {
+            i += 1;
+            nanolog!(
+                &mut logger,
+                "%d %d %d %d",
+                black_box(i),
+                black_box(i),
+                black_box(i),
+                black_box(i)
+            )
+        })
+    });
+    group.bench_function("4 floats", |b| {
+        b.iter(|| {
+            nanolog!(
+                &mut logger,
+                "%f %f %f %f",
+                black_box(f),
+                black_box(f),
+                black_box(f),
+                black_box(f)
+            )
+        })
+    });
+    group.bench_function("8 ints", |b| {
+        b.iter(|| {
+            i += 1;
+            nanolog!(
+                &mut logger,
+                "%d %d %d %d %d %d %d %d",
+                black_box(i),
+                black_box(i),
+                black_box(i),
+                black_box(i),
+                black_box(i),
+                black_box(i),
+                black_box(i),
+                black_box(i)
+            )
+        })
+    });
+    group.finish();
+
+    #[cfg(feature = "log")]
+    {
+        nanolog_internal::setup_log_facade(log::LevelFilter::Info).unwrap();
+        let mut group = c.benchmark_group("log facade");
+        group.bench_function("int float", |b| {
+            b.iter(|| {
+                i += 1;
+                log::info!("{} {}", black_box(i), black_box(f))
+            })
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, hot_path);
+criterion_main!(benches);
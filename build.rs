@@ -1,4 +1,6 @@
-use nanolog_rs_common::{const_fnv1a_hash, Nanolog};
+use nanolog_rs_common::context::CONTEXT_SITES;
+use nanolog_rs_common::facade::FACADE_SITES;
+use nanolog_rs_common::{const_fnv1a_hash, Nanolog, NanologType};
 use proc_macro2::TokenStream;
 use quote::quote;
 use std::collections::HashSet;
@@ -6,7 +8,6 @@ use std::env;
 use std::fs;
 use std::io::Write;
 use std::path::Path;
-use syn;
 use syn::spanned::Spanned;
 use syn::visit::Visit;
 
@@ -16,8 +17,9 @@ fn main() {
     let dest_path = Path::new(&out_dir).join("source_files.rs");
     let mut file = fs::File::create(&dest_path).unwrap();
 
-    let src_dir = Path::new("src/");
-    let files = collect_source_files_into_vec(src_dir);
+    // benches invoke nanolog! too (see setup_nanolog!)
+    let mut files = collect_source_files_into_vec(Path::new("src/"));
+    files.extend(collect_source_files_into_vec(Path::new("benches/")));
     let mut v = vec![];
     for f in files.iter() {
         let content = fs::read_to_string(f).unwrap();
@@ -30,36 +32,7 @@ fn main() {
     }
 
     let tokens = quote! {
-        use std::io::Write;
-        use nanolog_rs_common::compression::NibbleNibble;
-
-        pub trait Compressable{
-            fn compress(&self, writer: &mut impl Write);
-        }
-
-        impl Compressable for (u64, u64){
-            fn compress(&self, writer: &mut impl Write){
-                const MAX_SIZE: usize = 8 + 8 + 1;
-
-                let mut bytes = [0_u8; MAX_SIZE];
-
-                let nb = NibbleNibble::from(*self);
-                bytes[0] = nb.0;
-
-                let (lower_size, upper_size) = nb.get_num_bytes();
-                let lower_size = lower_size.map(|v|v.get()).unwrap_or(8);
-                let upper_size = upper_size.map(|v|v.get()).unwrap_or(8);
-                // bytes[1..1 + lower_size].copy_from_slice(&self.0.to_le_bytes()[..lower_size]);
-                // bytes[1 + lower_size..1 + lower_size + upper_size].copy_from_slice(&self.1.to_le_bytes()[..upper_size]);
-
-                writer.write(&[nb.0]).unwrap();
-                writer.write(&self.0.to_le_bytes()[..lower_size]).unwrap();
-                writer.write(&self.1.to_le_bytes()[..upper_size]).unwrap();
-                // writer.write(&bytes[..upper_size as usize + lower_size as usize + 1]).unwrap();
-            }
-        }
-
-        pub trait NanologLoggable<const F: u64, const L: u32>: Compressable{
+        pub trait NanologLoggable<const F: u64, const L: u32> {
             fn log(self, logger: &mut impl ::nanolog_rs_common::nanolog_logger::Logger);
         }
     };
@@ -78,6 +51,7 @@ fn main() {
             let i = quote::format_ident!("field{}", ind);
             fields.extend(match e {
                 'D' => quote! { #i: i64, },
+                'U' => quote! { #i: u64, },
                 'F' => quote! { #i: f64, },
                 _ => unreachable!(),
             });
@@ -90,20 +64,16 @@ fn main() {
                 #fields
             }
             impl #i{
+                // one argument per format specifier
+                #[allow(clippy::too_many_arguments)]
                 pub fn new(#fields) -> Self{
                     #i{#names}
                 }
             }
-
-            impl Compressable for #i{
-                fn compress(&self, writer: &mut impl Write){
-                    todo!()
-                }
-            }
         };
         writeln!(file, "{}", tokens).unwrap();
     }
-    let mut log_id_map = TokenStream::new();
+    let mut log_sites = TokenStream::new();
 
     for (log_id, invocation) in v.iter().enumerate() {
         let filename = invocation.file_name.as_str();
@@ -119,15 +89,14 @@ fn main() {
                 fn log(self, logger: &mut impl ::nanolog_rs_common::nanolog_logger::Logger){
                     const LOG_ID: u64 = #log_id_u64;
 
-                    let timestamp = ::nanolog_rs_common::get_rdtsc_time();
-                    // let timestamp = ::nanolog_rs_common::get_monotonic_time_micros();
-                    // let timestamp = ::nanolog_rs_common::system_time_to_micros(::std::time::SystemTime::now());
+                    // the clock chosen in setup_nanolog!
+                    let timestamp = <LogClock as ::nanolog_rs_common::clock::Clock>::now();
 
                     logger.write(&LOG_ID.to_ne_bytes());
 
                     logger.write(&timestamp.to_ne_bytes());
 
-                    if (std::mem::size_of::<Self>() > 0){
+                    if std::mem::size_of::<Self>() > 0 {
                         let struct_bytes = unsafe{
                             let ptr: *const Self = &self;
                             let byte_ptr: *const u8 = ptr.cast();
@@ -141,74 +110,80 @@ fn main() {
             }
         };
 
-        log_id_map.extend(quote! {#fmt_literal, });
+        let fields = invocation.nanolog.fields.iter().map(|f| f.name.as_str());
+        log_sites.extend(log_site(
+            fmt_literal,
+            filename,
+            linenum,
+            &invocation.nanolog.arg_types(),
+            &fields.collect::<Vec<_>>(),
+        ));
 
         writeln!(file, "{}", tokens).unwrap();
     }
-    let n = v.len();
-    writeln!(
-        file,
-        "const LOG_LITERALS: [&'static str; {n}] = [{}];",
-        log_id_map
-    )
-    .unwrap();
-
-    let mut log_id_cases = TokenStream::new();
-
-    for (log_id, invocation) in v.iter().enumerate() {
-        let log_id_u64 = log_id as u64;
-
-        let i = quote::format_ident!("Log{}", invocation.nanolog.get_log_type_suffix());
-        log_id_cases.extend(quote! {
-            #log_id_u64 => {
-                const LOG_SIZE: usize = std::mem::size_of::<crate::nanolog_internal::#i>();
-
-                (log_id, timestamp).compress(out);
-
-                out.write(&buf[consumed..consumed + LOG_SIZE]).unwrap();
-                // TODO: impl compression for general types
-                // let log_type = unsafe{&*(buf[consumed..consumed + LOG_SIZE].as_ptr() as *const crate::nanolog_internal::#i)};
-                // log_type.compress(out);
-                // println!("[{}] Fmt literal: {}, log_type: {:?}", timestamp, LOG_LITERALS[#log_id], log_type);
-                consumed += LOG_SIZE;
-            }
-        });
+    // the records of the log crate (with the `log` feature) and of the context come last, see
+    // nanolog_rs_common::facade and nanolog_rs_common::context
+    let facade_sites = match env::var_os("CARGO_FEATURE_LOG") {
+        Some(_) => &FACADE_SITES[..],
+        None => &[],
+    };
+    for site in facade_sites.iter().chain(CONTEXT_SITES.iter()) {
+        log_sites.extend(log_site(&site.fmt, &site.file, site.line, &site.args, &[]));
     }
-
-    let decode_buf = quote! {
-        pub fn decode_buf(out: &mut impl Write, start_instant: &::std::time::Instant, buf: &[u8]) {
-            // TODO: this is not the correct number of bytes - this is the number of bytes before
-            // compression
-            out.write(&buf.len().to_le_bytes());
-            let mut consumed = 0;
-            while !buf[consumed..].is_empty() {
-                let mut bytes = [0u8; 8];
-
-                bytes.copy_from_slice(&buf[consumed..consumed + 8]);
-                consumed += 8;
-                let log_id = u64::from_le_bytes(bytes);
-
-                bytes.copy_from_slice(&buf[consumed..consumed + 8]);
-                consumed += 8;
-                let timestamp = u64::from_le_bytes(bytes);
-
-                match log_id {
-                    #log_id_cases
-                    _ => panic!("unknown log id"),
-                }
-            }
+    let n = v.len() + facade_sites.len() + CONTEXT_SITES.len();
+    let facade_log_id = v.len() as u64;
+    let context_log_id = (v.len() + facade_sites.len()) as u64;
+    let runtime_log_id = n as u64;
+    let facade = (!facade_sites.is_empty()).then(|| {
+        quote! {
+            /// the log ID of the first of `nanolog_rs_common::facade::FACADE_SITES`
+            pub const FACADE_LOG_ID: u64 = #facade_log_id;
         }
+    });
+    let tokens = quote! {
+        pub const LOG_SITES: [::nanolog_rs_common::metadata::LogSite; #n] = [#log_sites];
+        #facade
+        /// the log ID of the first of `nanolog_rs_common::context::CONTEXT_SITES`
+        pub const CONTEXT_LOG_ID: u64 = #context_log_id;
+        /// the log ID of the first site registered at runtime (see `SiteRegistry`)
+        pub const RUNTIME_LOG_ID: u64 = #runtime_log_id;
     };
-    writeln!(file, "{}", decode_buf).unwrap();
+    writeln!(file, "{}", tokens).unwrap();
 
-    // Tell Cargo to rerun this script if any file in src changes
+    // Tell Cargo to rerun this script if any file in src or benches changes
     println!("cargo:rerun-if-changed=src/");
+    println!("cargo:rerun-if-changed=benches/");
     println!("cargo:rerun-if-changed=build.rs");
 }
 
+fn log_site(
+    fmt: &str,
+    file: &str,
+    line: u32,
+    args: &[NanologType],
+    fields: &[&str],
+) -> TokenStream {
+    let args = args.iter().map(|s| match s {
+        NanologType::Int => quote! { ::nanolog_rs_common::NanologType::Int },
+        NanologType::UInt => quote! { ::nanolog_rs_common::NanologType::UInt },
+        NanologType::Float => quote! { ::nanolog_rs_common::NanologType::Float },
+        NanologType::Str => quote! { ::nanolog_rs_common::NanologType::Str },
+    });
+    quote! {
+        ::nanolog_rs_common::metadata::LogSite {
+            fmt: ::std::borrow::Cow::Borrowed(#fmt),
+            file: ::std::borrow::Cow::Borrowed(#file),
+            line: #line,
+            args: ::std::borrow::Cow::Borrowed(&[#(#args),*]),
+            fields: ::std::borrow::Cow::Borrowed(&[#(::std::borrow::Cow::Borrowed(#fields)),*]),
+        },
+    }
+}
+
 fn log_error(s: &str) {
     println!("cargo::error={s}");
 }
+#[allow(dead_code)]
 fn log_warning(s: &str) {
     println!("cargo::warning={s}");
 }
@@ -218,6 +193,7 @@ struct LogInvocation {
     nanolog: Nanolog,
     file_name: String,
     line_num: usize,
+    #[allow(dead_code)]
     s: proc_macro2::Span,
 }
 
@@ -238,8 +214,8 @@ struct MacroVisitor<'file> {
 // println!("{}", nanolog!()) doesn't parse correctly into an AST
 impl<'ast, 'file> syn::visit::Visit<'ast> for MacroVisitor<'file> {
     fn visit_macro(&mut self, m: &'ast syn::Macro) {
-        let macro_ident = m.path.get_ident().unwrap();
-        if macro_ident != "nanolog" {
+        // e.g. log::info!, which has no single ident
+        if !m.path.is_ident("nanolog") {
             self.visit_path(&m.path);
             return;
         }
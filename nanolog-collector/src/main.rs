@@ -0,0 +1,86 @@
+use nanolog_rs_common::clock::AnchorClock;
+use nanolog_rs_common::shm::{registered_processes, AttachedProcess};
+use nanolog_rs_common::sink::{Encoding, Sink, WriterSink};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// consumes the shared memory ring buffers of every application that set up shared memory rings
+/// and writes the compressed logs of each to `<out dir>/<pid>.log`
+///
+/// usage: nanolog-collector [out dir]
+fn main() {
+    let out_dir = PathBuf::from(std::env::args().nth(1).unwrap_or_else(|| ".".to_string()));
+    std::fs::create_dir_all(&out_dir).unwrap();
+
+    let mut processes: HashMap<
+        libc::pid_t,
+        (AttachedProcess, WriterSink<BufWriter<File>>, AnchorClock),
+    > = HashMap::new();
+    // don't keep retrying control blocks we can't make sense of
+    let mut failed = HashSet::new();
+    loop {
+        for pid in registered_processes().unwrap() {
+            if processes.contains_key(&pid) || failed.contains(&pid) {
+                continue;
+            }
+            match AttachedProcess::attach(pid) {
+                Ok(Some(process)) => {
+                    let path = out_dir.join(format!("{pid}.log"));
+                    let mut sink = WriterSink::indexed_file(path, Encoding::Compressed).unwrap();
+                    sink.set_clock(process.clock());
+                    // the clocks are shared by all processes, so the collector can anchor the
+                    // timestamps of each
+                    let clock = AnchorClock::calibrate(process.clock());
+                    println!("attached to {pid}");
+                    processes.insert(pid, (process, sink, clock));
+                }
+                // still being set up
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("failed to attach to {pid}: {e}");
+                    failed.insert(pid);
+                }
+            }
+        }
+
+        let mut total = 0;
+        let mut finished = vec![];
+        for (pid, (process, sink, clock)) in processes.iter_mut() {
+            if let Some(anchor) = clock.poll() {
+                sink.write_anchor(&anchor);
+            }
+            // checked before polling, so everything the process wrote is read by this poll
+            let alive = process.is_alive();
+            match process.poll(sink) {
+                Ok(polled) => {
+                    for seq in polled.detached {
+                        eprintln!("detached ring buffer {seq} of {pid}: corrupted tail");
+                    }
+                    total += polled.bytes;
+                    if !alive && polled.bytes == 0 {
+                        println!("{pid} exited");
+                        finished.push(*pid);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("failed to read the logs of {pid}: {e}");
+                    failed.insert(*pid);
+                    finished.push(*pid);
+                }
+            }
+        }
+        for pid in finished {
+            let (process, _, _) = processes.remove(&pid).unwrap();
+            if !failed.contains(&pid) {
+                process.unlink();
+            }
+        }
+
+        if total == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
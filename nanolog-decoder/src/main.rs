@@ -0,0 +1,272 @@
+use nanolog_rs_common::decoder::{Decoder, Record};
+use nanolog_rs_common::format::{parse_utc_timestamp, OutputFormat, Timestamp};
+use nanolog_rs_common::index::{find_range, index_path, read_index};
+use nanolog_rs_common::merge::Merge;
+use std::io::{self, BufWriter, Write};
+
+const USAGE: &str = "usage: nanolog-decoder [--chunks] [--recover] [--raw] [--merge] \
+                     [--merge-window <turns>] [--format <text|json|logfmt>] [--thread <thread>] [--since <timestamp>] \
+                     [--until <timestamp>] <log file>";
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
+
+/// a bound of `--since` or `--until`
+#[derive(Clone, Copy)]
+enum Bound {
+    /// nanoseconds since the unix epoch
+    Utc(u64),
+    /// a timestamp as recorded
+    Raw(u64),
+}
+
+impl Bound {
+    fn parse(arg: &str) -> Option<Self> {
+        parse_utc_timestamp(arg)
+            .map(Bound::Utc)
+            .or_else(|| arg.parse().ok().map(Bound::Raw))
+    }
+}
+
+/// the raw timestamp of `bound`, UTC is converted with the first clock anchor of the file (see
+/// `ClockAnchor`)
+fn raw_bound(input: &[u8], bound: Option<Bound>) -> io::Result<Option<u64>> {
+    let nanos = match bound {
+        None => return Ok(None),
+        Some(Bound::Raw(timestamp)) => return Ok(Some(timestamp)),
+        Some(Bound::Utc(nanos)) => nanos,
+    };
+    let mut decoder = Decoder::new(input)?.recover();
+    loop {
+        if let Some(timestamp) = decoder.raw_timestamp(nanos) {
+            return Ok(Some(timestamp));
+        }
+        if decoder.next_chunk().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the timestamps can't be converted from UTC (the file has no clock anchor), \
+                 --since and --until need raw timestamps",
+            ));
+        }
+    }
+}
+
+/// the records of the file in the order they were written, or merged by timestamp
+enum Records<'a> {
+    Decoder(Decoder<'a>),
+    Merge(Merge<'a>),
+}
+
+impl<'a> Records<'a> {
+    fn next(&mut self) -> Option<std::io::Result<Record>> {
+        match self {
+            Records::Decoder(decoder) => decoder.next(),
+            Records::Merge(merge) => merge.next(),
+        }
+    }
+
+    fn decoder(&self) -> &Decoder<'a> {
+        match self {
+            Records::Decoder(decoder) => decoder,
+            Records::Merge(merge) => merge.decoder(),
+        }
+    }
+}
+
+/// formats a compressed log file, one line per record: `<timestamp> [<thread>] <file>:<line>
+/// <message>`
+/// - the thread is its name (or its stream if it has none), it is left out for the streams
+///   without a thread registration (e.g. recovered ring buffers)
+/// - the context pushed on the thread's logger (see `context`) follows the message as
+///   `key=value` pairs
+/// - timestamps are printed in UTC once the file pairs them with the wall-clock time (see
+///   `ClockAnchor`), `--raw` prints the timestamps as recorded
+/// - with `--chunks`, lists the chunks of the file instead: `<offset> <stream> <records> <bytes>`
+/// - with `--recover`, corrupted chunks are reported and skipped instead of ending the output
+/// - with `--merge`, the records of all threads are merged into a single timeline (see `Merge`),
+///   lines are tagged with the stream for threads that aren't registered. The order is best
+///   effort, the records that still came out of order are reported at the end, `--merge-window`
+///   holds records back for more turns of the log reader (2 by default) to avoid them
+/// - `--format json` outputs JSON Lines (see `write_json`) and `--format logfmt` logfmt (see
+///   `write_logfmt`) instead, with the named fields of `nanolog!` as keys of their own
+/// - `--thread` only outputs the records of a thread, given by its name or stream
+/// - `--since` and `--until` only output the records in the time range (inclusive), given as RFC
+///   3339 timestamps (e.g. `2026-10-19T01:08:15Z`) or as raw timestamps. The index of the file
+///   (`<log file>.idx`, if there is one) is used to only decode the relevant part of it
+/// - errors are reported on stderr with a non-zero exit code, a closed stdout (e.g. when piped into
+///   `head`) just ends the output
+///
+/// usage: nanolog-decoder [--chunks] [--recover] [--raw] [--merge] [--merge-window <turns>]
+///        [--format <text|json|logfmt>] [--thread <thread>] [--since <timestamp>] [--until <timestamp>] <log file>
+fn main() {
+    if let Err(e) = run() {
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run() -> io::Result<()> {
+    let mut chunks = false;
+    let mut recover = false;
+    let mut raw = false;
+    let mut merge = false;
+    let mut window = None;
+    let mut format = OutputFormat::Text;
+    let mut thread = None;
+    let mut since = None;
+    let mut until = None;
+    let mut path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut timestamp = || {
+            args.next()
+                .and_then(|ts| Bound::parse(&ts))
+                .unwrap_or_else(|| usage())
+        };
+        match arg.as_str() {
+            "--chunks" => chunks = true,
+            "--recover" => recover = true,
+            "--raw" => raw = true,
+            "--merge" => merge = true,
+            "--merge-window" => {
+                merge = true;
+                window = Some(
+                    args.next()
+                        .and_then(|turns| turns.parse().ok())
+                        .unwrap_or_else(|| usage()),
+                );
+            }
+            "--format" => {
+                format = match args.next().map(|f| f.parse()) {
+                    Some(Ok(format)) => format,
+                    Some(Err(e)) => {
+                        eprintln!("{e}");
+                        usage()
+                    }
+                    None => usage(),
+                }
+            }
+            "--thread" => thread = Some(args.next().unwrap_or_else(|| usage())),
+            "--since" => since = Some(timestamp()),
+            "--until" => until = Some(timestamp()),
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => usage(),
+        }
+    }
+    let Some(path) = path else { usage() };
+    let input = std::fs::read(&path)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to read {path}: {e}")))?;
+    let failed_to_decode =
+        |e: io::Error| io::Error::new(e.kind(), format!("failed to decode {path}: {e}"));
+    let mut decoder = Decoder::new(&input).map_err(failed_to_decode)?;
+    if recover {
+        decoder = decoder.recover();
+    }
+    let since = raw_bound(&input, since).map_err(failed_to_decode)?;
+    let until = raw_bound(&input, until).map_err(failed_to_decode)?;
+
+    if since.is_some() || until.is_some() {
+        match read_index(index_path(&path)) {
+            Ok(entries) => {
+                let (start, end) = find_range(
+                    &entries,
+                    decoder.offset() as u64,
+                    input.len() as u64,
+                    since,
+                    until,
+                );
+                decoder.seek(start as usize, end as usize);
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("failed to read the index of {path}, decoding all of it: {e}"),
+        }
+    }
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    if chunks {
+        while let Some(chunk) = decoder.next_chunk() {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    eprintln!("failed to decode {path}: {e}");
+                    continue;
+                }
+            };
+            let header = chunk.header;
+            writeln!(
+                out,
+                "{} {} {} {}",
+                chunk.offset, header.stream, header.records, header.len
+            )?;
+        }
+        return out.flush();
+    }
+
+    let in_range = |ts: u64| since.is_none_or(|s| ts >= s) && until.is_none_or(|u| ts <= u);
+    let mut records = if merge {
+        let merge = Merge::new(decoder);
+        Records::Merge(match window {
+            Some(turns) => merge.with_window(turns),
+            None => merge,
+        })
+    } else {
+        Records::Decoder(decoder)
+    };
+    while let Some(record) = records.next() {
+        let record = match record {
+            Ok(record) => record,
+            // without --recover, the decoder stops at the first error
+            Err(e) => {
+                eprintln!("failed to decode {path}: {e}");
+                continue;
+            }
+        };
+        if !in_range(record.timestamp) {
+            continue;
+        }
+        let decoder = records.decoder();
+        let stream = record.stream.to_string();
+        let registered = decoder.thread(record.stream);
+        if let Some(thread) = thread.as_ref() {
+            if *thread != stream && registered.is_none_or(|t| t.name != *thread) {
+                continue;
+            }
+        }
+        let label = match registered {
+            Some(t) => Some(t.label(record.stream)),
+            None => merge.then_some(stream),
+        };
+        // sites can be registered along the way
+        let site = &decoder.sites()[record.log_id as usize];
+        let timestamp = match decoder.timestamp_nanos(record.timestamp).filter(|_| !raw) {
+            Some(nanos) => Timestamp::Utc(nanos),
+            None => Timestamp::Raw(record.timestamp),
+        };
+        format.write(
+            &mut out,
+            timestamp,
+            label.as_deref(),
+            site,
+            &record.values,
+            &record.context,
+        )?;
+    }
+    out.flush()?;
+    if let Records::Merge(merge) = &records {
+        if merge.out_of_order() > 0 {
+            eprintln!(
+                "{} records are out of order, a larger --merge-window might avoid that",
+                merge.out_of_order()
+            );
+        }
+    }
+    let skipped = records.decoder().skipped();
+    if skipped > 0 {
+        eprintln!("skipped {skipped} records without a timestamp");
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+//! throughput of the log reader side: encoding the chunks read from the ring buffers and decoding
+//! them again
+//!
+//! usage: cargo bench -p nanolog-rs-common --bench codec [--features lz4]
+
+mod workload;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use nanolog_rs_common::decoder::Decoder;
+use nanolog_rs_common::sink::{Encoder, Encoding, MemorySink, Sink};
+use workload::{Pattern, Workload, CHUNK_RECORDS, RECORDS};
+
+/// (name, encoding, recent ids)
+const ENCODINGS: &[(&str, Encoding, bool)] = &[
+    ("compressed", Encoding::Compressed, false),
+    ("recent ids", Encoding::Compressed, true),
+    ("text", Encoding::Text, false),
+    #[cfg(feature = "lz4")]
+    ("lz4", Encoding::Lz4, false),
+];
+
+fn encoder(encoding: Encoding, recent_ids: bool) -> Encoder {
+    let encoder = Encoder::new(encoding);
+    if recent_ids {
+        encoder.with_recent_ids()
+    } else {
+        encoder
+    }
+}
+
+fn codec(c: &mut Criterion) {
+    let workloads = [
+        Workload::new("hot loop", 64, Pattern::HotLoop { hot: 4 }),
+        Workload::new("zipf", 40, Pattern::Zipf),
+        Workload::new("uniform", 250, Pattern::Uniform),
+    ];
+    let records = (RECORDS / CHUNK_RECORDS * CHUNK_RECORDS) as u64;
+
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Elements(records));
+    for workload in workloads.iter() {
+        let chunks = workload.chunks();
+        for &(name, encoding, recent_ids) in ENCODINGS {
+            let mut encoder = encoder(encoding, recent_ids);
+            let mut out = vec![];
+            group.bench_function(BenchmarkId::new(name, workload.name), |b| {
+                b.iter(|| {
+                    out.clear();
+                    for (stream, chunk) in chunks.iter() {
+                        encoder
+                            .encode(&mut out, *stream, &workload.sites, chunk)
+                            .unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(records));
+    for workload in workloads.iter() {
+        let chunks = workload.chunks();
+        for &(name, encoding, recent_ids) in ENCODINGS {
+            if encoding == Encoding::Text {
+                continue;
+            }
+            let mut sink = MemorySink::new(encoder(encoding, recent_ids));
+            for (stream, chunk) in chunks.iter() {
+                sink.write_chunk(*stream, &workload.sites, chunk).unwrap();
+            }
+            let contents = sink.contents();
+            group.bench_function(BenchmarkId::new(name, workload.name), |b| {
+                b.iter(|| {
+                    let decoded = Decoder::new(&contents).unwrap().count();
+                    assert_eq!(decoded as u64, records);
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, codec);
+criterion_main!(benches);
@@ -0,0 +1,64 @@
+//! bytes per record of each encoding on a few synthetic but realistic workloads
+//!
+//! usage: cargo bench -p nanolog-rs-common --bench compression_ratio [--features lz4]
+
+mod workload;
+
+use nanolog_rs_common::sink::{Encoder, Encoding, MemorySink, Sink};
+use workload::{Pattern, Workload, CHUNK_RECORDS, RECORDS};
+
+fn encoded_size(workload: &Workload, chunks: &[(u64, Vec<u8>)], encoder: Encoder) -> usize {
+    let mut sink = MemorySink::new(encoder);
+    for (stream, chunk) in chunks {
+        sink.write_chunk(*stream, &workload.sites, chunk).unwrap();
+    }
+    sink.contents().len()
+}
+
+fn main() {
+    let workloads = [
+        Workload::new("hot loop", 64, Pattern::HotLoop { hot: 4 }),
+        Workload::new("zipf", 40, Pattern::Zipf),
+        Workload::new("uniform", 250, Pattern::Uniform),
+    ];
+    // (name, encoding, recent ids)
+    let encoders = [
+        ("compressed", Encoding::Compressed, false),
+        ("recent ids", Encoding::Compressed, true),
+        #[cfg(feature = "lz4")]
+        ("lz4", Encoding::Lz4, false),
+        #[cfg(feature = "lz4")]
+        ("lz4 + recent ids", Encoding::Lz4, true),
+    ];
+
+    println!(
+        "{:<10} {:<18} {:>14} {:>8}",
+        "workload", "encoding", "bytes/record", "ratio"
+    );
+    for workload in workloads.iter() {
+        let chunks = workload.chunks();
+        let raw = chunks.iter().map(|(_, c)| c.len()).sum::<usize>();
+        let records = (RECORDS / CHUNK_RECORDS * CHUNK_RECORDS) as f64;
+        println!(
+            "{:<10} {:<18} {:>14.2} {:>8.2}",
+            workload.name,
+            "raw",
+            raw as f64 / records,
+            1.0
+        );
+        for (name, encoding, recent_ids) in encoders {
+            let mut encoder = Encoder::new(encoding);
+            if recent_ids {
+                encoder = encoder.with_recent_ids();
+            }
+            let size = encoded_size(workload, &chunks, encoder);
+            println!(
+                "{:<10} {:<18} {:>14.2} {:>8.2}",
+                workload.name,
+                name,
+                size as f64 / records,
+                raw as f64 / size as f64
+            );
+        }
+    }
+}
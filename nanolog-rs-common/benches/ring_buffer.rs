@@ -0,0 +1,77 @@
+//! throughput of the ring buffer between a logger thread and the log reader, by wait strategy
+//!
+//! usage: cargo bench -p nanolog-rs-common --bench ring_buffer
+
+use criterion::measurement::WallTime;
+use criterion::{criterion_group, criterion_main, BenchmarkGroup, Criterion, Throughput};
+use nanolog_rs_common::nanolog_logger::{
+    create_reader_writer_pair, LogReader, Logger, Panic, SharedRingBufferWriter, Spin,
+};
+use std::time::Instant;
+
+const N: usize = 1 << 16;
+/// the size of a record with two arguments (log ID, timestamp, 2 args)
+const RECORD: [u8; 32] = [7; 32];
+/// records written per iteration, fills half of the ring buffer
+const BATCH: usize = N / 2 / RECORD.len();
+
+/// the writer fills half of the ring buffer, then the reader empties it, on a single thread
+/// - the writer never has to wait, so any wait strategy works
+fn same_thread<W>(group: &mut BenchmarkGroup<WallTime>, name: &str)
+where
+    SharedRingBufferWriter<N, W>: Logger,
+{
+    let (mut reader, mut writer) = create_reader_writer_pair::<N, W>();
+    let mut buf = vec![0; N];
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            for _ in 0..BATCH {
+                writer.write(&RECORD);
+                writer.commit_write();
+            }
+            assert_eq!(reader.read(&mut buf), BATCH * RECORD.len());
+            reader.commit_read();
+        })
+    });
+}
+
+/// a writer and a reader thread running concurrently
+/// - only `Spin` waits for the reader when the ring buffer is full, `Panic` would panic
+fn cross_thread(group: &mut BenchmarkGroup<WallTime>) {
+    let (reader, mut writer) = create_reader_writer_pair::<N, Spin>();
+    let mut reader = Some(reader);
+    group.bench_function("spin/cross thread", |b| {
+        b.iter_custom(|iters| {
+            let bytes = iters as usize * BATCH * RECORD.len();
+            let mut r = reader.take().unwrap();
+            let start = Instant::now();
+            let consumer = std::thread::spawn(move || {
+                let mut buf = vec![0; N];
+                let mut read = 0;
+                while read < bytes {
+                    read += r.read(&mut buf);
+                    r.commit_read();
+                }
+                r
+            });
+            for _ in 0..iters as usize * BATCH {
+                writer.write(&RECORD);
+                writer.commit_write();
+            }
+            reader = Some(consumer.join().unwrap());
+            start.elapsed()
+        })
+    });
+}
+
+fn ring_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ring buffer");
+    group.throughput(Throughput::Bytes((BATCH * RECORD.len()) as u64));
+    same_thread::<Spin>(&mut group, "spin/same thread");
+    same_thread::<Panic>(&mut group, "panic/same thread");
+    cross_thread(&mut group);
+    group.finish();
+}
+
+criterion_group!(benches, ring_buffer);
+criterion_main!(benches);
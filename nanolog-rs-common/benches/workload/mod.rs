@@ -0,0 +1,124 @@
+//! synthetic but realistic ring buffer contents shared by the benches
+
+use nanolog_rs_common::metadata::LogSite;
+use nanolog_rs_common::NanologType;
+use std::borrow::Cow;
+
+pub const RECORDS: usize = 200_000;
+pub const CHUNK_RECORDS: usize = 512;
+const STREAMS: u64 = 2;
+
+/// a deterministic xorshift, so runs are comparable
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// which log site logs next
+pub enum Pattern {
+    /// a loop over `hot` sites makes up 90% of the records, the rest are spread over the others
+    HotLoop { hot: usize },
+    /// the frequency of a site is inversely proportional to its rank
+    Zipf,
+    /// every site is as likely
+    Uniform,
+}
+
+pub struct Workload {
+    pub name: &'static str,
+    pub sites: Vec<LogSite>,
+    pattern: Pattern,
+}
+
+impl Workload {
+    pub fn new(name: &'static str, sites: usize, pattern: Pattern) -> Self {
+        // a mix of the argument lists log statements tend to have
+        let args: [&[NanologType]; 4] = [
+            &[],
+            &[NanologType::Int],
+            &[NanologType::Int, NanologType::Float],
+            &[NanologType::Int, NanologType::Int, NanologType::Float],
+        ];
+        let sites = (0..sites)
+            .map(|i| LogSite {
+                fmt: Cow::Owned(format!("site {i}")),
+                file: Cow::Borrowed("src/main.rs"),
+                line: i as u32,
+                args: Cow::Borrowed(args[i % args.len()]),
+                fields: Cow::Borrowed(&[]),
+            })
+            .collect();
+        Self {
+            name,
+            sites,
+            pattern,
+        }
+    }
+
+    /// the ring buffer contents of each chunk, in the order the streams write them
+    pub fn chunks(&self) -> Vec<(u64, Vec<u8>)> {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let zipf = (0..self.sites.len())
+            .scan(0.0, |total, k| {
+                *total += 1.0 / (k + 1) as f64;
+                Some(*total)
+            })
+            .collect::<Vec<_>>();
+        let mut timestamps = [1u64 << 40; STREAMS as usize];
+        let mut chunks = vec![];
+        for (n, seq) in (0..RECORDS / CHUNK_RECORDS).zip(0u64..) {
+            let stream = seq % STREAMS;
+            let mut chunk = vec![];
+            for i in 0..CHUNK_RECORDS {
+                let log_id = match self.pattern {
+                    Pattern::HotLoop { hot } if rng.below(10) != 0 => i % hot,
+                    Pattern::HotLoop { hot } => {
+                        hot + rng.below((self.sites.len() - hot) as u64) as usize
+                    }
+                    Pattern::Zipf => {
+                        let x =
+                            (rng.below(1 << 20) as f64 / (1 << 20) as f64) * zipf.last().unwrap();
+                        zipf.partition_point(|total| *total < x)
+                    }
+                    Pattern::Uniform => rng.below(self.sites.len() as u64) as usize,
+                };
+                let ts = &mut timestamps[stream as usize];
+                *ts += 20 + rng.below(500);
+                chunk.extend_from_slice(&(log_id as u64).to_ne_bytes());
+                chunk.extend_from_slice(&ts.to_ne_bytes());
+
+                let sequence = (n * CHUNK_RECORDS + i) as i64;
+                for (j, arg) in self.sites[log_id].args.iter().enumerate() {
+                    match (arg, j) {
+                        // e.g. an order ID or a counter
+                        (NanologType::Int, 0) => chunk.extend_from_slice(&sequence.to_ne_bytes()),
+                        // e.g. a quantity
+                        (NanologType::Int, _) => {
+                            chunk.extend_from_slice(&(rng.below(1000) as i64).to_ne_bytes())
+                        }
+                        // e.g. a price on a tick grid
+                        (NanologType::Float, _) => {
+                            let price = 100.0 + rng.below(40) as f64 * 0.25;
+                            chunk.extend_from_slice(&price.to_ne_bytes())
+                        }
+                        (NanologType::UInt | NanologType::Str, _) => {
+                            unreachable!("the sites only take i64s and f64s")
+                        }
+                    }
+                }
+            }
+            chunks.push((stream, chunk));
+        }
+        chunks
+    }
+}
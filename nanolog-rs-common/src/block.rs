@@ -0,0 +1,68 @@
+use crate::checksum::crc32c;
+use crate::compression::CHUNK_MARKER;
+use std::io::{self, Write};
+
+/// the size of the frame in front of every block
+pub const BLOCK_HEADER_SIZE: usize = 16;
+
+/// lz4 compress a whole chunk, which exploits repetition across records that nibble packing can't
+/// - framed as [chunk marker][compressed len: u32][uncompressed len: u32][crc32c of the uncompressed
+///   chunk: u32] followed by the lz4 block
+pub fn write_block(out: &mut impl Write, chunk: &[u8]) -> io::Result<()> {
+    let compressed = lz4_flex::block::compress(chunk);
+    out.write_all(&CHUNK_MARKER)?;
+    out.write_all(&(compressed.len() as u32).to_le_bytes())?;
+    out.write_all(&(chunk.len() as u32).to_le_bytes())?;
+    out.write_all(&crc32c(chunk).to_le_bytes())?;
+    out.write_all(&compressed)
+}
+
+/// inverse of `write_block`, returns the uncompressed chunk
+pub fn read_block(input: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "truncated block");
+
+    let (header, rest) = input
+        .split_first_chunk::<BLOCK_HEADER_SIZE>()
+        .ok_or_else(truncated)?;
+    if header[..4] != CHUNK_MARKER {
+        return Err(invalid("missing block marker"));
+    }
+    let field = |i: usize| u32::from_le_bytes(header[i * 4..i * 4 + 4].try_into().unwrap());
+    let (compressed_len, len, checksum) = (field(1) as usize, field(2) as usize, field(3));
+    let (compressed, rest) = rest
+        .split_at_checked(compressed_len)
+        .ok_or_else(truncated)?;
+    // lz4 can't compress by more than a factor of 255, don't allocate whatever a corrupted length
+    // asks for
+    if len > compressed_len.saturating_mul(255) + 16 {
+        return Err(invalid("corrupted block length"));
+    }
+
+    let chunk = lz4_flex::block::decompress(compressed, len)
+        .map_err(|e| invalid(&format!("corrupted block: {e}")))?;
+    if chunk.len() != len || crc32c(&chunk) != checksum {
+        return Err(invalid("block checksum mismatch"));
+    }
+    *input = rest;
+    Ok(chunk)
+}
+
+#[test]
+fn block_round_trip() {
+    let chunk = (0..4096u32)
+        .flat_map(|i| (i % 7).to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut out = vec![];
+    write_block(&mut out, &chunk).unwrap();
+    assert!(out.len() < chunk.len() / 4);
+
+    let mut input = out.as_slice();
+    assert_eq!(read_block(&mut input).unwrap(), chunk);
+    assert!(input.is_empty());
+
+    let last = out.len() - 1;
+    out[last] ^= 1;
+    assert!(read_block(&mut out.as_slice()).is_err());
+    assert!(read_block(&mut &out[..last]).is_err());
+}
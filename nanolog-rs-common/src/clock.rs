@@ -0,0 +1,385 @@
+use crate::compression::ChunkHeader;
+use crate::get_rdtsc_time;
+use libc::{
+    clock_gettime, clockid_t, timespec, CLOCK_MONOTONIC_COARSE, CLOCK_MONOTONIC_RAW,
+    CLOCK_REALTIME, CLOCK_REALTIME_COARSE,
+};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// anchors are written as chunks of this stream, which no ring buffer uses
+pub const ANCHOR_STREAM: u64 = u64::MAX;
+/// how long a TSC clock is measured against the system clock at startup
+pub const CALIBRATION_TIME: Duration = Duration::from_millis(20);
+/// how often the log reader writes an anchor, so drift between the TSC and the system clock
+/// doesn't add up
+pub const ANCHOR_INTERVAL: Duration = Duration::from_secs(1);
+
+fn clock_nanos(clock: clockid_t) -> u64 {
+    let mut ts = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    if unsafe { clock_gettime(clock, &mut ts) } != 0 {
+        panic!("Failed to get the time of clock {clock}");
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// where the timestamps of records come from, chosen in `setup_nanolog!` and recorded in the
+/// header of a log file so the decoder knows how to interpret them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ClockKind {
+    /// records aren't timestamped (every timestamp is 0)
+    None = 0,
+    /// TSC ticks, see `calibrate`
+    Rdtsc = 1,
+    /// TSC ticks, waits for the preceding instructions to execute
+    Rdtscp = 2,
+    /// nanoseconds of CLOCK_MONOTONIC_RAW
+    MonotonicRaw = 3,
+    /// nanoseconds since the unix epoch
+    Realtime = 4,
+    /// nanoseconds of CLOCK_MONOTONIC_COARSE (a few milliseconds of resolution, but cheaper)
+    MonotonicCoarse = 5,
+    /// nanoseconds of CLOCK_REALTIME_COARSE
+    RealtimeCoarse = 6,
+}
+
+impl ClockKind {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        [
+            Self::None,
+            Self::Rdtsc,
+            Self::Rdtscp,
+            Self::MonotonicRaw,
+            Self::Realtime,
+            Self::MonotonicCoarse,
+            Self::RealtimeCoarse,
+        ]
+        .into_iter()
+        .find(|kind| *kind as u32 == value)
+    }
+
+    /// read the clock outside of the hot path (the `Clock` types are what loggers use)
+    pub fn now(self) -> u64 {
+        match self {
+            Self::None => NoClock::now(),
+            Self::Rdtsc => Rdtsc::now(),
+            Self::Rdtscp => Rdtscp::now(),
+            Self::MonotonicRaw => MonotonicRaw::now(),
+            Self::Realtime => Realtime::now(),
+            Self::MonotonicCoarse => MonotonicCoarse::now(),
+            Self::RealtimeCoarse => RealtimeCoarse::now(),
+        }
+    }
+
+    fn is_tsc(self) -> bool {
+        matches!(self, Self::Rdtsc | Self::Rdtscp)
+    }
+
+    /// whether the timestamps need anchors to be turned into wall-clock time
+    pub fn needs_anchors(self) -> bool {
+        !matches!(self, Self::None | Self::Realtime | Self::RealtimeCoarse)
+    }
+
+    /// nanoseconds since the unix epoch (UTC) of `timestamp`, given the latest anchor
+    pub fn utc_nanos(self, anchor: Option<&ClockAnchor>, timestamp: u64) -> Option<u64> {
+        match self {
+            Self::None => None,
+            Self::Realtime | Self::RealtimeCoarse => Some(timestamp),
+            _ => anchor?.nanos(timestamp),
+        }
+    }
+
+    /// the timestamp at `nanos` since the unix epoch, the inverse of `utc_nanos`
+    pub fn timestamp(self, anchor: Option<&ClockAnchor>, nanos: u64) -> Option<u64> {
+        match self {
+            Self::None => None,
+            Self::Realtime | Self::RealtimeCoarse => Some(nanos),
+            _ => anchor?.ticks(nanos),
+        }
+    }
+}
+
+/// a timestamp source for loggers, see `ClockKind` for what each one measures
+pub trait Clock {
+    const KIND: ClockKind;
+    fn now() -> u64;
+}
+
+pub struct NoClock;
+impl Clock for NoClock {
+    const KIND: ClockKind = ClockKind::None;
+    #[inline(always)]
+    fn now() -> u64 {
+        0
+    }
+}
+
+pub struct Rdtsc;
+impl Clock for Rdtsc {
+    const KIND: ClockKind = ClockKind::Rdtsc;
+    #[inline(always)]
+    fn now() -> u64 {
+        get_rdtsc_time()
+    }
+}
+
+pub struct Rdtscp;
+impl Clock for Rdtscp {
+    const KIND: ClockKind = ClockKind::Rdtscp;
+    #[inline(always)]
+    #[cfg(target_arch = "x86_64")]
+    fn now() -> u64 {
+        let mut aux = 0;
+        unsafe { core::arch::x86_64::__rdtscp(&mut aux) }
+    }
+
+    /// falls back to `Rdtsc` (which falls back to `CLOCK_MONOTONIC_RAW`)
+    #[inline(always)]
+    #[cfg(not(target_arch = "x86_64"))]
+    fn now() -> u64 {
+        get_rdtsc_time()
+    }
+}
+
+pub struct MonotonicRaw;
+impl Clock for MonotonicRaw {
+    const KIND: ClockKind = ClockKind::MonotonicRaw;
+    #[inline(always)]
+    fn now() -> u64 {
+        clock_nanos(CLOCK_MONOTONIC_RAW)
+    }
+}
+
+pub struct Realtime;
+impl Clock for Realtime {
+    const KIND: ClockKind = ClockKind::Realtime;
+    #[inline(always)]
+    fn now() -> u64 {
+        clock_nanos(CLOCK_REALTIME)
+    }
+}
+
+pub struct MonotonicCoarse;
+impl Clock for MonotonicCoarse {
+    const KIND: ClockKind = ClockKind::MonotonicCoarse;
+    #[inline(always)]
+    fn now() -> u64 {
+        clock_nanos(CLOCK_MONOTONIC_COARSE)
+    }
+}
+
+pub struct RealtimeCoarse;
+impl Clock for RealtimeCoarse {
+    const KIND: ClockKind = ClockKind::RealtimeCoarse;
+    #[inline(always)]
+    fn now() -> u64 {
+        clock_nanos(CLOCK_REALTIME_COARSE)
+    }
+}
+
+/// read `reference` and `clock` as close together as possible
+/// - returns (`clock`, nanoseconds of `reference`), `clock` is read halfway through the tightest
+///   of a few attempts
+fn paired_reading(clock: ClockKind, reference: clockid_t) -> (u64, u64) {
+    (0..5)
+        .map(|_| {
+            let before = clock.now();
+            let nanos = clock_nanos(reference);
+            let after = clock.now();
+            (
+                after.wrapping_sub(before),
+                before + after.wrapping_sub(before) / 2,
+                nanos,
+            )
+        })
+        .min_by_key(|(spread, _, _)| *spread)
+        .map(|(_, tsc, nanos)| (tsc, nanos))
+        .unwrap()
+}
+
+/// measure the frequency (ticks per second) of `clock` against CLOCK_MONOTONIC_RAW over `duration`
+pub fn calibrate(clock: ClockKind, duration: Duration) -> u64 {
+    let (tsc_start, start) = paired_reading(clock, CLOCK_MONOTONIC_RAW);
+    std::thread::sleep(duration);
+    let (tsc_end, end) = paired_reading(clock, CLOCK_MONOTONIC_RAW);
+    (tsc_end.wrapping_sub(tsc_start) as u128 * 1_000_000_000 / (end - start).max(1) as u128) as u64
+}
+
+/// pairs a reading of the clock records are timestamped with with the wall-clock time
+/// (CLOCK_REALTIME), which lets the decoder turn the timestamps of records into UTC
+/// - written as a chunk of `ANCHOR_STREAM` holding [ticks: u64][realtime nanos: u64][ticks per
+///   second: u64]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockAnchor {
+    /// the reading of the clock
+    pub ticks: u64,
+    /// nanoseconds since the unix epoch
+    pub realtime_nanos: u64,
+    pub ticks_per_sec: u64,
+}
+
+impl ClockAnchor {
+    pub const SIZE: usize = 24;
+
+    pub fn now(clock: ClockKind, ticks_per_sec: u64) -> Self {
+        let (ticks, realtime_nanos) = paired_reading(clock, CLOCK_REALTIME);
+        Self {
+            ticks,
+            realtime_nanos,
+            ticks_per_sec,
+        }
+    }
+
+    /// nanoseconds since the unix epoch at `timestamp`, which can be before or after the anchor
+    pub fn nanos(&self, timestamp: u64) -> Option<u64> {
+        if self.ticks_per_sec == 0 {
+            return None;
+        }
+        let ticks = timestamp as i128 - self.ticks as i128;
+        let nanos =
+            self.realtime_nanos as i128 + ticks * 1_000_000_000 / self.ticks_per_sec as i128;
+        u64::try_from(nanos).ok()
+    }
+
+    /// the reading of the clock at `nanos` since the unix epoch (the inverse of `nanos`)
+    /// - clamped to the range of the clock
+    pub fn ticks(&self, nanos: u64) -> Option<u64> {
+        if self.ticks_per_sec == 0 {
+            return None;
+        }
+        let nanos = nanos as i128 - self.realtime_nanos as i128;
+        let ticks = self.ticks as i128 + nanos * self.ticks_per_sec as i128 / 1_000_000_000;
+        Some(ticks.clamp(0, u64::MAX as i128) as u64)
+    }
+
+    /// write the anchor as a chunk (see `ChunkHeader`)
+    pub fn write_chunk(&self, out: &mut impl Write) -> io::Result<()> {
+        let mut payload = [0; Self::SIZE];
+        for (i, field) in [self.ticks, self.realtime_nanos, self.ticks_per_sec]
+            .into_iter()
+            .enumerate()
+        {
+            payload[i * 8..i * 8 + 8].copy_from_slice(&field.to_le_bytes());
+        }
+        ChunkHeader::new(ANCHOR_STREAM, 0, &payload).write(out)?;
+        out.write_all(&payload)
+    }
+
+    /// the inverse of `write_chunk`, from the chunk's payload
+    pub fn from_payload(payload: &[u8]) -> io::Result<Self> {
+        let payload: &[u8; Self::SIZE] = payload
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "anchor of the wrong size"))?;
+        let field = |i: usize| u64::from_le_bytes(payload[i * 8..i * 8 + 8].try_into().unwrap());
+        Ok(Self {
+            ticks: field(0),
+            realtime_nanos: field(1),
+            ticks_per_sec: field(2),
+        })
+    }
+}
+
+/// hands out an anchor every `ANCHOR_INTERVAL`, for the log reader to write along with the logs
+/// - clocks that don't need anchors (see `ClockKind::needs_anchors`) never get any
+pub struct AnchorClock {
+    clock: ClockKind,
+    ticks_per_sec: u64,
+    next: Instant,
+}
+
+impl AnchorClock {
+    /// blocks for `CALIBRATION_TIME` for TSC clocks, the others count nanoseconds
+    pub fn calibrate(clock: ClockKind) -> Self {
+        let ticks_per_sec = if clock.is_tsc() {
+            calibrate(clock, CALIBRATION_TIME)
+        } else {
+            1_000_000_000
+        };
+        Self {
+            clock,
+            ticks_per_sec,
+            next: Instant::now(),
+        }
+    }
+
+    pub fn ticks_per_sec(&self) -> u64 {
+        self.ticks_per_sec
+    }
+
+    pub fn anchor(&self) -> ClockAnchor {
+        ClockAnchor::now(self.clock, self.ticks_per_sec)
+    }
+
+    /// returns an anchor if one is due (the first call returns one)
+    pub fn poll(&mut self) -> Option<ClockAnchor> {
+        let now = Instant::now();
+        if !self.clock.needs_anchors() || now < self.next {
+            return None;
+        }
+        self.next = now + ANCHOR_INTERVAL;
+        Some(self.anchor())
+    }
+}
+
+#[test]
+fn anchor_conversion() {
+    let anchor = ClockAnchor {
+        ticks: 9_000_000_000,
+        realtime_nanos: 1_792_372_095_000_000_000,
+        ticks_per_sec: 3_000_000_000,
+    };
+    assert_eq!(anchor.nanos(anchor.ticks), Some(anchor.realtime_nanos));
+    assert_eq!(
+        anchor.nanos(anchor.ticks + 3),
+        Some(anchor.realtime_nanos + 1)
+    );
+    assert_eq!(
+        anchor.nanos(anchor.ticks - 6_000_000_000 + 300),
+        Some(anchor.realtime_nanos - 1_999_999_900)
+    );
+    assert_eq!(anchor.nanos(0), Some(anchor.realtime_nanos - 3_000_000_000));
+    assert_eq!(
+        anchor.ticks(anchor.realtime_nanos + 1),
+        Some(anchor.ticks + 3)
+    );
+    assert_eq!(
+        anchor.ticks(anchor.realtime_nanos - 1_999_999_900),
+        Some(anchor.ticks - 6_000_000_000 + 300)
+    );
+    assert_eq!(anchor.ticks(0), Some(0));
+
+    let mut chunk = vec![];
+    anchor.write_chunk(&mut chunk).unwrap();
+    let mut input = chunk.as_slice();
+    let header = ChunkHeader::read(&mut input).unwrap();
+    assert_eq!((header.stream, header.records), (ANCHOR_STREAM, 0));
+    assert!(header.verify(input));
+    assert_eq!(ClockAnchor::from_payload(input).unwrap(), anchor);
+}
+
+#[test]
+fn calibrated_anchors() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    for kind in (0..).map_while(ClockKind::from_u32) {
+        let mut clock = AnchorClock::calibrate(kind);
+        assert!(clock.ticks_per_sec() > 0);
+        let anchor = clock.poll();
+        assert_eq!(anchor.is_some(), kind.needs_anchors());
+        assert!(clock.poll().is_none());
+
+        let nanos = kind.utc_nanos(anchor.as_ref(), kind.now());
+        if kind == ClockKind::None {
+            assert_eq!(nanos, None);
+            continue;
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        // the coarse clocks lag by up to a tick
+        let error = now.as_nanos() as i128 - nanos.unwrap() as i128;
+        assert!(error.abs() < 20_000_000, "{kind:?} is off by {error}ns");
+    }
+}
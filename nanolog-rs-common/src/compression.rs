@@ -1,13 +1,26 @@
+use crate::checksum::{crc32c, crc32c_append};
+use crate::metadata::LogSite;
+use crate::record::{raw_records, Value};
+use crate::NanologType;
+use std::collections::HashMap;
+use std::io::{self, Write};
 use std::num::NonZero;
 
 /// bit layout of a nibble:
 /// xxxxxxxx
-/// ^   ^    <---- unused at the moment, eventually will impl sign
+/// ^        <---- upper sign (only for signed values)
 ///  ^^^     <---- upper nibble
+///     ^    <---- lower sign (only for signed values)
 ///      ^^^ <---- lower nibble
+/// - a nibble holds the number of bytes needed for the value (0 means all 8)
+/// - negative values are stored as !value (with the sign bit set), so small negative numbers take
+///   up as few bytes as small positive ones
 #[derive(Debug)]
 pub struct NibbleNibble(pub u8);
 
+const LOWER_SIGN: u8 = 1 << 3;
+const UPPER_SIGN: u8 = 1 << 7;
+
 impl NibbleNibble {
     pub fn get_num_bytes(&self) -> (Option<NonZero<usize>>, Option<NonZero<usize>>) {
         let lower_nibble = self.0 & ((1 << 4) - 1);
@@ -17,6 +30,41 @@ impl NibbleNibble {
             NonZero::new((upper_nibble & 7) as usize),
         )
     }
+
+    /// (lower is negative, upper is negative)
+    pub fn get_signs(&self) -> (bool, bool) {
+        (self.0 & LOWER_SIGN != 0, self.0 & UPPER_SIGN != 0)
+    }
+
+    pub fn from_signed(value: (i64, i64)) -> Self {
+        let (lower_negative, lower) = signed_magnitude(value.0);
+        let (upper_negative, upper) = signed_magnitude(value.1);
+        let mut nb = NibbleNibble::from((lower, upper));
+        if lower_negative {
+            nb.0 |= LOWER_SIGN;
+        }
+        if upper_negative {
+            nb.0 |= UPPER_SIGN;
+        }
+        nb
+    }
+}
+
+/// (is negative, magnitude to store)
+fn signed_magnitude(value: i64) -> (bool, u64) {
+    if value < 0 {
+        (true, !value as u64)
+    } else {
+        (false, value as u64)
+    }
+}
+
+fn from_signed_magnitude(negative: bool, magnitude: u64) -> i64 {
+    if negative {
+        !(magnitude as i64)
+    } else {
+        magnitude as i64
+    }
 }
 
 impl From<u64> for NibbleNibble {
@@ -77,6 +125,604 @@ impl From<(u64, u64)> for NibbleNibble {
     }
 }
 
+pub trait Compressable {
+    fn compress(&self, writer: &mut impl Write) -> io::Result<()>;
+}
+
+fn byte_counts(nb: &NibbleNibble) -> (usize, usize) {
+    let (lower_size, upper_size) = nb.get_num_bytes();
+    (
+        lower_size.map(|v| v.get()).unwrap_or(8),
+        upper_size.map(|v| v.get()).unwrap_or(8),
+    )
+}
+
+impl Compressable for (u64, u64) {
+    fn compress(&self, writer: &mut impl Write) -> io::Result<()> {
+        let nb = NibbleNibble::from(*self);
+        let (lower_size, upper_size) = byte_counts(&nb);
+
+        writer.write_all(&[nb.0])?;
+        writer.write_all(&self.0.to_le_bytes()[..lower_size])?;
+        writer.write_all(&self.1.to_le_bytes()[..upper_size])
+    }
+}
+
+impl Compressable for (i64, i64) {
+    fn compress(&self, writer: &mut impl Write) -> io::Result<()> {
+        let nb = NibbleNibble::from_signed(*self);
+        let (lower_size, upper_size) = byte_counts(&nb);
+
+        writer.write_all(&[nb.0])?;
+        writer.write_all(&signed_magnitude(self.0).1.to_le_bytes()[..lower_size])?;
+        writer.write_all(&signed_magnitude(self.1).1.to_le_bytes()[..upper_size])
+    }
+}
+
+/// a value without a partner only uses the lower half of the nibble
+impl Compressable for i64 {
+    fn compress(&self, writer: &mut impl Write) -> io::Result<()> {
+        let nb = NibbleNibble::from_signed((*self, 0));
+        let (lower_size, _) = byte_counts(&nb);
+
+        writer.write_all(&[nb.0 & 0x0f])?;
+        writer.write_all(&signed_magnitude(*self).1.to_le_bytes()[..lower_size])
+    }
+}
+
+/// read `n` little endian bytes
+fn read_le(input: &mut &[u8], n: usize) -> Option<u64> {
+    let (bytes, rest) = input.split_at_checked(n)?;
+    let mut value = [0; 8];
+    value[..n].copy_from_slice(bytes);
+    *input = rest;
+    Some(u64::from_le_bytes(value))
+}
+
+fn read_nibble(input: &mut &[u8]) -> Option<NibbleNibble> {
+    let (nb, rest) = input.split_first()?;
+    *input = rest;
+    Some(NibbleNibble(*nb))
+}
+
+/// inverse of `Compressable for (u64, u64)`, returns None if `input` is too short
+pub fn decompress_pair(input: &mut &[u8]) -> Option<(u64, u64)> {
+    let nb = read_nibble(input)?;
+    let (lower_size, upper_size) = byte_counts(&nb);
+    Some((read_le(input, lower_size)?, read_le(input, upper_size)?))
+}
+
+/// inverse of `Compressable for (i64, i64)`
+pub fn decompress_signed_pair(input: &mut &[u8]) -> Option<(i64, i64)> {
+    let nb = read_nibble(input)?;
+    let (lower_size, upper_size) = byte_counts(&nb);
+    let (lower_negative, upper_negative) = nb.get_signs();
+    Some((
+        from_signed_magnitude(lower_negative, read_le(input, lower_size)?),
+        from_signed_magnitude(upper_negative, read_le(input, upper_size)?),
+    ))
+}
+
+/// inverse of `Compressable for i64`
+pub fn decompress_signed(input: &mut &[u8]) -> Option<i64> {
+    let nb = read_nibble(input)?;
+    let (lower_size, _) = byte_counts(&nb);
+    Some(from_signed_magnitude(
+        nb.get_signs().0,
+        read_le(input, lower_size)?,
+    ))
+}
+
+/// a float is xored with the previous value of the same argument, so that repeated values and
+/// values that only differ in a few bits take up few bytes
+/// - header byte: (trailing zero bytes << 4) | significant bytes, the significant bytes of the xor
+///   follow in little endian order
+/// - a repeated value is just the header (0)
+pub fn compress_float(out: &mut impl Write, value: f64, prev: &mut u64) -> io::Result<()> {
+    let bits = value.to_bits();
+    let xor = bits ^ *prev;
+    *prev = bits;
+    if xor == 0 {
+        return out.write_all(&[0]);
+    }
+    let trailing = xor.trailing_zeros() / 8;
+    let significant = 8 - trailing - xor.leading_zeros() / 8;
+    out.write_all(&[(trailing << 4 | significant) as u8])?;
+    out.write_all(&(xor >> (8 * trailing)).to_le_bytes()[..significant as usize])
+}
+
+/// inverse of `compress_float`
+pub fn decompress_float(input: &mut &[u8], prev: &mut u64) -> Option<f64> {
+    let header = read_nibble(input)?.0;
+    let (trailing, significant) = ((header >> 4) as usize, (header & 0x0f) as usize);
+    if trailing + significant > 8 {
+        return None;
+    }
+    let xor = match significant {
+        0 => 0,
+        _ => read_le(input, significant)? << (8 * trailing),
+    };
+    *prev ^= xor;
+    Some(f64::from_bits(*prev))
+}
+
+/// the number of bytes `value` takes up in a nibble
+fn signed_num_bytes(value: i64) -> usize {
+    byte_counts(&NibbleNibble::from_signed((value, 0))).0
+}
+
+/// whether `value` takes up fewer bytes as its difference to `prev`
+fn delta_is_smaller(value: i64, prev: u64) -> bool {
+    signed_num_bytes(value.wrapping_sub(prev as i64)) < signed_num_bytes(value)
+}
+
+/// what the arguments of the next record of a log site are compressed relative to
+#[derive(Debug, Clone, Default)]
+pub struct PrevArgs {
+    /// the previous value of each argument
+    values: Vec<u64>,
+    /// whether an integer of an earlier record would have taken up fewer bytes as a difference,
+    /// only then do the integers come with the bitmap of differences
+    deltas: bool,
+}
+
+impl PrevArgs {
+    pub fn new(num_args: usize) -> Self {
+        Self {
+            values: vec![0; num_args],
+            deltas: false,
+        }
+    }
+}
+
+/// the integer arguments are packed pairwise (in argument order), followed by the floats and then
+/// the strings (a varint length followed by the string)
+/// - once an integer of the log site would have taken up fewer bytes as its difference to the
+///   previous value (e.g. a counter), the integers of its later records are replaced by their
+///   differences wherever that's smaller, and the pairs are preceded by a bitmap with a bit set for
+///   each integer that is a difference (one byte per 8 integers). The decoder tells when from the
+///   values it decoded, so sites that never benefit don't pay for the bitmap
+/// - `ints` is scratch space, kept by the caller to not allocate for every record
+pub fn compress_args(
+    out: &mut impl Write,
+    values: &[Value],
+    prev: &mut PrevArgs,
+    ints: &mut Vec<(i64, bool)>,
+) -> io::Result<()> {
+    ints.clear();
+    let mut had_delta = false;
+    for (v, p) in values.iter().zip(prev.values.iter_mut()) {
+        if let Value::Int(v) = v {
+            let smaller = delta_is_smaller(*v, *p);
+            had_delta |= smaller;
+            ints.push(match prev.deltas && smaller {
+                true => (v.wrapping_sub(*p as i64), true),
+                false => (*v, false),
+            });
+            *p = *v as u64;
+        }
+    }
+    if prev.deltas {
+        for byte in ints.chunks(8) {
+            let bitmap = byte
+                .iter()
+                .enumerate()
+                .fold(0u8, |bitmap, (i, (_, delta))| bitmap | (*delta as u8) << i);
+            out.write_all(&[bitmap])?;
+        }
+    }
+    prev.deltas |= had_delta;
+    let mut pairs = ints.chunks_exact(2);
+    for pair in pairs.by_ref() {
+        (pair[0].0, pair[1].0).compress(out)?;
+    }
+    if let [(last, _)] = pairs.remainder() {
+        last.compress(out)?;
+    }
+
+    for (v, prev) in values.iter().zip(prev.values.iter_mut()) {
+        if let Value::Float(v) = v {
+            compress_float(out, *v, prev)?;
+        }
+    }
+    for v in values.iter() {
+        if let Value::Str(s) = v {
+            write_varint(out, s.len() as u64)?;
+            out.write_all(s.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// inverse of `compress_args`, `args` are the argument types of the log site
+pub fn decompress_args(
+    input: &mut &[u8],
+    args: &[NanologType],
+    prev: &mut PrevArgs,
+) -> Option<Vec<Value>> {
+    let num_ints = args
+        .iter()
+        .filter(|a| matches!(a, NanologType::Int | NanologType::UInt))
+        .count();
+    let bitmap_len = if prev.deltas { num_ints.div_ceil(8) } else { 0 };
+    let (deltas, rest) = input.split_at_checked(bitmap_len)?;
+    *input = rest;
+    let mut ints = Vec::with_capacity(num_ints);
+    while ints.len() + 1 < num_ints {
+        let (lower, upper) = decompress_signed_pair(input)?;
+        ints.extend([lower, upper]);
+    }
+    if ints.len() < num_ints {
+        ints.push(decompress_signed(input)?);
+    }
+
+    let mut ints = ints.into_iter().enumerate();
+    let mut values = Vec::with_capacity(args.len());
+    let mut had_delta = false;
+    for (arg, p) in args.iter().zip(prev.values.iter_mut()) {
+        values.push(match arg {
+            NanologType::Int | NanologType::UInt => {
+                let (i, mut v) = ints.next()?;
+                if deltas.get(i / 8).is_some_and(|d| d & (1 << (i % 8)) != 0) {
+                    v = v.wrapping_add(*p as i64);
+                }
+                had_delta |= delta_is_smaller(v, *p);
+                *p = v as u64;
+                Value::Int(v)
+            }
+            NanologType::Float => Value::Float(decompress_float(input, p)?),
+            // after the floats
+            NanologType::Str => Value::Str(String::new()),
+        });
+    }
+    prev.deltas |= had_delta;
+    for (arg, value) in args.iter().zip(values.iter_mut()) {
+        if *arg == NanologType::Str {
+            let len = read_varint(input)? as usize;
+            let (s, rest) = input.split_at_checked(len)?;
+            *input = rest;
+            *value = Value::Str(String::from_utf8_lossy(s).into_owned());
+        }
+    }
+    Some(values)
+}
+
+/// marks the start of every chunk, a decoder that lost track of the chunk boundaries (because of
+/// a corrupted chunk) scans for it to find the next chunk
+pub const CHUNK_MARKER: [u8; 4] = *b"NLCK";
+
+/// the frame in front of the compressed records of every chunk:
+/// [marker: 4 bytes][stream: u64][record count: u32][compressed len: u32][crc32c: u32]
+/// - the checksum covers the stream, record count and length fields followed by the records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkHeader {
+    /// the producing thread (each logger thread has its own ring buffer)
+    pub stream: u64,
+    pub records: u32,
+    /// the number of bytes of compressed records following the header
+    pub len: u32,
+    pub checksum: u32,
+}
+
+impl ChunkHeader {
+    pub const SIZE: usize = 24;
+
+    pub fn new(stream: u64, records: u32, compressed: &[u8]) -> Self {
+        let mut header = Self {
+            stream,
+            records,
+            len: compressed.len() as u32,
+            checksum: 0,
+        };
+        header.checksum = header.compute_checksum(compressed);
+        header
+    }
+
+    fn fields(&self) -> [u8; 16] {
+        let mut fields = [0; 16];
+        fields[..8].copy_from_slice(&self.stream.to_le_bytes());
+        fields[8..12].copy_from_slice(&self.records.to_le_bytes());
+        fields[12..].copy_from_slice(&self.len.to_le_bytes());
+        fields
+    }
+
+    fn compute_checksum(&self, compressed: &[u8]) -> u32 {
+        crc32c_append(crc32c(&self.fields()), compressed)
+    }
+
+    /// whether `compressed` are the records this header was written for
+    pub fn verify(&self, compressed: &[u8]) -> bool {
+        compressed.len() == self.len as usize && self.compute_checksum(compressed) == self.checksum
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&CHUNK_MARKER)?;
+        out.write_all(&self.fields())?;
+        out.write_all(&self.checksum.to_le_bytes())
+    }
+
+    pub fn read(input: &mut &[u8]) -> io::Result<Self> {
+        let (bytes, rest) = input.split_first_chunk::<{ Self::SIZE }>().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "truncated chunk header")
+        })?;
+        if bytes[..4] != CHUNK_MARKER {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing chunk marker",
+            ));
+        }
+        *input = rest;
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        Ok(Self {
+            stream: u64::from_le_bytes(bytes[4..12].try_into().unwrap()),
+            records: u32_at(12),
+            len: u32_at(16),
+            checksum: u32_at(20),
+        })
+    }
+}
+
+/// the minimum number of records of a stream between two sync records
+pub const SYNC_INTERVAL: usize = 1024;
+
+/// set in the nibble of a record header if it carries the absolute timestamp (a sync record)
+/// - log IDs are never negative, so the sign bit of the log ID nibble is free
+const SYNC_FLAG: u8 = LOWER_SIGN;
+
+/// the number of log IDs in the recent-ID table of a stream, the header can refer to each of them
+/// with the 3 bits of the log ID size
+const RECENT_IDS: usize = 7;
+/// the log ID isn't in the recent-ID table, it follows the header as a varint
+const VARINT_ID: u8 = RECENT_IDS as u8;
+
+/// the most recently used log IDs of a stream, most recent first
+/// - with the `FLAG_RECENT_IDS` option, the log ID of a record is encoded as its position in the
+///   table (in the header byte itself), so the log sites of a hot loop cost no extra bytes
+#[derive(Debug, Default)]
+struct RecentIds {
+    ids: Vec<u64>,
+}
+
+impl RecentIds {
+    /// returns the position of `log_id` before moving it to the front
+    fn touch(&mut self, log_id: u64) -> Option<usize> {
+        let position = self.ids.iter().position(|id| *id == log_id);
+        match position {
+            Some(i) => self.ids[..=i].rotate_right(1),
+            None => {
+                self.ids.truncate(RECENT_IDS - 1);
+                self.ids.insert(0, log_id);
+            }
+        }
+        position
+    }
+
+    fn get(&mut self, i: usize) -> Option<u64> {
+        let log_id = *self.ids.get(i)?;
+        self.ids[..=i].rotate_right(1);
+        Some(log_id)
+    }
+}
+
+fn write_varint(out: &mut impl Write, mut value: u64) -> io::Result<()> {
+    while value >= 0x80 {
+        out.write_all(&[value as u8 | 0x80])?;
+        value >>= 7;
+    }
+    out.write_all(&[value as u8])
+}
+
+fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = input.split_first()?;
+        *input = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// the compressor state of a single stream of records (i.e. the records read from one ring buffer)
+/// - a record header is the nibble of (log ID, timestamp), where the timestamp is the (signed)
+///   difference to the previous record of the stream
+/// - with recent IDs the lower nibble holds the position of the log ID in the `RecentIds` table
+///   instead of its size, or `VARINT_ID` followed by the log ID as a varint
+/// - arguments are compressed relative to the previous value of the same argument of the same log
+///   site (see `compress_args`)
+/// - the first record of a stream, and the first record of a chunk once `SYNC_INTERVAL` records
+///   went by, are sync records carrying the absolute timestamp, so decoding can start at any
+///   chunk. Sync records also reset the previous argument values and the recent IDs
+#[derive(Debug, Default)]
+pub struct StreamCompressor {
+    last_timestamp: u64,
+    /// records since the last sync record, None before the first record
+    since_sync: Option<usize>,
+    /// whether the next record is the first of a chunk
+    chunk_start: bool,
+    /// the previous arguments, by log ID
+    args: HashMap<u64, PrevArgs>,
+    /// only kept with recent IDs
+    recent_ids: Option<RecentIds>,
+    /// the records of the chunk being compressed, they are written once their size is known
+    records: Vec<u8>,
+    /// scratch space of `compress_args`
+    ints: Vec<(i64, bool)>,
+}
+
+impl StreamCompressor {
+    /// encodes log IDs relative to a table of recently used ones (see `FLAG_RECENT_IDS`)
+    pub fn with_recent_ids() -> Self {
+        Self {
+            recent_ids: Some(RecentIds::default()),
+            ..Self::default()
+        }
+    }
+
+    /// the next record is the first of a chunk, so it may be a sync record
+    pub fn start_chunk(&mut self) {
+        self.chunk_start = true;
+    }
+
+    pub fn compress_header(
+        &mut self,
+        out: &mut impl Write,
+        log_id: u64,
+        timestamp: u64,
+    ) -> io::Result<()> {
+        let sync = self
+            .since_sync
+            .is_none_or(|n| self.chunk_start && n >= SYNC_INTERVAL);
+        self.chunk_start = false;
+        let (nb, value) = if sync {
+            self.since_sync = Some(0);
+            self.args.clear();
+            if let Some(recent_ids) = self.recent_ids.as_mut() {
+                recent_ids.ids.clear();
+            }
+            let mut nb = NibbleNibble::from((log_id, timestamp));
+            nb.0 |= SYNC_FLAG;
+            (nb, timestamp)
+        } else {
+            *self.since_sync.as_mut().unwrap() += 1;
+            let delta = timestamp.wrapping_sub(self.last_timestamp) as i64;
+            let (negative, magnitude) = signed_magnitude(delta);
+            let mut nb = NibbleNibble::from((log_id, magnitude));
+            if negative {
+                nb.0 |= UPPER_SIGN;
+            }
+            (nb, magnitude)
+        };
+        self.last_timestamp = timestamp;
+
+        let (lower_size, upper_size) = byte_counts(&nb);
+        match self.recent_ids.as_mut() {
+            Some(recent_ids) => {
+                let position = recent_ids.touch(log_id);
+                let id_bits = position.map_or(VARINT_ID, |i| i as u8);
+                out.write_all(&[nb.0 & !0b111 | id_bits])?;
+                if position.is_none() {
+                    write_varint(out, log_id)?;
+                }
+            }
+            None => {
+                out.write_all(&[nb.0])?;
+                out.write_all(&log_id.to_le_bytes()[..lower_size])?;
+            }
+        }
+        out.write_all(&value.to_le_bytes()[..upper_size])
+    }
+
+    /// compress a chunk of records read from the ring buffer of stream `stream` into `out`, framed
+    /// by a `ChunkHeader`
+    /// - `sites` is indexed by log ID and determines the size of each record's arguments
+    pub fn compress_buf(
+        &mut self,
+        out: &mut impl Write,
+        stream: u64,
+        sites: &[LogSite],
+        buf: &[u8],
+    ) -> io::Result<()> {
+        let mut records = std::mem::take(&mut self.records);
+        records.clear();
+        self.start_chunk();
+        let mut count = 0;
+        for record in raw_records(sites, buf) {
+            self.compress_header(&mut records, record.log_id, record.timestamp)?;
+
+            let site = &sites[record.log_id as usize];
+            let prev = self
+                .args
+                .entry(record.log_id)
+                .or_insert_with(|| PrevArgs::new(site.args.len()));
+            compress_args(&mut records, &record.values(site), prev, &mut self.ints)?;
+            count += 1;
+        }
+        ChunkHeader::new(stream, count, &records).write(out)?;
+        out.write_all(&records)?;
+        self.records = records;
+        Ok(())
+    }
+}
+
+/// the inverse of `StreamCompressor`
+#[derive(Debug, Default)]
+pub struct StreamDecompressor {
+    /// None until the first sync record is seen
+    last_timestamp: Option<u64>,
+    args: HashMap<u64, PrevArgs>,
+    recent_ids: Option<RecentIds>,
+}
+
+impl StreamDecompressor {
+    pub fn with_recent_ids() -> Self {
+        Self {
+            recent_ids: Some(RecentIds::default()),
+            ..Self::default()
+        }
+    }
+
+    /// whether the record at the start of `input` can't be decoded because decoding started after
+    /// the stream's last sync record
+    /// - the log ID can refer to the recent IDs, and whether the arguments come with the bitmap of
+    ///   differences depends on the records before (see `compress_args`), so the records up to the
+    ///   next sync record (at the start of a later chunk) must be skipped
+    pub fn needs_sync(&self, input: &[u8]) -> bool {
+        self.last_timestamp.is_none() && input.first().is_some_and(|nb| nb & SYNC_FLAG == 0)
+    }
+
+    /// returns the log ID and the timestamp of the record, the timestamp is None if decoding
+    /// started after the stream's last sync record
+    /// - returns None if `input` is too short or the log ID can't be resolved
+    pub fn decompress_header(&mut self, input: &mut &[u8]) -> Option<(u64, Option<u64>)> {
+        let nb = read_nibble(input)?;
+        let sync = nb.0 & SYNC_FLAG != 0;
+        let (lower_size, upper_size) = byte_counts(&nb);
+        let log_id = match self.recent_ids.as_mut() {
+            Some(recent_ids) => {
+                if sync {
+                    recent_ids.ids.clear();
+                }
+                match nb.0 & 0b111 {
+                    VARINT_ID => {
+                        let log_id = read_varint(input)?;
+                        recent_ids.touch(log_id);
+                        log_id
+                    }
+                    i => recent_ids.get(i as usize)?,
+                }
+            }
+            None => read_le(input, lower_size)?,
+        };
+        let value = read_le(input, upper_size)?;
+
+        if sync {
+            self.last_timestamp = Some(value);
+            self.args.clear();
+        } else if let Some(last) = self.last_timestamp.as_mut() {
+            let delta = from_signed_magnitude(nb.get_signs().1, value);
+            *last = last.wrapping_add(delta as u64);
+        }
+        Some((log_id, self.last_timestamp))
+    }
+
+    /// decompress the arguments of a record of log `log_id` (following its header)
+    pub fn decompress_args(
+        &mut self,
+        input: &mut &[u8],
+        log_id: u64,
+        args: &[NanologType],
+    ) -> Option<Vec<Value>> {
+        let prev = self
+            .args
+            .entry(log_id)
+            .or_insert_with(|| PrevArgs::new(args.len()));
+        decompress_args(input, args, prev)
+    }
+}
+
 #[test]
 fn nibble_creation() {
     assert_eq!(NibbleNibble::from(200).0, 1);
@@ -99,3 +745,276 @@ fn nibble_values() {
     assert_eq!(lower.unwrap(), NonZero::new(7).unwrap());
     assert_eq!(upper.unwrap(), NonZero::new(1).unwrap());
 }
+
+#[test]
+fn signed_nibble() {
+    assert_eq!(NibbleNibble::from_signed((-1, 1)).0, 0b0001_1001);
+    assert_eq!(
+        NibbleNibble::from_signed((1, -256)).get_signs(),
+        (false, true)
+    );
+    assert_eq!(
+        NibbleNibble::from_signed((i64::MIN, i64::MAX)).0,
+        0b0000_1000
+    );
+}
+
+#[test]
+fn signed_round_trip() {
+    let values = [
+        0,
+        1,
+        -1,
+        127,
+        -128,
+        255,
+        -256,
+        1 << 40,
+        -(1 << 40),
+        i64::MAX,
+        i64::MIN,
+    ];
+    for a in values {
+        let mut out = vec![];
+        a.compress(&mut out).unwrap();
+        assert_eq!(decompress_signed(&mut out.as_slice()), Some(a));
+        for b in values {
+            let mut out = vec![];
+            (a, b).compress(&mut out).unwrap();
+            let mut input = out.as_slice();
+            assert_eq!(decompress_signed_pair(&mut input), Some((a, b)));
+            assert!(input.is_empty());
+        }
+    }
+
+    // -1 takes up as much space as 1
+    let mut out = vec![];
+    (-1i64, 1i64).compress(&mut out).unwrap();
+    assert_eq!(out.len(), 3);
+}
+
+#[test]
+fn args_round_trip() {
+    let types = [
+        NanologType::Int,
+        NanologType::Str,
+        NanologType::Float,
+        NanologType::Int,
+        NanologType::Str,
+        NanologType::Int,
+        // a u64 field, compressed like an i64 with the same bits
+        NanologType::UInt,
+    ];
+    let values = [
+        Value::Int(-5),
+        Value::Str("hyper::client".to_string()),
+        Value::Float(-0.25),
+        Value::Int(1 << 33),
+        Value::Str(String::new()),
+        Value::Int(i64::MIN),
+        Value::Int(u64::MAX as i64),
+    ];
+    let mut out = vec![];
+    compress_args(&mut out, &values, &mut PrevArgs::new(7), &mut vec![]).unwrap();
+    let mut input = out.as_slice();
+    assert_eq!(
+        decompress_args(&mut input, &types, &mut PrevArgs::new(7)).unwrap(),
+        values
+    );
+    assert!(input.is_empty());
+    assert!(decompress_args(&mut &out[..out.len() - 1], &types, &mut PrevArgs::new(7)).is_none());
+}
+
+#[test]
+fn int_deltas() {
+    let types = [NanologType::Int; 9];
+    let first = [1 << 40, -1, i64::MAX, 0, 5, 1 << 20, 7, 8, -(1 << 40)];
+    let records = [
+        first,
+        first,
+        [
+            (1 << 40) + 1,
+            -2,
+            i64::MIN,
+            0,
+            6,
+            (1 << 20) - 3,
+            9,
+            8,
+            -(1 << 40) - 1,
+        ],
+        [(1 << 40) + 2, 1 << 50, i64::MAX, 0, 7, 0, 11, 8, 1],
+    ]
+    .map(|r| r.map(Value::Int));
+
+    let mut out = vec![];
+    let mut prev = PrevArgs::new(9);
+    let mut ints = vec![];
+    let mut sizes = vec![];
+    for r in records.iter() {
+        let start = out.len();
+        compress_args(&mut out, r, &mut prev, &mut ints).unwrap();
+        sizes.push(out.len() - start);
+    }
+    // 5 nibbles and the values, no bitmap until a difference would have been smaller (the repeated
+    // record)
+    assert_eq!(sizes[0], 5 + 6 + 1 + 8 + 1 + 1 + 3 + 1 + 1 + 5);
+    assert_eq!(sizes[1], sizes[0]);
+    // two bitmap bytes, the counters (and i64::MIN, right after i64::MAX) only take up a single
+    // byte
+    assert_eq!(sizes[2], 2 + 5 + 9);
+
+    let mut input = out.as_slice();
+    let mut prev = PrevArgs::new(9);
+    for r in records.iter() {
+        assert_eq!(
+            decompress_args(&mut input, &types, &mut prev).unwrap(),
+            r.to_vec()
+        );
+    }
+    assert!(input.is_empty());
+}
+
+#[test]
+fn timestamp_deltas() {
+    let timestamps = (0..3 * SYNC_INTERVAL as u64)
+        .map(|i| match i % 3 {
+            0 => u64::MAX - i,
+            1 => (1 << 40) + i * 300,
+            _ => (1 << 40) + i * 300 - 1000,
+        })
+        .collect::<Vec<_>>();
+    let mut compressor = StreamCompressor::default();
+    let mut out = vec![];
+    for ts in timestamps.iter() {
+        compressor.compress_header(&mut out, 3, *ts).unwrap();
+    }
+
+    let mut decompressor = StreamDecompressor::default();
+    let mut input = out.as_slice();
+    for ts in timestamps.iter() {
+        assert_eq!(
+            decompressor.decompress_header(&mut input),
+            Some((3, Some(*ts)))
+        );
+    }
+    assert!(input.is_empty());
+}
+
+#[test]
+fn timestamp_sync() {
+    let mut compressor = StreamCompressor::default();
+    let mut out = vec![];
+    compressor.compress_header(&mut out, 1, 1 << 40).unwrap();
+    // the delta fits in a single byte
+    let start = out.len();
+    compressor
+        .compress_header(&mut out, 1, (1 << 40) + 200)
+        .unwrap();
+    assert_eq!(out.len() - start, 3);
+    for i in 2..SYNC_INTERVAL as u64 + 3 {
+        // only the first record of a chunk can be a sync record
+        if i == 100 || i == SYNC_INTERVAL as u64 + 1 {
+            compressor.start_chunk();
+        }
+        compressor
+            .compress_header(&mut out, 1, (1 << 40) + 200 * i)
+            .unwrap();
+    }
+
+    // starting mid stream, timestamps are unknown until the next sync record
+    let mut decompressor = StreamDecompressor::default();
+    let mut input = &out[start..];
+    for _ in 1..=SYNC_INTERVAL {
+        assert_eq!(decompressor.decompress_header(&mut input), Some((1, None)));
+    }
+    for i in SYNC_INTERVAL as u64 + 1..SYNC_INTERVAL as u64 + 3 {
+        assert_eq!(
+            decompressor.decompress_header(&mut input),
+            Some((1, Some((1 << 40) + 200 * i)))
+        );
+    }
+    assert!(input.is_empty());
+}
+
+#[test]
+fn recent_ids() {
+    // a hot loop over a few log sites, with the occasional rare one
+    let ids = (0..3000u64)
+        .map(|i| if i % 100 == 99 { 1000 + i } else { i % 4 })
+        .collect::<Vec<_>>();
+    let mut compressor = StreamCompressor::with_recent_ids();
+    let mut out = vec![];
+    let mut mid_stream = 0;
+    for (i, id) in ids.iter().enumerate() {
+        if i % 500 == 0 {
+            compressor.start_chunk();
+        }
+        if i == 10 {
+            mid_stream = out.len();
+        }
+        let start = out.len();
+        compressor
+            .compress_header(&mut out, *id, 1000 + i as u64)
+            .unwrap();
+        // the log IDs of the hot loop are found in the table (once they were seen after the
+        // sync records at 0 and 1500), the delta fits in a byte
+        if i % 1500 > 3 && i % 100 != 99 {
+            assert_eq!(out.len() - start, 2, "record {i}");
+        }
+    }
+
+    let mut decompressor = StreamDecompressor::with_recent_ids();
+    let mut input = out.as_slice();
+    for (i, id) in ids.iter().enumerate() {
+        assert_eq!(
+            decompressor.decompress_header(&mut input),
+            Some((*id, Some(1000 + i as u64)))
+        );
+    }
+    assert!(input.is_empty());
+
+    // starting mid stream, nothing can be decoded until the next sync record
+    let decompressor = StreamDecompressor::with_recent_ids();
+    assert!(decompressor.needs_sync(&out[mid_stream..]));
+    assert!(!decompressor.needs_sync(&out));
+}
+
+#[test]
+fn float_round_trip() {
+    let values = [
+        0.0,
+        -0.0,
+        101.25,
+        101.25,
+        101.5,
+        -101.5,
+        f64::MIN_POSITIVE,
+        f64::MAX,
+        f64::INFINITY,
+        f64::NEG_INFINITY,
+        f64::from_bits(0x7ff8_0000_0000_0001),
+        f64::from_bits(1),
+        1.0 / 3.0,
+    ];
+    let mut out = vec![];
+    let mut prev = 0;
+    for v in values {
+        compress_float(&mut out, v, &mut prev).unwrap();
+    }
+    let mut input = out.as_slice();
+    let mut prev = 0;
+    for v in values {
+        let decoded = decompress_float(&mut input, &mut prev).unwrap();
+        assert_eq!(decoded.to_bits(), v.to_bits());
+    }
+    assert!(input.is_empty());
+
+    // a repeated value is a single byte, a price with few significant digits a few
+    let mut out = vec![];
+    let mut prev = 0;
+    compress_float(&mut out, 101.25, &mut prev).unwrap();
+    assert_eq!(out.len(), 4);
+    compress_float(&mut out, 101.25, &mut prev).unwrap();
+    assert_eq!(out.len(), 5);
+}
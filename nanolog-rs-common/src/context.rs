@@ -0,0 +1,196 @@
+use crate::metadata::LogSite;
+use crate::nanolog_logger::Logger;
+use crate::record::{truncate, Value, MAX_MESSAGE_LEN, MAX_TARGET_LEN};
+use crate::NanologType;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+const fn site(fmt: &'static str, args: &'static [NanologType]) -> LogSite {
+    LogSite {
+        fmt: Cow::Borrowed(fmt),
+        file: Cow::Borrowed("context"),
+        line: 0,
+        args: Cow::Borrowed(args),
+        fields: Cow::Borrowed(&[]),
+    }
+}
+
+/// the log sites of the records that push, pop and reset the context of a stream
+/// - a push carries the key and the value (an integer or a string), a pop nothing
+/// - a reset drops the whole context, the encoder writes it ahead of the pushes that repeat the
+///   context where decoding can start (see `write_context`)
+/// - build.rs puts them after `FACADE_SITES`
+pub const CONTEXT_SITES: [LogSite; 4] = [
+    site("push context %s=%d", &[NanologType::Str, NanologType::Int]),
+    site("push context %s=%s", &[NanologType::Str, NanologType::Str]),
+    site("pop context", &[]),
+    site("reset context", &[]),
+];
+
+/// the value of a context entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ContextValue<'a> {
+    Int(i64),
+    /// cut to `MAX_MESSAGE_LEN` bytes
+    Str(&'a str),
+}
+
+impl From<i64> for ContextValue<'_> {
+    fn from(v: i64) -> Self {
+        ContextValue::Int(v)
+    }
+}
+
+impl<'a> From<&'a str> for ContextValue<'a> {
+    fn from(v: &'a str) -> Self {
+        ContextValue::Str(v)
+    }
+}
+
+impl From<ContextValue<'_>> for Value {
+    fn from(v: ContextValue<'_>) -> Self {
+        match v {
+            ContextValue::Int(v) => Value::Int(v),
+            ContextValue::Str(v) => Value::Str(truncate(v, MAX_MESSAGE_LEN).to_string()),
+        }
+    }
+}
+
+fn write_str(logger: &mut dyn Logger, s: &str) {
+    logger.write(&(s.len() as u64).to_ne_bytes());
+    logger.write(s.as_bytes());
+}
+
+/// write a record pushing `key=value` onto the context of the logger's stream
+/// - `first_id` is the log ID of the first of the `CONTEXT_SITES`
+/// - the key is cut to `MAX_TARGET_LEN` bytes
+pub fn write_push(
+    logger: &mut dyn Logger,
+    first_id: u64,
+    timestamp: u64,
+    key: &str,
+    value: ContextValue,
+) {
+    let log_id = match value {
+        ContextValue::Int(_) => first_id,
+        ContextValue::Str(_) => first_id + 1,
+    };
+    logger.write(&log_id.to_ne_bytes());
+    logger.write(&timestamp.to_ne_bytes());
+    write_str(logger, truncate(key, MAX_TARGET_LEN));
+    match value {
+        ContextValue::Int(v) => logger.write(&v.to_ne_bytes()),
+        ContextValue::Str(v) => write_str(logger, truncate(v, MAX_MESSAGE_LEN)),
+    }
+    logger.commit_write();
+}
+
+/// write a record popping the most recently pushed entry off the context of the logger's stream
+pub fn write_pop(logger: &mut dyn Logger, first_id: u64, timestamp: u64) {
+    logger.write(&(first_id + 2).to_ne_bytes());
+    logger.write(&timestamp.to_ne_bytes());
+    logger.commit_write();
+}
+
+/// write a record resetting the context of the logger's stream, followed by a push for each of
+/// `entries` (outermost first)
+/// - this is how the context of a stream is repeated where decoding can start, and how it's
+///   carried over to another logger of the thread
+pub fn write_context(
+    logger: &mut dyn Logger,
+    first_id: u64,
+    timestamp: u64,
+    entries: &[(String, Value)],
+) {
+    logger.write(&(first_id + 3).to_ne_bytes());
+    logger.write(&timestamp.to_ne_bytes());
+    logger.commit_write();
+    for (key, value) in entries {
+        let value = match value {
+            Value::Int(v) => ContextValue::Int(*v),
+            Value::Str(v) => ContextValue::Str(v),
+            // not pushed by write_push
+            Value::Float(v) => ContextValue::Str(&v.to_string()),
+        };
+        write_push(logger, first_id, timestamp, key, value);
+    }
+}
+
+/// the entries pushed on a stream (and not popped yet) when a record was written, outermost
+/// first
+/// - shared by all the records of the scope
+pub type Context = Arc<[(String, Value)]>;
+
+/// whether `site` could be one of the `CONTEXT_SITES`, cheaper than `apply` for the sites that
+/// aren't
+pub fn is_context_site(site: &LogSite) -> bool {
+    site.line == 0 && site.file == CONTEXT_SITES[0].file
+}
+
+/// the context after a record of `site`, None if it isn't one of the `CONTEXT_SITES`
+/// - a pop of an empty context leaves it empty
+pub fn apply(context: &Context, site: &LogSite, values: &[Value]) -> Option<Context> {
+    if !is_context_site(site) {
+        return None;
+    }
+    if *site == CONTEXT_SITES[2] {
+        return Some(context[..context.len().saturating_sub(1)].into());
+    }
+    if *site == CONTEXT_SITES[3] {
+        return Some(Context::default());
+    }
+    if *site != CONTEXT_SITES[0] && *site != CONTEXT_SITES[1] {
+        return None;
+    }
+    let [Value::Str(key), value] = values else {
+        return None;
+    };
+    let mut entries = context.to_vec();
+    entries.push((key.clone(), value.clone()));
+    Some(entries.into())
+}
+
+#[test]
+fn push_and_pop() {
+    use crate::nanolog_logger::{create_reader_writer_pair, LogReader, Panic};
+    use crate::record::raw_records;
+
+    let (mut reader, mut writer) = create_reader_writer_pair::<{ 16 * 1024 }, Panic>();
+    write_push(&mut writer, 1, 10, "request", 42.into());
+    write_push(&mut writer, 1, 11, "user", "alice".into());
+    write_pop(&mut writer, 1, 12);
+    write_pop(&mut writer, 1, 13);
+    write_pop(&mut writer, 1, 14);
+    let entries = [("request".to_string(), Value::Int(42))];
+    write_push(&mut writer, 1, 15, "user", "bob".into());
+    write_context(&mut writer, 1, 16, &entries);
+
+    let mut sites = vec![site("%d", &[NanologType::Int])];
+    sites.extend(CONTEXT_SITES);
+    let mut chunk = vec![0; 16 * 1024];
+    let n = reader.read(&mut chunk);
+    let mut context = Context::default();
+    let mut contexts = vec![];
+    for record in raw_records(&sites, &chunk[..n]) {
+        let site = &sites[record.log_id as usize];
+        context = apply(&context, site, &record.values(site)).unwrap();
+        contexts.push(context.clone());
+    }
+    let request = ("request".to_string(), Value::Int(42));
+    let user = ("user".to_string(), Value::Str("alice".to_string()));
+    assert_eq!(
+        contexts,
+        [
+            vec![request.clone()].into(),
+            vec![request.clone(), user].into(),
+            vec![request].into(),
+            Context::default(),
+            Context::default(),
+            vec![("user".to_string(), Value::Str("bob".to_string()))].into(),
+            // the reset, then the push
+            Context::default(),
+            entries.to_vec().into(),
+        ]
+    );
+    assert_eq!(apply(&context, &sites[0], &[Value::Int(1)]), None);
+}
@@ -0,0 +1,113 @@
+use libc::{c_int, sigaction, sigemptyset, SA_RESETHAND, SIGINT, SIGTERM};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// signals that flush the logs before letting the process die
+/// - fatal signals (SIGSEGV, SIGBUS, SIGABRT) aren't handled: the flush can't run inside their
+///   handler without locking, allocating and doing buffered IO, and a faulting thread can't wait
+///   for another thread to do it
+pub const FLUSH_SIGNALS: [c_int; 2] = [SIGTERM, SIGINT];
+
+/// write end of the pipe the signal handler passes the signal number through
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// run `flush` whenever any thread panics (after the previously installed hook has reported the
+/// panic)
+pub fn install_panic_hook(flush: fn()) {
+    let prev = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        prev(info);
+        flush();
+    }));
+}
+
+/// run `flush` when the process receives one of the FLUSH_SIGNALS, then let the signal take its
+/// default action
+/// - the handler only writes the signal number to a pipe, `flush` runs on a separate thread
+///   reading from it, so it can lock, allocate and write like any other code
+/// - only the first call installs the handlers, they are process wide
+/// - a second signal while `flush` runs terminates the process right away
+pub fn install_signal_handlers(flush: fn()) -> std::io::Result<()> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    if SIGNAL_PIPE
+        .compare_exchange(-1, write_fd, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        return Ok(());
+    }
+    std::thread::Builder::new()
+        .name("nanolog-signal".into())
+        .spawn(move || {
+            let mut signal = 0u8;
+            loop {
+                let n = unsafe { libc::read(read_fd, (&raw mut signal).cast(), 1) };
+                if n == 1 {
+                    break;
+                }
+                if n < 0
+                    && std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted
+                {
+                    continue;
+                }
+                return;
+            }
+            flush();
+            // SA_RESETHAND restored the default disposition, so this terminates the process the
+            // way the original signal would have
+            unsafe {
+                libc::raise(signal as c_int);
+            }
+        })?;
+    for signal in FLUSH_SIGNALS {
+        unsafe {
+            let mut action: sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_signal as extern "C" fn(c_int) as usize;
+            // the handler only runs once, after that the default disposition is back in place
+            action.sa_flags = SA_RESETHAND;
+            sigemptyset(&mut action.sa_mask);
+            if sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// only calls write(2), which is async-signal safe
+extern "C" fn handle_signal(signal: c_int) {
+    let signal = signal as u8;
+    unsafe {
+        libc::write(
+            SIGNAL_PIPE.load(Ordering::Acquire),
+            (&raw const signal).cast(),
+            1,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FLUSHES: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_flush() {
+        FLUSHES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn panic_hook_flushes() {
+        install_panic_hook(count_flush);
+        let before = FLUSHES.load(Ordering::SeqCst);
+        assert!(std::thread::spawn(|| panic!("boom")).join().is_err());
+        assert_eq!(FLUSHES.load(Ordering::SeqCst), before + 1);
+    }
+}
@@ -0,0 +1,803 @@
+use crate::clock::{ClockAnchor, ClockKind, ANCHOR_STREAM};
+use crate::compression::{ChunkHeader, StreamDecompressor, CHUNK_MARKER};
+use crate::context::{self, Context};
+use crate::file_format::{read_header, FLAG_LZ4, FLAG_RECENT_IDS};
+use crate::metadata::{read_site_chunk, LogSite, SITE_STREAM};
+use crate::record::Value;
+use crate::thread::{ThreadInfo, THREAD_STREAM};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+
+/// a record read back from a compressed stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub stream: u64,
+    pub log_id: u64,
+    pub timestamp: u64,
+    pub values: Vec<Value>,
+    /// the context pushed on the stream when the record was written, see `context`
+    pub context: Context,
+}
+
+/// a chunk as found in the input, see `ChunkHeader`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk<'a> {
+    /// where the chunk (or the block containing it) starts in the input
+    pub offset: usize,
+    pub header: ChunkHeader,
+    /// the compressed records
+    pub records: Cow<'a, [u8]>,
+}
+
+/// decodes the compressed format written by the sinks (header followed by chunks)
+/// - records that can't be given a timestamp (e.g. because the input starts in the middle of a
+///   stream) are skipped until the stream's next sync record
+/// - by default decoding stops at the first corrupted chunk, see `recover`
+/// - the records pushing and popping context are applied to the context of their stream rather
+///   than returned
+pub struct Decoder<'a> {
+    /// the whole input, to report offsets
+    start: &'a [u8],
+    input: &'a [u8],
+    flags: u32,
+    clock: ClockKind,
+    sites: Vec<LogSite>,
+    streams: HashMap<u64, StreamDecompressor>,
+    /// the records of the current chunk that haven't been returned yet
+    pending: std::vec::IntoIter<Record>,
+    skipped: usize,
+    recover: bool,
+    /// the latest anchor read
+    anchor: Option<ClockAnchor>,
+    /// the threads registered so far, by stream
+    threads: HashMap<u64, ThreadInfo>,
+    /// the current context of each stream
+    contexts: HashMap<u64, Context>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated chunk")
+}
+
+/// split a chunk (header and records) off the start of `input` and verify its checksum
+fn split_chunk<'a>(input: &mut &'a [u8]) -> io::Result<(ChunkHeader, &'a [u8])> {
+    let mut rest = *input;
+    let header = ChunkHeader::read(&mut rest)?;
+    let (records, rest) = rest
+        .split_at_checked(header.len as usize)
+        .ok_or_else(truncated)?;
+    if !header.verify(records) {
+        return Err(invalid("chunk checksum mismatch"));
+    }
+    *input = rest;
+    Ok((header, records))
+}
+
+impl<'a> Decoder<'a> {
+    /// reads the header at the start of `input`
+    pub fn new(start: &'a [u8]) -> io::Result<Self> {
+        let mut input = start;
+        let header = read_header(&mut input)?;
+        Ok(Self {
+            start,
+            input,
+            flags: header.flags,
+            clock: header.clock,
+            sites: header.sites,
+            streams: HashMap::new(),
+            pending: Vec::new().into_iter(),
+            skipped: 0,
+            recover: false,
+            anchor: None,
+            threads: HashMap::new(),
+            contexts: HashMap::new(),
+        })
+    }
+
+    /// instead of stopping at a corrupted chunk, report it (as an error) and continue with the
+    /// next valid chunk after it
+    /// - the chunks are found by scanning for chunk markers, a chunk is valid if its checksum
+    ///   matches
+    /// - the streams can't continue where they left off, as the corrupted chunk could have been
+    ///   part of any of them. Their records are skipped until their next sync record
+    pub fn recover(mut self) -> Self {
+        self.recover = true;
+        self
+    }
+
+    pub fn sites(&self) -> &[LogSite] {
+        &self.sites
+    }
+
+    /// the number of records skipped for lack of a timestamp so far
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn clock(&self) -> ClockKind {
+        self.clock
+    }
+
+    /// nanoseconds since the unix epoch (UTC) of the timestamp of a record that was just returned
+    /// - None for clocks that need anchors (see `ClockAnchor`) until the first one is read, and
+    ///   for records without a timestamp
+    pub fn timestamp_nanos(&self, timestamp: u64) -> Option<u64> {
+        self.clock.utc_nanos(self.anchor.as_ref(), timestamp)
+    }
+
+    /// the timestamp records have at `nanos` since the unix epoch (the inverse of
+    /// `timestamp_nanos`), with the latest anchor read so far
+    pub fn raw_timestamp(&self, nanos: u64) -> Option<u64> {
+        self.clock.timestamp(self.anchor.as_ref(), nanos)
+    }
+
+    /// the thread of a stream, once its registration was read (it is written before the first
+    /// chunk of the stream)
+    pub fn thread(&self, stream: u64) -> Option<&ThreadInfo> {
+        self.threads.get(&stream)
+    }
+
+    /// the offset of the next chunk in the input
+    pub fn offset(&self) -> usize {
+        self.start.len() - self.input.len()
+    }
+
+    /// only decode the chunks in `start..end` of the input (e.g. a range found with the index)
+    /// - `start` must be the start of a chunk, the streams continue from there as if the input
+    ///   started at `start`, without the context pushed before it
+    pub fn seek(&mut self, start: usize, end: usize) {
+        self.input = &self.start[start..end];
+        self.start = &self.start[..end];
+        self.streams.clear();
+        self.contexts.clear();
+        self.pending = Vec::new().into_iter();
+    }
+
+    /// the next chunk without decoding its records, this is how the decoder skips through a file
+    /// - the records of skipped chunks are missing from the stream, so the following records of
+    ///   the stream are skipped until its next sync record, and its context is dropped
+    pub fn next_chunk(&mut self) -> Option<io::Result<Chunk<'a>>> {
+        let chunk = self.read_chunk();
+        if let Some(Ok(chunk)) = chunk.as_ref() {
+            self.streams.remove(&chunk.header.stream);
+            self.contexts.remove(&chunk.header.stream);
+        }
+        chunk
+    }
+
+    fn read_chunk(&mut self) -> Option<io::Result<Chunk<'a>>> {
+        if self.input.is_empty() {
+            return None;
+        }
+        let offset = self.offset();
+        let mut input = self.input;
+        let chunk = self.parse_chunk(&mut input).and_then(|(header, records)| {
+            match header.stream {
+                ANCHOR_STREAM => self.anchor = Some(ClockAnchor::from_payload(&records)?),
+                THREAD_STREAM => {
+                    let (stream, thread) = ThreadInfo::from_payload(&records)?;
+                    self.threads.insert(stream, thread);
+                }
+                SITE_STREAM => {
+                    let (log_id, site) = read_site_chunk(&records)?;
+                    let log_id = log_id as usize;
+                    if log_id >= self.sites.len() {
+                        // the sites before it are registered before it, unless decoding started
+                        // in the middle of the file
+                        self.sites.resize(log_id + 1, site.clone());
+                    }
+                    self.sites[log_id] = site;
+                }
+                _ => {}
+            }
+            Ok((header, records))
+        });
+        match chunk {
+            Ok((header, records)) => {
+                self.input = input;
+                Some(Ok(Chunk {
+                    offset,
+                    header,
+                    records,
+                }))
+            }
+            Err(e) => Some(Err(self.corrupted(offset, e))),
+        }
+    }
+
+    fn parse_chunk(&self, input: &mut &'a [u8]) -> io::Result<(ChunkHeader, Cow<'a, [u8]>)> {
+        if self.flags & FLAG_LZ4 == 0 {
+            split_chunk(input).map(|(header, records)| (header, Cow::Borrowed(records)))
+        } else {
+            self.read_block(input)
+        }
+    }
+
+    /// stop decoding, or when recovering, move on to the next valid chunk after `offset`
+    fn corrupted(&mut self, offset: usize, e: io::Error) -> io::Error {
+        if !self.recover {
+            self.input = &[];
+            return io::Error::new(e.kind(), format!("corrupted chunk at offset {offset}: {e}"));
+        }
+        // the corrupted chunk could have pushed or popped context
+        self.streams.clear();
+        self.contexts.clear();
+        self.input = &[];
+        let mut candidate = offset + 1;
+        while let Some(pos) = self.start[candidate..]
+            .windows(CHUNK_MARKER.len())
+            .position(|w| w == CHUNK_MARKER)
+        {
+            candidate += pos;
+            if self.parse_chunk(&mut &self.start[candidate..]).is_ok() {
+                self.input = &self.start[candidate..];
+                break;
+            }
+            candidate += 1;
+        }
+        io::Error::new(
+            e.kind(),
+            format!(
+                "corrupted chunk at offset {offset}, skipped {} bytes: {e}",
+                self.offset() - offset
+            ),
+        )
+    }
+
+    #[cfg(feature = "lz4")]
+    fn read_block(&self, input: &mut &'a [u8]) -> io::Result<(ChunkHeader, Cow<'a, [u8]>)> {
+        let block = crate::block::read_block(input)?;
+        let mut chunk = block.as_slice();
+        let (header, records) = split_chunk(&mut chunk)?;
+        if !chunk.is_empty() {
+            return Err(invalid("block length doesn't match its chunk"));
+        }
+        Ok((header, Cow::Owned(records.to_vec())))
+    }
+
+    #[cfg(not(feature = "lz4"))]
+    fn read_block(&self, _input: &mut &'a [u8]) -> io::Result<(ChunkHeader, Cow<'a, [u8]>)> {
+        unreachable!("read_header rejects lz4 files without the lz4 feature")
+    }
+
+    /// decode the records of the next chunk into `records`, None at the end of the input
+    /// - the records decoded before an error in the chunk are kept
+    pub(crate) fn decode_next_chunk(
+        &mut self,
+        records: &mut Vec<Record>,
+    ) -> Option<io::Result<()>> {
+        let chunk = match self.read_chunk()? {
+            Ok(chunk) => chunk,
+            Err(e) => return Some(Err(e)),
+        };
+        if let Err(e) = self.decode_chunk(&chunk.header, &chunk.records, records) {
+            // the records passed the checksum, so they were written this way and the chunks after
+            // it are fine
+            self.streams.remove(&chunk.header.stream);
+            if !self.recover {
+                self.input = &[];
+            }
+            return Some(Err(e));
+        }
+        Some(Ok(()))
+    }
+
+    /// decode the records of a single chunk
+    fn decode_chunk(
+        &mut self,
+        header: &ChunkHeader,
+        mut input: &[u8],
+        records: &mut Vec<Record>,
+    ) -> io::Result<()> {
+        let stream = header.stream;
+        if matches!(stream, ANCHOR_STREAM | THREAD_STREAM | SITE_STREAM) {
+            return Ok(());
+        }
+        let recent_ids = self.flags & FLAG_RECENT_IDS != 0;
+        let decompressor = self.streams.entry(stream).or_insert_with(|| {
+            if recent_ids {
+                StreamDecompressor::with_recent_ids()
+            } else {
+                StreamDecompressor::default()
+            }
+        });
+        for i in 0..header.records {
+            if decompressor.needs_sync(input) {
+                // can't tell where the records end without their log IDs
+                self.skipped += (header.records - i) as usize;
+                return Ok(());
+            }
+            let (log_id, timestamp) = decompressor
+                .decompress_header(&mut input)
+                .ok_or_else(truncated)?;
+            let site = self
+                .sites
+                .get(log_id as usize)
+                .ok_or_else(|| invalid("unknown log id"))?;
+            let values = decompressor
+                .decompress_args(&mut input, log_id, &site.args)
+                .ok_or_else(truncated)?;
+            let context = self.contexts.entry(stream).or_default();
+            if let Some(pushed_or_popped) = context::apply(context, site, &values) {
+                *context = pushed_or_popped;
+                continue;
+            }
+
+            match timestamp {
+                Some(timestamp) => records.push(Record {
+                    stream,
+                    log_id,
+                    timestamp,
+                    values,
+                    context: context.clone(),
+                }),
+                None => self.skipped += 1,
+            }
+        }
+        if !input.is_empty() {
+            return Err(invalid("chunk length doesn't match its records"));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Decoder<'_> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.pending.next() {
+                return Some(Ok(record));
+            }
+            let mut records = vec![];
+            let result = self.decode_next_chunk(&mut records)?;
+            self.pending = records.into_iter();
+            if let Err(e) = result {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::SYNC_INTERVAL;
+    use crate::sink::{Encoder, Encoding, MemorySink, Sink};
+    use crate::NanologType;
+    use std::borrow::Cow;
+
+    const SITES: [LogSite; 2] = [
+        LogSite {
+            fmt: Cow::Borrowed("Hello, world!"),
+            file: Cow::Borrowed("src/main.rs"),
+            line: 3,
+            args: Cow::Borrowed(&[]),
+            fields: Cow::Borrowed(&[]),
+        },
+        LogSite {
+            fmt: Cow::Borrowed("%d %f"),
+            file: Cow::Borrowed("src/main.rs"),
+            line: 4,
+            args: Cow::Borrowed(&[NanologType::Int, NanologType::Float]),
+            fields: Cow::Borrowed(&[]),
+        },
+    ];
+
+    fn record(stream: u64, i: u64) -> Record {
+        let timestamp = (1 << 40) + stream * 7 + i * 250;
+        match i % 2 {
+            0 => Record {
+                stream,
+                log_id: 0,
+                timestamp,
+                values: vec![],
+                context: Context::default(),
+            },
+            _ => Record {
+                stream,
+                log_id: 1,
+                timestamp,
+                // a counter, which its records store as differences once they are smaller
+                values: vec![
+                    Value::Int(-(1 << 40) - i as i64),
+                    Value::Float(i as f64 / 4.0),
+                ],
+                context: Context::default(),
+            },
+        }
+    }
+
+    fn chunk(records: &[Record]) -> Vec<u8> {
+        let mut chunk = vec![];
+        for r in records {
+            chunk.extend_from_slice(&r.log_id.to_ne_bytes());
+            chunk.extend_from_slice(&r.timestamp.to_ne_bytes());
+            for v in r.values.iter() {
+                match v {
+                    Value::Int(v) => chunk.extend_from_slice(&v.to_ne_bytes()),
+                    Value::Float(v) => chunk.extend_from_slice(&v.to_ne_bytes()),
+                    Value::Str(v) => {
+                        chunk.extend_from_slice(&(v.len() as u64).to_ne_bytes());
+                        chunk.extend_from_slice(v.as_bytes());
+                    }
+                }
+            }
+        }
+        chunk
+    }
+
+    /// two streams writing chunks of 5 records in turns, returns the output and the records
+    fn interleaved_streams(encoding: impl Into<Encoder>) -> (Vec<u8>, Vec<Record>) {
+        let mut sink = MemorySink::new(encoding);
+        let mut expected = vec![];
+        for i in 0..(SYNC_INTERVAL as u64 + 10) / 5 {
+            for stream in [0, 1] {
+                let records = (i * 5..i * 5 + 5)
+                    .map(|i| record(stream, i))
+                    .collect::<Vec<_>>();
+                sink.write_chunk(stream, &SITES, &chunk(&records)).unwrap();
+                expected.extend(records);
+            }
+        }
+        (sink.contents(), expected)
+    }
+
+    /// decoding picked up again somewhere in the middle of each stream
+    fn assert_stream_tails(decoded: &[Record], expected: &[Record]) {
+        for stream in [0, 1] {
+            let of_stream = |records: &[Record]| {
+                records
+                    .iter()
+                    .filter(|r| r.stream == stream)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            let (decoded, expected) = (of_stream(decoded), of_stream(expected));
+            assert!(!decoded.is_empty());
+            assert_eq!(decoded[..], expected[expected.len() - decoded.len()..]);
+        }
+    }
+
+    fn decode_interleaved_streams(encoding: impl Into<Encoder>) {
+        let (contents, expected) = interleaved_streams(encoding);
+        let decoder = Decoder::new(&contents).unwrap();
+        assert_eq!(decoder.sites(), SITES);
+        let decoded = decoder.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(decoded, expected);
+
+        let mut truncated = Decoder::new(&contents[..contents.len() - 1]).unwrap();
+        assert!(truncated.any(|r| r.is_err()));
+
+        // skip through the chunks, then decode from the middle of the file
+        let mut decoder = Decoder::new(&contents).unwrap();
+        let mut chunks = 0;
+        while let Some(chunk) = decoder.next_chunk() {
+            let chunk = chunk.unwrap();
+            assert_eq!(chunk.header.stream, chunks % 2);
+            assert_eq!(chunk.header.records, 5);
+            chunks += 1;
+            if chunks == 10 {
+                break;
+            }
+        }
+        let rest = decoder.by_ref().collect::<io::Result<Vec<_>>>().unwrap();
+        assert!(decoder.skipped() > 0);
+        assert_eq!(rest.len() + decoder.skipped(), expected.len() - 50);
+        assert_stream_tails(&rest, &expected);
+    }
+
+    fn recover_from_corruption(encoding: impl Into<Encoder>) {
+        let (mut contents, expected) = interleaved_streams(encoding);
+        let mut decoder = Decoder::new(&contents).unwrap();
+        let offsets = std::iter::from_fn(|| decoder.next_chunk())
+            .map(|c| c.unwrap().offset)
+            .collect::<Vec<_>>();
+        // flip a bit in the middle of the third chunk and in the marker of the fifth
+        let middle = (offsets[2] + offsets[3]) / 2;
+        contents[middle] ^= 1;
+        contents[offsets[4]] ^= 1;
+
+        let decoded = Decoder::new(&contents).unwrap().collect::<Vec<_>>();
+        assert_eq!(decoded.len(), 10 + 1);
+        assert!(decoded[10].is_err());
+
+        let mut decoder = Decoder::new(&contents).unwrap().recover();
+        let mut errors = vec![];
+        let mut records = vec![];
+        for r in decoder.by_ref() {
+            match r {
+                Ok(r) => records.push(r),
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with(&format!("corrupted chunk at offset {}", offsets[2])));
+        assert!(errors[1].starts_with(&format!("corrupted chunk at offset {}", offsets[4])));
+        // the chunks before the corruption, then everything after the next sync records
+        assert_eq!(records[..10], expected[..10]);
+        assert_eq!(records.len() + decoder.skipped(), expected.len() - 5 - 5);
+        assert_stream_tails(&records[10..], &expected);
+    }
+
+    #[test]
+    fn recover_compressed() {
+        recover_from_corruption(Encoding::Compressed);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn recover_lz4() {
+        recover_from_corruption(Encoding::Lz4);
+    }
+
+    #[test]
+    fn decode_compressed() {
+        decode_interleaved_streams(Encoding::Compressed);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn decode_lz4() {
+        decode_interleaved_streams(Encoding::Lz4);
+    }
+
+    #[test]
+    fn anchored_timestamps() {
+        let anchor = ClockAnchor {
+            ticks: 1 << 40,
+            realtime_nanos: 1_792_372_095_000_000_000,
+            ticks_per_sec: 1_000_000_000,
+        };
+        let mut encoder = Encoder::new(Encoding::Compressed);
+        let mut contents = vec![];
+        encoder.write_header(&mut contents, &SITES).unwrap();
+        encoder
+            .encode(&mut contents, 0, &SITES, &chunk(&[record(0, 0)]))
+            .unwrap();
+        encoder.set_anchor(anchor);
+        encoder
+            .encode(&mut contents, 0, &SITES, &chunk(&[record(0, 1)]))
+            .unwrap();
+        // e.g. the start of an index section, decoding can start here
+        encoder.restart_streams();
+        encoder
+            .encode(&mut contents, 0, &SITES, &chunk(&[record(0, 2)]))
+            .unwrap();
+
+        let mut decoder = Decoder::new(&contents).unwrap();
+        let first = decoder.next().unwrap().unwrap();
+        assert_eq!(first, record(0, 0));
+        assert_eq!(decoder.timestamp_nanos(first.timestamp), None);
+        for i in 1..3 {
+            let r = decoder.next().unwrap().unwrap();
+            assert_eq!(r, record(0, i));
+            assert_eq!(
+                decoder.timestamp_nanos(r.timestamp),
+                Some(anchor.realtime_nanos + i * 250)
+            );
+            assert_eq!(
+                decoder.raw_timestamp(anchor.realtime_nanos + i * 250),
+                Some(r.timestamp)
+            );
+        }
+        assert!(decoder.next().is_none());
+
+        let mut decoder = Decoder::new(&contents).unwrap();
+        let anchors = std::iter::from_fn(|| decoder.next_chunk())
+            .filter(|c| c.as_ref().unwrap().header.stream == ANCHOR_STREAM)
+            .count();
+        assert_eq!(anchors, 2);
+    }
+
+    #[test]
+    fn registered_threads() {
+        let t1 = ThreadInfo {
+            tid: 1234,
+            name: "T1".to_string(),
+        };
+        let mut encoder = Encoder::new(Encoding::Compressed);
+        let mut contents = vec![];
+        encoder.write_header(&mut contents, &SITES).unwrap();
+        encoder.register_thread(1, t1.clone());
+        encoder
+            .encode(&mut contents, 1, &SITES, &chunk(&[record(1, 0)]))
+            .unwrap();
+        encoder
+            .encode(&mut contents, 0, &SITES, &chunk(&[record(0, 0)]))
+            .unwrap();
+        let section = contents.len();
+        encoder.restart_streams();
+        encoder
+            .encode(&mut contents, 1, &SITES, &chunk(&[record(1, 1)]))
+            .unwrap();
+
+        let mut decoder = Decoder::new(&contents).unwrap();
+        assert_eq!(decoder.next().unwrap().unwrap(), record(1, 0));
+        assert_eq!(decoder.thread(1), Some(&t1));
+        assert_eq!(decoder.thread(0), None);
+        let rest = decoder.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(rest, [record(0, 0), record(1, 1)]);
+
+        // the registration is repeated where decoding can start
+        let mut decoder = Decoder::new(&contents).unwrap();
+        decoder.seek(section, contents.len());
+        assert_eq!(decoder.next().unwrap().unwrap(), record(1, 1));
+        assert_eq!(decoder.thread(1), Some(&t1));
+    }
+
+    #[test]
+    fn registered_sites() {
+        let mut sites = SITES.to_vec();
+        let mut encoder = Encoder::new(Encoding::Compressed);
+        let mut contents = vec![];
+        encoder.write_header(&mut contents, &sites).unwrap();
+        encoder
+            .encode(&mut contents, 0, &sites, &chunk(&[record(0, 0)]))
+            .unwrap();
+        // e.g. a tracing callsite
+        sites.push(LogSite {
+            fmt: Cow::Borrowed("INFO app: %s"),
+            file: Cow::Borrowed("src/app.rs"),
+            line: 9,
+            args: Cow::Borrowed(&[NanologType::Str]),
+            fields: Cow::Borrowed(&[]),
+        });
+        let registered = |i: u64| Record {
+            stream: 0,
+            log_id: 2,
+            timestamp: (1 << 40) + i * 250,
+            values: vec![Value::Str(format!("event {i}"))],
+            context: Context::default(),
+        };
+        encoder
+            .encode(&mut contents, 0, &sites, &chunk(&[registered(1)]))
+            .unwrap();
+        let section = contents.len();
+        encoder.restart_streams();
+        encoder
+            .encode(&mut contents, 0, &sites, &chunk(&[registered(2)]))
+            .unwrap();
+
+        let mut decoder = Decoder::new(&contents).unwrap();
+        assert_eq!(decoder.sites(), SITES);
+        let decoded = decoder.by_ref().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(decoded, [record(0, 0), registered(1), registered(2)]);
+        assert_eq!(decoder.sites(), sites);
+
+        // the registration is repeated where decoding can start
+        let mut decoder = Decoder::new(&contents).unwrap();
+        decoder.seek(section, contents.len());
+        assert_eq!(decoder.next().unwrap().unwrap(), registered(2));
+        assert_eq!(decoder.sites(), sites);
+    }
+
+    #[test]
+    fn scoped_context() {
+        use crate::context::{write_pop, write_push, CONTEXT_SITES};
+        use crate::nanolog_logger::{create_reader_writer_pair, LogReader, Logger, Panic};
+
+        let mut sites = SITES.to_vec();
+        sites.extend(CONTEXT_SITES);
+        let (mut reader, mut writer) = create_reader_writer_pair::<{ 16 * 1024 }, Panic>();
+        let mut sink = MemorySink::new(Encoding::Compressed);
+        let mut buf = vec![0; 16 * 1024];
+        let mut write_chunk = |stream: u64, sink: &mut MemorySink| {
+            let n = reader.read(&mut buf);
+            sink.write_chunk(stream, &sites, &buf[..n]).unwrap();
+            reader.commit_read();
+        };
+        let write_record = |writer: &mut dyn Logger, i: u64| {
+            writer.write(&chunk(&[record(0, i)]));
+            writer.commit_write();
+        };
+
+        write_record(&mut writer, 0);
+        write_push(&mut writer, 2, 1 << 40, "request", 7.into());
+        write_record(&mut writer, 2);
+        write_chunk(0, &mut sink);
+        // the context outlives the chunk, and is only that of its stream
+        write_push(&mut writer, 2, 1 << 40, "user", "alice".into());
+        write_record(&mut writer, 4);
+        write_pop(&mut writer, 2, 1 << 40);
+        write_record(&mut writer, 6);
+        write_chunk(0, &mut sink);
+        write_record(&mut writer, 8);
+        write_chunk(1, &mut sink);
+        write_pop(&mut writer, 2, 1 << 40);
+        write_record(&mut writer, 10);
+        write_chunk(0, &mut sink);
+
+        let request = ("request".to_string(), Value::Int(7));
+        let user = ("user".to_string(), Value::Str("alice".to_string()));
+        let with_context = |stream: u64, i: u64, context: Vec<(String, Value)>| Record {
+            stream,
+            context: context.into(),
+            ..record(0, i)
+        };
+        let contents = sink.contents();
+        let decoded = Decoder::new(&contents)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            decoded,
+            [
+                with_context(0, 0, vec![]),
+                with_context(0, 2, vec![request.clone()]),
+                with_context(0, 4, vec![request.clone(), user]),
+                with_context(0, 6, vec![request.clone()]),
+                with_context(1, 8, vec![]),
+                with_context(0, 10, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn context_after_restart() {
+        use crate::context::{write_push, CONTEXT_SITES};
+
+        let mut sites = SITES.to_vec();
+        sites.extend(CONTEXT_SITES);
+        let mut encoder = Encoder::new(Encoding::Compressed);
+        let mut contents = vec![];
+        encoder.write_header(&mut contents, &sites).unwrap();
+        let mut pushed = vec![];
+        write_push(&mut pushed, 2, 1 << 40, "request", 7.into());
+        pushed.extend(chunk(&[record(0, 0)]));
+        encoder.encode(&mut contents, 0, &sites, &pushed).unwrap();
+        // e.g. an index section
+        encoder.restart_streams();
+        let section = contents.len();
+        encoder
+            .encode(&mut contents, 0, &sites, &chunk(&[record(0, 2)]))
+            .unwrap();
+
+        let request = vec![("request".to_string(), Value::Int(7))];
+        let with_context = |i: u64| Record {
+            context: request.clone().into(),
+            ..record(0, i)
+        };
+        // the context is repeated rather than pushed twice
+        let decoded = Decoder::new(&contents)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(decoded, [with_context(0), with_context(2)]);
+        let mut decoder = Decoder::new(&contents).unwrap();
+        decoder.seek(section, contents.len());
+        assert_eq!(decoder.next().unwrap().unwrap(), with_context(2));
+    }
+
+    #[test]
+    fn realtime_timestamps() {
+        let mut encoder = Encoder::new(Encoding::Compressed);
+        encoder.set_clock(ClockKind::Realtime);
+        let mut contents = vec![];
+        encoder.write_header(&mut contents, &SITES).unwrap();
+        encoder
+            .encode(&mut contents, 0, &SITES, &chunk(&[record(0, 7)]))
+            .unwrap();
+
+        let mut decoder = Decoder::new(&contents).unwrap();
+        assert_eq!(decoder.clock(), ClockKind::Realtime);
+        let r = decoder.next().unwrap().unwrap();
+        // realtime timestamps don't need an anchor
+        assert_eq!(decoder.timestamp_nanos(r.timestamp), Some(r.timestamp));
+    }
+
+    #[test]
+    fn decode_recent_ids() {
+        decode_interleaved_streams(Encoder::new(Encoding::Compressed).with_recent_ids());
+    }
+
+    #[test]
+    fn recover_recent_ids() {
+        recover_from_corruption(Encoder::new(Encoding::Compressed).with_recent_ids());
+    }
+}
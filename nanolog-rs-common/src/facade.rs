@@ -0,0 +1,100 @@
+use crate::metadata::LogSite;
+use crate::nanolog_logger::Logger;
+use crate::record::{truncate, MAX_MESSAGE_LEN, MAX_TARGET_LEN};
+use crate::NanologType;
+use std::borrow::Cow;
+use std::fmt::Write;
+
+/// for `setup_nanolog!`, so that crates using it don't need to depend on `log` themselves
+pub use log;
+
+const fn site(fmt: &'static str) -> LogSite {
+    LogSite {
+        fmt: Cow::Borrowed(fmt),
+        file: Cow::Borrowed("log"),
+        line: 0,
+        args: Cow::Borrowed(&[NanologType::Str, NanologType::Str]),
+        fields: Cow::Borrowed(&[]),
+    }
+}
+
+/// the log sites of the records of the `log` crate, one per level (in the order of `log::Level`)
+/// - their call sites aren't known to build.rs, so the target and the formatted message are
+///   written to the ring buffer as strings
+/// - build.rs puts them after the log sites of the `nanolog!` invocations
+pub const FACADE_SITES: [LogSite; 5] = [
+    site("ERROR %s: %s"),
+    site("WARN %s: %s"),
+    site("INFO %s: %s"),
+    site("DEBUG %s: %s"),
+    site("TRACE %s: %s"),
+];
+
+/// write a record of the `log` crate to a ring buffer
+/// - `first_id` is the log ID of the first of the `FACADE_SITES`
+/// - the message is formatted into `buf`, which is reused across records
+pub fn write_record(
+    logger: &mut dyn Logger,
+    first_id: u64,
+    timestamp: u64,
+    record: &log::Record,
+    buf: &mut String,
+) {
+    buf.clear();
+    let _ = write!(buf, "{}", record.args());
+    let log_id = first_id + record.level() as u64 - 1;
+    logger.write(&log_id.to_ne_bytes());
+    logger.write(&timestamp.to_ne_bytes());
+    for s in [
+        truncate(record.target(), MAX_TARGET_LEN),
+        truncate(buf, MAX_MESSAGE_LEN),
+    ] {
+        logger.write(&(s.len() as u64).to_ne_bytes());
+        logger.write(s.as_bytes());
+    }
+    logger.commit_write();
+}
+
+#[test]
+fn facade_records() {
+    use crate::format::render;
+    use crate::nanolog_logger::{create_reader_writer_pair, LogReader, Panic};
+    use crate::record::raw_records;
+
+    let (mut reader, mut writer) = create_reader_writer_pair::<{ 16 * 1024 }, Panic>();
+    let mut buf = String::new();
+    let long = "é".repeat(MAX_MESSAGE_LEN);
+    for (level, message) in [
+        (
+            log::Level::Info,
+            format_args!("connected to {}", "10.0.0.1"),
+        ),
+        (log::Level::Trace, format_args!("{long}")),
+    ] {
+        let record = log::Record::builder()
+            .level(level)
+            .target("hyper::client")
+            .args(message)
+            .build();
+        write_record(&mut writer, 7, 42, &record, &mut buf);
+    }
+
+    let mut sites = vec![site("%d"); 7];
+    sites.extend(FACADE_SITES);
+    let mut chunk = vec![0; 16 * 1024];
+    let n = reader.read(&mut chunk);
+    let records = raw_records(&sites, &chunk[..n]).collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!((records[0].log_id, records[0].timestamp), (9, 42));
+    let site = &sites[9];
+    assert_eq!(
+        render(&site.fmt, &records[0].values(site)),
+        "INFO hyper::client: connected to 10.0.0.1"
+    );
+    assert_eq!(records[1].log_id, 11);
+    let values = records[1].values(&sites[11]);
+    assert_eq!(
+        values[1],
+        crate::record::Value::Str("é".repeat(MAX_MESSAGE_LEN / 2))
+    );
+}
@@ -0,0 +1,105 @@
+use crate::clock::ClockKind;
+use crate::metadata::{deserialize_log_sites, serialize_log_sites, LogSite};
+use std::io::{self, Read, Write};
+
+/// identifies a stream of compressed logs
+pub const FILE_MAGIC: [u8; 8] = *b"nanolog\0";
+pub const FORMAT_VERSION: u32 = 5;
+
+/// every chunk is lz4 compressed as a whole (see `block`)
+pub const FLAG_LZ4: u32 = 1;
+/// log IDs are encoded relative to a table of the recently used ones of each stream (see
+/// `StreamCompressor`)
+pub const FLAG_RECENT_IDS: u32 = 2;
+/// the flags this build knows how to decode
+#[cfg(feature = "lz4")]
+const SUPPORTED_FLAGS: u32 = FLAG_LZ4 | FLAG_RECENT_IDS;
+#[cfg(not(feature = "lz4"))]
+const SUPPORTED_FLAGS: u32 = FLAG_RECENT_IDS;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileHeader {
+    /// how the chunks of the stream are encoded (`FLAG_*`)
+    pub flags: u32,
+    /// what the timestamps of the records are
+    pub clock: ClockKind,
+    pub sites: Vec<LogSite>,
+}
+
+/// every compressed stream (file, socket, ...) starts with a header that makes it self-describing:
+/// [magic: 8 bytes][version: u32][flags: u32][clock: u32][metadata len: u32][serialized log sites]
+pub fn write_header(
+    out: &mut impl Write,
+    flags: u32,
+    clock: ClockKind,
+    sites: &[LogSite],
+) -> io::Result<()> {
+    let metadata = serialize_log_sites(sites);
+    out.write_all(&FILE_MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    out.write_all(&flags.to_le_bytes())?;
+    out.write_all(&(clock as u32).to_le_bytes())?;
+    out.write_all(&(metadata.len() as u32).to_le_bytes())?;
+    out.write_all(&metadata)
+}
+
+/// returns the log sites the records of the stream refer to and how they are encoded
+pub fn read_header(input: &mut impl Read) -> io::Result<FileHeader> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if magic != FILE_MAGIC {
+        return Err(invalid("not a nanolog log file"));
+    }
+    let mut int = [0; 4];
+    input.read_exact(&mut int)?;
+    if u32::from_le_bytes(int) != FORMAT_VERSION {
+        return Err(invalid("unsupported nanolog log file version"));
+    }
+    input.read_exact(&mut int)?;
+    let flags = u32::from_le_bytes(int);
+    if flags & !SUPPORTED_FLAGS != 0 {
+        return Err(invalid(
+            "the log file uses features this build doesn't support (e.g. the lz4 feature)",
+        ));
+    }
+    input.read_exact(&mut int)?;
+    let clock =
+        ClockKind::from_u32(u32::from_le_bytes(int)).ok_or_else(|| invalid("unknown clock"))?;
+    input.read_exact(&mut int)?;
+    let mut metadata = vec![0; u32::from_le_bytes(int) as usize];
+    input.read_exact(&mut metadata)?;
+    Ok(FileHeader {
+        flags,
+        clock,
+        sites: deserialize_log_sites(&metadata)?,
+    })
+}
+
+#[test]
+fn header_round_trip() {
+    use crate::NanologType;
+    use std::borrow::Cow;
+
+    let sites = [LogSite {
+        fmt: Cow::Borrowed("%d"),
+        file: Cow::Borrowed("src/main.rs"),
+        line: 7,
+        args: Cow::Borrowed(&[NanologType::Int]),
+        fields: Cow::Borrowed(&[]),
+    }];
+    let mut out = vec![];
+    write_header(&mut out, 0, ClockKind::Rdtscp, &sites).unwrap();
+    let header = read_header(&mut out.as_slice()).unwrap();
+    assert_eq!(
+        (header.flags, header.clock, header.sites.as_slice()),
+        (0, ClockKind::Rdtscp, &sites[..])
+    );
+    out[0] = b'N';
+    assert!(read_header(&mut out.as_slice()).is_err());
+
+    let mut out = vec![];
+    write_header(&mut out, 1 << 31, ClockKind::Rdtsc, &sites).unwrap();
+    assert!(read_header(&mut out.as_slice()).is_err());
+}
@@ -0,0 +1,592 @@
+use crate::metadata::LogSite;
+use crate::record::Value;
+use crate::NanologType;
+use std::borrow::{Borrow, Cow};
+use std::fmt::{Display, Write};
+use std::io;
+
+/// render a nanolog format string the way printf would
+/// - `%d`, `%f` and `%s` are replaced by the next value, `%%` by a literal `%`
+/// - the values must match the format specifiers (which the nanolog! macro guarantees)
+pub fn render(fmt: &str, values: &[Value]) -> String {
+    let mut out = String::with_capacity(fmt.len());
+    let mut values = values.iter();
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('d') | Some('f') | Some('s') => match values.next() {
+                Some(value) => write_value(&mut out, value),
+                None => out.push_str("<missing>"),
+            },
+            Some('%') => out.push('%'),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+fn write_value(out: &mut String, value: &Value) {
+    match value {
+        Value::Int(v) => write!(out, "{v}").unwrap(),
+        Value::Float(v) => write!(out, "{v:.6}").unwrap(),
+        Value::Str(v) => out.push_str(v),
+    }
+}
+
+/// the names and values of the named fields of a record
+/// - `u64` fields (decoded as the bits of an `i64`) above `i64::MAX` are strings, the way the
+///   tracing layer records them
+pub fn field_values<'v>(
+    site: &'v LogSite,
+    fields: &'v [Value],
+) -> impl Iterator<Item = (&'v str, Cow<'v, Value>)> {
+    let types = &site.args[site.args.len().saturating_sub(fields.len())..];
+    site.fields
+        .iter()
+        .zip(types)
+        .zip(fields)
+        .map(|((name, ty), value)| {
+            let value = match (ty, value) {
+                (NanologType::UInt, Value::Int(v)) if *v < 0 => {
+                    Cow::Owned(Value::Str((*v as u64).to_string()))
+                }
+                _ => Cow::Borrowed(value),
+            };
+            (name.as_ref(), value)
+        })
+}
+
+/// the message of a record: the format string of its site rendered with the format arguments,
+/// followed by ` name=value` for every named field
+pub fn message(site: &LogSite, values: &[Value]) -> String {
+    let (args, fields) = site.split_values(values);
+    let mut out = render(&site.fmt, args);
+    for (name, value) in field_values(site, fields) {
+        write!(out, " {name}=").unwrap();
+        write_value(&mut out, &value);
+    }
+    out
+}
+
+/// the timestamp of a record as output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamp {
+    /// as recorded
+    Raw(u64),
+    /// nanoseconds since the unix epoch, output as a `utc_timestamp`
+    Utc(u64),
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Timestamp::Raw(ts) => write!(f, "{ts}"),
+            Timestamp::Utc(nanos) => f.write_str(&utc_timestamp(*nanos)),
+        }
+    }
+}
+
+/// the human readable form of a record: `<timestamp> [<thread>] <file>:<line> <message>`
+/// - the timestamp is either the raw timestamp or a `utc_timestamp`
+/// - the thread is left out if it isn't known, see `ThreadInfo::label`
+/// - the context of the record (see `context`) follows the message as ` key=value` pairs
+pub fn write_line(
+    out: &mut impl io::Write,
+    timestamp: impl Display,
+    thread: Option<&str>,
+    site: &LogSite,
+    values: &[Value],
+    context: &[(String, Value)],
+) -> io::Result<()> {
+    let mut message = message(site, values);
+    for (key, value) in context {
+        write!(message, " {key}=").unwrap();
+        write_value(&mut message, value);
+    }
+    match thread {
+        Some(thread) => writeln!(
+            out,
+            "{timestamp} [{thread}] {}:{} {message}",
+            site.file, site.line
+        ),
+        None => writeln!(out, "{timestamp} {}:{} {message}", site.file, site.line),
+    }
+}
+
+/// `s` as a JSON string
+fn write_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// floats JSON can't represent (NaN and the infinities) are null
+fn write_json_value(out: &mut String, value: &Value) {
+    match value {
+        Value::Int(v) => write!(out, "{v}").unwrap(),
+        Value::Float(v) if v.is_finite() => write!(out, "{v}").unwrap(),
+        Value::Float(_) => out.push_str("null"),
+        Value::Str(v) => write_json_str(out, v),
+    }
+}
+
+fn write_json_object<'v>(
+    out: &mut String,
+    pairs: impl Iterator<Item = (&'v str, impl Borrow<Value>)>,
+) {
+    out.push('{');
+    for (i, (name, value)) in pairs.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_json_str(out, name);
+        out.push(':');
+        write_json_value(out, value.borrow());
+    }
+    out.push('}');
+}
+
+/// the structured form of a record, a JSON object on a line of its own (JSON Lines):
+/// `{"timestamp":..,"thread":"T1","level":"INFO","file":"src/main.rs","line":42,"fmt":"order %d filled","args":[3],"message":"order 3 filled","fields":{"id":7,"px":1.5}}`
+/// - raw timestamps are numbers, UTC timestamps strings
+/// - `args` are the values of the format string, the message is rendered without the named
+///   fields, which are a nested object, like the context of the record (`"context":{..}`)
+/// - the thread and the level are left out if they aren't known, see `ThreadInfo::label` and
+///   `LogSite::level`
+pub fn write_json(
+    out: &mut impl io::Write,
+    timestamp: Timestamp,
+    thread: Option<&str>,
+    site: &LogSite,
+    values: &[Value],
+    context: &[(String, Value)],
+) -> io::Result<()> {
+    let (args, fields) = site.split_values(values);
+    let mut json = String::from("{\"timestamp\":");
+    match timestamp {
+        Timestamp::Raw(ts) => write!(json, "{ts}").unwrap(),
+        Timestamp::Utc(_) => write_json_str(&mut json, &timestamp.to_string()),
+    }
+    if let Some(thread) = thread {
+        json.push_str(",\"thread\":");
+        write_json_str(&mut json, thread);
+    }
+    if let Some(level) = site.level() {
+        json.push_str(",\"level\":");
+        write_json_str(&mut json, level);
+    }
+    json.push_str(",\"file\":");
+    write_json_str(&mut json, &site.file);
+    write!(json, ",\"line\":{},\"fmt\":", site.line).unwrap();
+    write_json_str(&mut json, &site.fmt);
+    json.push_str(",\"args\":[");
+    for (i, value) in args.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_json_value(&mut json, value);
+    }
+    json.push_str("],\"message\":");
+    write_json_str(&mut json, &render(&site.fmt, args));
+    json.push_str(",\"fields\":");
+    write_json_object(&mut json, field_values(site, fields));
+    json.push_str(",\"context\":");
+    write_json_object(&mut json, context.iter().map(|(k, v)| (k.as_str(), v)));
+    json.push('}');
+    writeln!(out, "{json}")
+}
+
+/// `s` as a logfmt value, quoted if it's empty or has spaces, quotes, `=` or control characters
+fn write_logfmt_str(out: &mut String, s: &str) {
+    if !s.is_empty() && !s.contains(|c: char| c <= ' ' || c == '"' || c == '=') {
+        out.push_str(s);
+    } else {
+        write_json_str(out, s);
+    }
+}
+
+/// a record in logfmt, on a line of its own:
+/// `ts=.. thread=T1 level=INFO file=src/main.rs line=42 msg="order 3 filled" id=7 px=1.5`
+/// - the message is rendered without the named fields, which follow it as pairs of their own, and
+///   so does the context of the record
+/// - the thread and the level are left out if they aren't known, like `write_json`
+pub fn write_logfmt(
+    out: &mut impl io::Write,
+    timestamp: Timestamp,
+    thread: Option<&str>,
+    site: &LogSite,
+    values: &[Value],
+    context: &[(String, Value)],
+) -> io::Result<()> {
+    let (args, fields) = site.split_values(values);
+    let mut line = format!("ts={timestamp}");
+    if let Some(thread) = thread {
+        line.push_str(" thread=");
+        write_logfmt_str(&mut line, thread);
+    }
+    if let Some(level) = site.level() {
+        write!(line, " level={level}").unwrap();
+    }
+    line.push_str(" file=");
+    write_logfmt_str(&mut line, &site.file);
+    write!(line, " line={} msg=", site.line).unwrap();
+    write_logfmt_str(&mut line, &render(&site.fmt, args));
+    let context = context.iter().map(|(k, v)| (k.as_str(), Cow::Borrowed(v)));
+    for (name, value) in field_values(site, fields).chain(context) {
+        write!(line, " {name}=").unwrap();
+        match value.as_ref() {
+            Value::Int(v) => write!(line, "{v}").unwrap(),
+            Value::Float(v) => write!(line, "{v}").unwrap(),
+            Value::Str(v) => write_logfmt_str(&mut line, v),
+        }
+    }
+    writeln!(out, "{line}")
+}
+
+/// how the decoder outputs records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// `write_line`
+    #[default]
+    Text,
+    /// `write_json`
+    Json,
+    /// `write_logfmt`
+    Logfmt,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" | "jsonl" => Ok(OutputFormat::Json),
+            "logfmt" => Ok(OutputFormat::Logfmt),
+            _ => Err(format!(
+                "unknown output format {s}, expected text, json or logfmt"
+            )),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// output a record on a line of its own
+    pub fn write(
+        self,
+        out: &mut impl io::Write,
+        timestamp: Timestamp,
+        thread: Option<&str>,
+        site: &LogSite,
+        values: &[Value],
+        context: &[(String, Value)],
+    ) -> io::Result<()> {
+        match self {
+            OutputFormat::Text => write_line(out, timestamp, thread, site, values, context),
+            OutputFormat::Json => write_json(out, timestamp, thread, site, values, context),
+            OutputFormat::Logfmt => write_logfmt(out, timestamp, thread, site, values, context),
+        }
+    }
+}
+
+/// (year, month, day, hour, minute, second) in UTC of the given seconds since the unix epoch
+pub fn utc_date_time(unix_secs: u64) -> (u64, u64, u64, u64, u64, u64) {
+    let (days, secs) = (unix_secs / 86400, unix_secs % 86400);
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// `YYYY-MM-DDTHH:MM:SS.nnnnnnnnnZ` of nanoseconds since the unix epoch
+pub fn utc_timestamp(nanos: u64) -> String {
+    let (year, month, day, hour, minute, second) = utc_date_time(nanos / 1_000_000_000);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{:09}Z",
+        nanos % 1_000_000_000
+    )
+}
+
+/// nanoseconds since the unix epoch of an RFC 3339 timestamp, e.g. `2026-10-19T01:08:15Z` or
+/// `2026-10-19 03:08:15.5+02:00` (the inverse of `utc_timestamp`)
+/// - None for anything else, including dates that don't exist and times before the epoch
+pub fn parse_utc_timestamp(s: &str) -> Option<u64> {
+    // only digits, `parse` would also take a sign
+    let number = |s: &str, len: usize| -> Option<u64> {
+        (s.len() == len && s.bytes().all(|b| b.is_ascii_digit())).then(|| s.parse().ok())?
+    };
+    let (date, time) = s.split_once(['T', 't', ' '])?;
+    let (time, offset_secs) = match time.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, 0),
+        None => {
+            let (time, offset) = time.split_at(time.rfind(['+', '-'])?);
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let secs = (number(hours, 2)? * 60 + number(minutes, 2)?) as i64 * 60;
+            (time, if offset.starts_with('-') { -secs } else { secs })
+        }
+    };
+    let (time, fraction) = match time.split_once('.') {
+        Some((time, fraction)) => (time, Some(fraction)),
+        None => (time, None),
+    };
+
+    let mut date = date.split('-');
+    let year = number(date.next()?, 4)?;
+    let month = number(date.next()?, 2)?;
+    let day = number(date.next()?, 2)?;
+    let mut time = time.split(':');
+    let hour = number(time.next()?, 2)?;
+    let minute = number(time.next()?, 2)?;
+    let second = number(time.next()?, 2)?;
+    if date.next().is_some() || time.next().is_some() || year < 1970 || !(1..=12).contains(&month) {
+        return None;
+    }
+    // past the last digit of the nanoseconds, the fraction is truncated
+    let nanos = match fraction {
+        None => 0,
+        Some(fraction) => {
+            let digits = &fraction[..fraction.len().min(9)];
+            if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            number(digits, digits.len())? * 10u64.pow(9 - digits.len() as u32)
+        }
+    };
+
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let y = year - u64::from(month <= 2);
+    let (era, yoe) = (y / 400, y % 400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let unix_secs = (era * 146097 + doe - 719468) * 86400 + hour * 3600 + minute * 60 + second;
+    // e.g. February 30th or 24:00:00 don't survive the round trip
+    if day == 0 || utc_date_time(unix_secs) != (year, month, day, hour, minute, second) {
+        return None;
+    }
+    let unix_secs = unix_secs.checked_add_signed(-offset_secs)?;
+    unix_secs.checked_mul(1_000_000_000)?.checked_add(nanos)
+}
+
+#[test]
+fn render_values() {
+    assert_eq!(render("Hello, world!", &[]), "Hello, world!");
+    assert_eq!(
+        render(
+            "%d %f 100%% %s %x",
+            &[Value::Int(-3), Value::Float(1.1), Value::Str("ok".into())]
+        ),
+        "-3 1.100000 100% ok %x"
+    );
+}
+
+#[test]
+fn utc_dates() {
+    assert_eq!(utc_date_time(0), (1970, 1, 1, 0, 0, 0));
+    assert_eq!(utc_date_time(951782400), (2000, 2, 29, 0, 0, 0));
+    assert_eq!(utc_date_time(1792372095), (2026, 10, 19, 1, 8, 15));
+    assert_eq!(
+        utc_timestamp(1_792_372_095_000_000_042),
+        "2026-10-19T01:08:15.000000042Z"
+    );
+}
+
+#[test]
+fn parse_utc_dates() {
+    let nanos = 1_792_372_095_000_000_042;
+    assert_eq!(parse_utc_timestamp(&utc_timestamp(nanos)), Some(nanos));
+    assert_eq!(
+        parse_utc_timestamp("2026-10-19T01:08:15Z"),
+        Some(1_792_372_095_000_000_000)
+    );
+    assert_eq!(
+        parse_utc_timestamp("2026-10-19 03:08:15.5+02:00"),
+        Some(1_792_372_095_500_000_000)
+    );
+    assert_eq!(
+        parse_utc_timestamp("2026-10-18t20:08:15.0000000429-05:00"),
+        Some(nanos)
+    );
+    assert_eq!(
+        parse_utc_timestamp("2000-02-29T00:00:00Z"),
+        Some(951_782_400_000_000_000)
+    );
+    for invalid in [
+        "1792372095",
+        "2026-10-19",
+        "2026-10-19T01:08:15",
+        "2026-10-19T01:08Z",
+        "2026-02-30T00:00:00Z",
+        "2026-10-19T24:00:00Z",
+        "2026-10-19T01:08:15.Z",
+        "2026-10-19T01:08:15+2:00",
+        "1969-12-31T23:59:59Z",
+        "1970-01-01T00:00:00+00:01",
+    ] {
+        assert_eq!(parse_utc_timestamp(invalid), None, "{invalid}");
+    }
+}
+
+#[test]
+fn u64_fields() {
+    let site = LogSite {
+        fmt: Cow::Borrowed("sent"),
+        file: Cow::Borrowed("src/main.rs"),
+        line: 7,
+        args: Cow::Borrowed(&[NanologType::UInt, NanologType::UInt]),
+        fields: Cow::Borrowed(&[Cow::Borrowed("bytes"), Cow::Borrowed("hash")]),
+    };
+    // decoded with the bits of an i64
+    let values = [Value::Int(5), Value::Int(u64::MAX as i64)];
+    assert_eq!(
+        message(&site, &values),
+        "sent bytes=5 hash=18446744073709551615"
+    );
+    let mut out = vec![];
+    write_json(&mut out, Timestamp::Raw(9), None, &site, &values, &[]).unwrap();
+    write_logfmt(&mut out, Timestamp::Raw(9), None, &site, &values, &[]).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "{\"timestamp\":9,\"file\":\"src/main.rs\",\"line\":7,\"fmt\":\"sent\",\"args\":[],\
+         \"message\":\"sent\",\"fields\":{\"bytes\":5,\"hash\":\"18446744073709551615\"},\
+         \"context\":{}}\n\
+         ts=9 file=src/main.rs line=7 msg=sent bytes=5 hash=18446744073709551615\n"
+    );
+}
+
+#[test]
+fn structured_records() {
+    let site = LogSite {
+        fmt: Cow::Borrowed("order %d \"filled\""),
+        file: Cow::Borrowed("src/main.rs"),
+        line: 42,
+        args: Cow::Borrowed(&[
+            NanologType::Int,
+            NanologType::Int,
+            NanologType::Float,
+            NanologType::Str,
+        ]),
+        fields: Cow::Borrowed(&[
+            Cow::Borrowed("id"),
+            Cow::Borrowed("px"),
+            Cow::Borrowed("venue"),
+        ]),
+    };
+    let values = [
+        Value::Int(3),
+        Value::Int(-7),
+        Value::Float(1.5),
+        Value::Str("x\ny".into()),
+    ];
+    assert_eq!(
+        message(&site, &values),
+        "order 3 \"filled\" id=-7 px=1.500000 venue=x\ny"
+    );
+
+    let context = [("request".to_string(), Value::Int(12))];
+    let mut out = vec![];
+    write_line(
+        &mut out,
+        Timestamp::Raw(9),
+        Some("T1"),
+        &site,
+        &values,
+        &context,
+    )
+    .unwrap();
+    write_json(&mut out, Timestamp::Raw(9), Some("T1"), &site, &values, &[]).unwrap();
+    write_json(
+        &mut out,
+        Timestamp::Utc(1_000_000_001),
+        None,
+        &site,
+        &values,
+        &context,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "9 [T1] src/main.rs:42 order 3 \"filled\" id=-7 px=1.500000 venue=x\ny request=12\n\
+         {\"timestamp\":9,\"thread\":\"T1\",\"file\":\"src/main.rs\",\"line\":42,\
+         \"fmt\":\"order %d \\\"filled\\\"\",\"args\":[3],\
+         \"message\":\"order 3 \\\"filled\\\"\",\"fields\":{\"id\":-7,\"px\":1.5,\"venue\":\"x\\ny\"},\
+         \"context\":{}}\n\
+         {\"timestamp\":\"1970-01-01T00:00:01.000000001Z\",\"file\":\"src/main.rs\",\"line\":42,\
+         \"fmt\":\"order %d \\\"filled\\\"\",\"args\":[3],\
+         \"message\":\"order 3 \\\"filled\\\"\",\"fields\":{\"id\":-7,\"px\":1.5,\"venue\":\"x\\ny\"},\
+         \"context\":{\"request\":12}}\n"
+    );
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn output_formats() {
+    use crate::facade::FACADE_SITES;
+
+    let site = &FACADE_SITES[2];
+    assert_eq!(site.level(), Some("INFO"));
+    assert_eq!(FACADE_SITES[1].level(), Some("WARN"));
+    let values = [Value::Str("hyper".into()), Value::Str("a=b".into())];
+    let format = |format: &str, timestamp| {
+        let mut out = vec![];
+        let format: OutputFormat = format.parse().unwrap();
+        format
+            .write(&mut out, timestamp, Some("T 1"), site, &values, &[])
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(
+        format("logfmt", Timestamp::Raw(9)),
+        "ts=9 thread=\"T 1\" level=INFO file=log line=0 msg=\"INFO hyper: a=b\"\n"
+    );
+    assert_eq!(
+        format("jsonl", Timestamp::Utc(0)),
+        "{\"timestamp\":\"1970-01-01T00:00:00.000000000Z\",\"thread\":\"T 1\",\"level\":\"INFO\",\
+         \"file\":\"log\",\"line\":0,\"fmt\":\"INFO %s: %s\",\"args\":[\"hyper\",\"a=b\"],\
+         \"message\":\"INFO hyper: a=b\",\"fields\":{},\"context\":{}}\n"
+    );
+    assert_eq!(
+        format("text", Timestamp::Raw(9)),
+        "9 [T 1] log:0 INFO hyper: a=b\n"
+    );
+    assert!("xml".parse::<OutputFormat>().is_err());
+
+    // named fields follow the message, then the context
+    let site = LogSite {
+        args: vec![NanologType::Str, NanologType::Str, NanologType::Float].into(),
+        fields: vec!["px".into()].into(),
+        ..FACADE_SITES[0].clone()
+    };
+    let values = [values[0].clone(), values[1].clone(), Value::Float(0.25)];
+    let mut out = vec![];
+    let context = [("user".to_string(), Value::Str("a b".into()))];
+    write_logfmt(&mut out, Timestamp::Raw(1), None, &site, &values, &context).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "ts=1 level=ERROR file=log line=0 msg=\"ERROR hyper: a=b\" px=0.25 user=\"a b\"\n"
+    );
+}
@@ -0,0 +1,227 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// the default number of bytes of a log file covered by an index entry
+pub const INDEX_INTERVAL: u64 = 1 << 20;
+
+/// a section of a log file (a run of whole chunks) and the range of the timestamps of its records
+/// - every stream starts with a sync record in a section, so decoding can start at any section
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub offset: u64,
+    pub len: u64,
+    pub min_timestamp: u64,
+    pub max_timestamp: u64,
+}
+
+impl IndexEntry {
+    pub const SIZE: usize = 32;
+
+    fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+
+    pub fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        for field in [
+            self.offset,
+            self.len,
+            self.min_timestamp,
+            self.max_timestamp,
+        ] {
+            out.write_all(&field.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn from_bytes(bytes: &[u8; Self::SIZE]) -> Self {
+        let field = |i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        Self {
+            offset: field(0),
+            len: field(1),
+            min_timestamp: field(2),
+            max_timestamp: field(3),
+        }
+    }
+}
+
+/// the index of a log file is kept next to it in `<log file>.idx`
+pub fn index_path(log: impl AsRef<Path>) -> PathBuf {
+    let mut path = log.as_ref().as_os_str().to_owned();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+/// appends an entry to the index for every `interval` bytes written to a log file
+/// - an entry is only written once its section is complete (the last one when the writer is
+///   dropped), sections that never made it into the index are treated as containing any timestamp
+pub struct IndexWriter {
+    out: BufWriter<File>,
+    interval: u64,
+    section: Option<IndexEntry>,
+}
+
+impl IndexWriter {
+    /// appends to the index at `path`, creating it if needed
+    pub fn open(path: impl AsRef<Path>, interval: u64) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(Self {
+            out: BufWriter::new(file),
+            interval,
+            section: None,
+        })
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// called before a chunk is written at `offset`
+    /// - returns whether the chunk starts a new section, in which case it must not be compressed
+    ///   relative to earlier chunks
+    pub fn start_chunk(&mut self, offset: u64) -> io::Result<bool> {
+        if let Some(section) = self.section {
+            if offset - section.offset < self.interval {
+                return Ok(false);
+            }
+            section.write(&mut self.out)?;
+        }
+        self.section = Some(IndexEntry {
+            offset,
+            len: 0,
+            min_timestamp: u64::MAX,
+            max_timestamp: 0,
+        });
+        Ok(true)
+    }
+
+    /// called after a chunk with records of `timestamps` was written, `end` is the offset right
+    /// after it
+    pub fn end_chunk(&mut self, end: u64, timestamps: impl Iterator<Item = u64>) {
+        let section = self
+            .section
+            .as_mut()
+            .expect("start_chunk must be called first");
+        section.len = end - section.offset;
+        for ts in timestamps {
+            section.min_timestamp = section.min_timestamp.min(ts);
+            section.max_timestamp = section.max_timestamp.max(ts);
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Drop for IndexWriter {
+    fn drop(&mut self) {
+        if let Some(section) = self.section.take() {
+            let _ = section.write(&mut self.out);
+        }
+        let _ = self.out.flush();
+    }
+}
+
+pub fn read_index(path: impl AsRef<Path>) -> io::Result<Vec<IndexEntry>> {
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    // a partially written entry at the end is ignored
+    Ok(bytes
+        .chunks_exact(IndexEntry::SIZE)
+        .map(|entry| IndexEntry::from_bytes(entry.try_into().unwrap()))
+        .collect())
+}
+
+/// the byte range of a log file that holds all records with `since <= timestamp <= until`
+/// - `data_start` is where the chunks start (right after the header), `file_len` where they end
+/// - the range starts and ends at section boundaries, it can contain records outside of the
+///   bounds (as the streams of a file are only roughly in timestamp order)
+pub fn find_range(
+    entries: &[IndexEntry],
+    data_start: u64,
+    file_len: u64,
+    since: Option<u64>,
+    until: Option<u64>,
+) -> (u64, u64) {
+    // the parts of the file missing from the index could contain anything
+    let mut sections = vec![];
+    let mut pos = data_start;
+    for entry in entries.iter().filter(|e| e.offset >= data_start) {
+        if entry.offset > pos {
+            sections.push(IndexEntry {
+                offset: pos,
+                len: entry.offset - pos,
+                min_timestamp: 0,
+                max_timestamp: u64::MAX,
+            });
+        }
+        sections.push(*entry);
+        pos = entry.end();
+    }
+    if pos < file_len {
+        sections.push(IndexEntry {
+            offset: pos,
+            len: file_len - pos,
+            min_timestamp: 0,
+            max_timestamp: u64::MAX,
+        });
+    }
+
+    // the largest timestamp up to and the smallest from each section on, both are sorted
+    let max_before = sections
+        .iter()
+        .scan(0, |max, s| {
+            *max = s.max_timestamp.max(*max);
+            Some(*max)
+        })
+        .collect::<Vec<_>>();
+    let mut min_after = sections
+        .iter()
+        .rev()
+        .scan(u64::MAX, |min, s| {
+            *min = s.min_timestamp.min(*min);
+            Some(*min)
+        })
+        .collect::<Vec<_>>();
+    min_after.reverse();
+
+    let first = max_before.partition_point(|max| since.is_some_and(|since| *max < since));
+    let last = min_after.partition_point(|min| until.is_none_or(|until| *min <= until));
+    let start = sections.get(first).map_or(file_len, |s| s.offset);
+    let end = match last.checked_sub(1) {
+        Some(last) => sections[last].end(),
+        None => start,
+    };
+    (start, end.max(start))
+}
+
+#[test]
+fn find_ranges() {
+    let entry = |offset, min_timestamp, max_timestamp| IndexEntry {
+        offset,
+        len: 100,
+        min_timestamp,
+        max_timestamp,
+    };
+    // the section at 310 is missing, the last one hasn't been written yet
+    let entries = [
+        entry(10, 0, 50),
+        entry(110, 40, 100),
+        entry(210, 90, 150),
+        entry(410, 300, 400),
+    ];
+    let range = |since, until| find_range(&entries, 10, 600, since, until);
+    assert_eq!(range(None, None), (10, 600));
+    assert_eq!(range(Some(45), None), (10, 600));
+    assert_eq!(range(Some(60), Some(95)), (110, 600));
+    assert_eq!(range(Some(120), Some(140)), (210, 600));
+    assert_eq!(range(Some(401), None), (310, 600));
+    assert_eq!(range(Some(1000), None), (310, 600));
+
+    let entries = &entries[..3];
+    let range = |since, until| find_range(entries, 10, 310, since, until);
+    assert_eq!(range(None, Some(30)), (10, 110));
+    assert_eq!(range(Some(60), Some(95)), (110, 310));
+    assert_eq!(range(Some(200), None), (310, 310));
+}
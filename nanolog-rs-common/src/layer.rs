@@ -0,0 +1,449 @@
+use crate::metadata::{LogSite, SiteRegistry};
+use crate::nanolog_logger::Logger;
+use crate::record::{truncate, MAX_MESSAGE_LEN};
+use crate::NanologType;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Write};
+use std::sync::RwLock;
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// the kinds of records with fields, the first byte of their shape
+const EVENT: u8 = 0;
+const NEW_SPAN: u8 = 1;
+const RECORD_SPAN: u8 = 2;
+
+/// the fields of an event or a span, in ring buffer layout
+#[derive(Default)]
+struct Fields {
+    /// the kind of the record, whether there is a message, then the index (u16 le) and type
+    /// character of every other field, in the order they were recorded
+    shape: Vec<u8>,
+    /// [len: u64][utf-8] when there is a message
+    message: Vec<u8>,
+    args: Vec<u8>,
+    /// `Debug` values are formatted into this
+    buf: String,
+}
+
+impl Fields {
+    fn clear(&mut self, kind: u8) {
+        self.shape.clear();
+        self.shape.extend_from_slice(&[kind, 0]);
+        self.message.clear();
+        self.args.clear();
+    }
+
+    /// whether nothing was recorded
+    fn is_empty(&self) -> bool {
+        self.shape[1..] == [0]
+    }
+
+    /// the fields past index `u16::MAX` (of callsites with more fields than that) are left out
+    fn push_shape(&mut self, field: &Field, ty: u8) -> bool {
+        let Ok(index) = u16::try_from(field.index()) else {
+            return false;
+        };
+        self.shape.extend_from_slice(&index.to_le_bytes());
+        self.shape.push(ty);
+        true
+    }
+
+    fn push(&mut self, field: &Field, ty: NanologType, value: [u8; 8]) {
+        if self.push_shape(field, ty.to_type_string().as_bytes()[0]) {
+            self.args.extend_from_slice(&value);
+        }
+    }
+
+    fn push_str(&mut self, field: &Field, value: &str) {
+        let value = truncate(value, MAX_MESSAGE_LEN);
+        let out = if field.name() == "message" {
+            self.shape[1] = 1;
+            self.message.clear();
+            &mut self.message
+        } else if self.push_shape(field, b'S') {
+            &mut self.args
+        } else {
+            return;
+        };
+        out.extend_from_slice(&(value.len() as u64).to_ne_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    /// the log site of the records of `metadata` recorded with these fields
+    /// - span records log as "LEVEL target: new name" (or "record name" for the values recorded
+    ///   later), with a `span` field ahead of the others
+    fn site(&self, metadata: &Metadata<'_>) -> LogSite {
+        let mut fmt = format!("{} {}:", metadata.level(), escape(metadata.target()));
+        let what = match self.shape[0] {
+            NEW_SPAN => Some("new"),
+            RECORD_SPAN => Some("record"),
+            _ => None,
+        };
+        if let Some(what) = what {
+            let _ = write!(fmt, " {what} {}", escape(metadata.name()));
+        }
+        let mut args = vec![];
+        if self.shape[1] == 1 {
+            fmt.push_str(" %s");
+            args.push(NanologType::Str);
+        }
+        let mut fields = vec![];
+        if what.is_some() {
+            fields.push(Cow::Borrowed("span"));
+            args.push(NanologType::Int);
+        }
+        for field in self.shape[2..].chunks(3) {
+            let index = u16::from_le_bytes([field[0], field[1]]) as usize;
+            let name = metadata.fields().iter().nth(index).unwrap();
+            fields.push(Cow::Borrowed(name.name()));
+            args.push(NanologType::from_type_char(field[2] as char).unwrap());
+        }
+        site(metadata, fmt, args, fields)
+    }
+}
+
+impl Visit for Fields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, NanologType::Float, value.to_ne_bytes());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, NanologType::Int, value.to_ne_bytes());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match i64::try_from(value) {
+            Ok(value) => self.record_i64(field, value),
+            Err(_) => self.push_str(field, &value.to_string()),
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push_str(field, if value { "true" } else { "false" });
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push_str(field, value);
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        let _ = write!(buf, "{value:?}");
+        self.push_str(field, &buf);
+        self.buf = buf;
+    }
+}
+
+thread_local! {
+    static FIELDS: RefCell<Fields> = RefCell::new(Fields::default());
+}
+
+fn escape(s: &str) -> Cow<'_, str> {
+    if s.contains('%') {
+        Cow::Owned(s.replace('%', "%%"))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+fn site(
+    metadata: &Metadata<'_>,
+    fmt: String,
+    args: Vec<NanologType>,
+    fields: Vec<Cow<'static, str>>,
+) -> LogSite {
+    LogSite {
+        fmt: Cow::Owned(fmt),
+        file: Cow::Owned(metadata.file().unwrap_or("tracing").to_string()),
+        line: metadata.line().unwrap_or(0),
+        args: Cow::Owned(args),
+        fields: Cow::Owned(fields),
+    }
+}
+
+/// log IDs by callsite (the address of its metadata) and shape
+type Callsites = HashMap<usize, Vec<(Box<[u8]>, u64)>>;
+
+/// runs its argument with the ring buffer of the calling thread
+pub type WithLogger = fn(&mut dyn FnMut(&mut dyn Logger));
+
+/// the shapes of the span enter and exit records
+const ENTER: &[u8] = b"enter";
+const EXIT: &[u8] = b"exit";
+
+/// a `tracing_subscriber::Layer` writing events and spans (their fields, enters and exits) to ring
+/// buffers
+/// - the callsite metadata is the format dictionary: a log site is registered (see
+///   `SiteRegistry`) for every callsite and shape of its records (which fields an event has and
+///   their types are only known once it's recorded), and records hold the field values in
+///   binary form like `nanolog!` records
+/// - events log as "LEVEL target: message" with the fields of the event as named fields (see
+///   `LogSite::fields`). A new span logs as "LEVEL target: new name" with its fields, values
+///   recorded later as "LEVEL target: record name", and enters and exits as "LEVEL target: enter
+///   name", all with a `span` field holding the span's ID
+/// - strings (`&str`, `Debug`, `bool` and `u64` above `i64::MAX` fields) are formatted on the
+///   logging thread and cut to `MAX_MESSAGE_LEN` bytes
+/// - set up with `tracing_layer` of `setup_nanolog!`
+pub struct NanologLayer {
+    registry: &'static SiteRegistry,
+    sites: RwLock<Callsites>,
+    now: fn() -> u64,
+    with_logger: WithLogger,
+}
+
+impl NanologLayer {
+    /// - `now` timestamps the records
+    pub fn new(registry: &'static SiteRegistry, now: fn() -> u64, with_logger: WithLogger) -> Self {
+        Self {
+            registry,
+            sites: RwLock::new(HashMap::new()),
+            now,
+            with_logger,
+        }
+    }
+
+    fn log_id(
+        &self,
+        metadata: &'static Metadata<'static>,
+        shape: &[u8],
+        site: impl FnOnce() -> LogSite,
+    ) -> u64 {
+        let callsite = metadata as *const Metadata as usize;
+        let find = |sites: &Callsites| {
+            sites
+                .get(&callsite)
+                .and_then(|shapes| shapes.iter().find(|(s, _)| **s == *shape))
+                .map(|&(_, log_id)| log_id)
+        };
+        if let Some(log_id) = find(&self.sites.read().unwrap()) {
+            return log_id;
+        }
+        let mut sites = self.sites.write().unwrap();
+        if let Some(log_id) = find(&sites) {
+            return log_id;
+        }
+        let log_id = self.registry.register(site());
+        sites
+            .entry(callsite)
+            .or_default()
+            .push((shape.into(), log_id));
+        log_id
+    }
+
+    fn write(&self, log_id: u64, timestamp: u64, args: &[&[u8]]) {
+        (self.with_logger)(&mut |logger| {
+            logger.write(&log_id.to_ne_bytes());
+            logger.write(&timestamp.to_ne_bytes());
+            for arg in args {
+                logger.write(arg);
+            }
+            logger.commit_write();
+        });
+    }
+
+    fn write_span<S>(&self, id: &Id, ctx: Context<'_, S>, what: &'static [u8])
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let timestamp = (self.now)();
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let metadata = span.metadata();
+        let log_id = self.log_id(metadata, what, || {
+            let fmt = format!(
+                "{} {}: {} {}",
+                metadata.level(),
+                escape(metadata.target()),
+                std::str::from_utf8(what).unwrap(),
+                escape(metadata.name()),
+            );
+            site(
+                metadata,
+                fmt,
+                vec![NanologType::Int],
+                vec![Cow::Borrowed("span")],
+            )
+        });
+        self.write(log_id, timestamp, &[&id.into_u64().to_ne_bytes()]);
+    }
+
+    /// write the fields `record` records, as a record of `kind` (of the span `id`)
+    fn write_fields(
+        &self,
+        metadata: &'static Metadata<'static>,
+        kind: u8,
+        id: Option<&Id>,
+        record: impl FnOnce(&mut Fields),
+    ) {
+        let timestamp = (self.now)();
+        let _ = FIELDS.try_with(|fields| {
+            // a record written while recording another one (by a `Debug` impl) is dropped
+            let Ok(mut fields) = fields.try_borrow_mut() else {
+                return;
+            };
+            fields.clear(kind);
+            record(&mut fields);
+            if kind == RECORD_SPAN && fields.is_empty() {
+                return;
+            }
+            let log_id = self.log_id(metadata, &fields.shape, || fields.site(metadata));
+            let span = id.map(|id| id.into_u64().to_ne_bytes());
+            let span = span.as_ref().map_or(&[][..], |s| &s[..]);
+            self.write(log_id, timestamp, &[&fields.message, span, &fields.args]);
+        });
+    }
+}
+
+impl<S> Layer<S> for NanologLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        self.write_fields(event.metadata(), EVENT, None, |fields| event.record(fields));
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _: Context<'_, S>) {
+        self.write_fields(attrs.metadata(), NEW_SPAN, Some(id), |fields| {
+            attrs.record(fields)
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        self.write_fields(span.metadata(), RECORD_SPAN, Some(id), |fields| {
+            values.record(fields)
+        });
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.write_span(id, ctx, ENTER);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.write_span(id, ctx, EXIT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::message;
+    use crate::nanolog_logger::{
+        create_reader_writer_pair, LogReader, Panic, SharedRingBufferWriter,
+    };
+    use crate::record::raw_records;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    type Writer = SharedRingBufferWriter<{ 64 * 1024 }, Panic>;
+
+    static REGISTRY: SiteRegistry = SiteRegistry::new(2);
+    static WRITER: std::sync::Mutex<Option<Writer>> = std::sync::Mutex::new(None);
+    static NOW: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+    fn now() -> u64 {
+        NOW.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn with_logger(f: &mut dyn FnMut(&mut dyn Logger)) {
+        f(WRITER.lock().unwrap().as_mut().unwrap());
+    }
+
+    struct Unprintable;
+
+    impl Debug for Unprintable {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            tracing::info!("dropped");
+            f.write_str("Unprintable")
+        }
+    }
+
+    #[test]
+    fn events_and_spans() {
+        let (mut reader, writer) = create_reader_writer_pair::<{ 64 * 1024 }, Panic>();
+        *WRITER.lock().unwrap() = Some(writer);
+        let layer = NanologLayer::new(&REGISTRY, now, with_logger);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", id = 7, user = tracing::field::Empty);
+            span.record("user", "bob");
+            let _guard = span.enter();
+            for i in 0..2 {
+                tracing::warn!(target: "net%", attempt = i, rtt = 0.5, "retrying {}", "10.0.0.1");
+            }
+            tracing::debug!(big = u64::MAX, ok = true, thing = ?Unprintable);
+        });
+
+        let generated = LogSite {
+            fmt: Cow::Borrowed("%d"),
+            file: Cow::Borrowed("src/main.rs"),
+            line: 1,
+            args: Cow::Borrowed(&[NanologType::Int]),
+            fields: Cow::Borrowed(&[]),
+        };
+        let mut sites = vec![generated; 2];
+        REGISTRY.sync(&mut sites);
+        let mut chunk = vec![0; 64 * 1024];
+        let n = reader.read(&mut chunk);
+        let lines = raw_records(&sites, &chunk[..n])
+            .map(|r| {
+                let site = &sites[r.log_id as usize];
+                (r.timestamp, message(site, &r.values(site)))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                (
+                    0,
+                    "INFO nanolog_rs_common::layer::tests: new request span=1 id=7".to_string()
+                ),
+                (
+                    1,
+                    "INFO nanolog_rs_common::layer::tests: record request span=1 user=bob"
+                        .to_string()
+                ),
+                (
+                    2,
+                    "INFO nanolog_rs_common::layer::tests: enter request span=1".to_string()
+                ),
+                (
+                    3,
+                    "WARN net%: retrying 10.0.0.1 attempt=0 rtt=0.500000".to_string()
+                ),
+                (
+                    4,
+                    "WARN net%: retrying 10.0.0.1 attempt=1 rtt=0.500000".to_string()
+                ),
+                (
+                    5,
+                    "DEBUG nanolog_rs_common::layer::tests: big=18446744073709551615 ok=true \
+                     thing=Unprintable"
+                        .to_string()
+                ),
+                (
+                    6,
+                    "INFO nanolog_rs_common::layer::tests: exit request span=1".to_string()
+                ),
+            ]
+        );
+        // one site per callsite and shape, the event of the Debug impl was dropped
+        assert_eq!(sites.len(), 2 + 6);
+        assert_eq!(*sites[2].fields, ["span", "id"]);
+        assert_eq!(sites[5].file, file!());
+        assert_eq!(
+            *sites[5].args,
+            [NanologType::Str, NanologType::Int, NanologType::Float]
+        );
+        assert_eq!(*sites[5].fields, ["attempt", "rtt"]);
+    }
+}
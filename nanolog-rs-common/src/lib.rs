@@ -1,29 +1,112 @@
+#[cfg(feature = "lz4")]
+pub mod block;
+pub mod checksum;
+pub mod clock;
 pub mod compression;
+pub mod context;
+pub mod crash;
+pub mod decoder;
+#[cfg(feature = "log")]
+pub mod facade;
+pub mod file_format;
+pub mod format;
+pub mod index;
+#[cfg(feature = "tracing")]
+pub mod layer;
+pub mod merge;
+pub mod metadata;
 pub mod nanolog_logger;
+pub mod record;
+pub mod shm;
+pub mod sink;
+pub mod thread;
 
+#[cfg(target_arch = "x86_64")]
 use core::arch::x86_64::_rdtsc;
+#[cfg(not(target_arch = "x86_64"))]
+use libc::CLOCK_MONOTONIC_RAW;
 use libc::{clock_gettime, timespec, CLOCK_MONOTONIC};
 use regex::Regex;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{SystemTime, UNIX_EPOCH};
+use syn::parse::discouraged::Speculative;
+use syn::spanned::Spanned;
 use syn::{parse::Parse, token::Comma, Expr};
 
 // #[derive(Debug)]
 pub struct Nanolog {
     pub fmt_literal: String,
     pub fmt_specifiers: Vec<NanologType>,
+    /// the named fields that follow the format arguments, see `NanologField`
+    pub fields: Vec<NanologField>,
+    /// the format arguments followed by the values of the fields
     pub punctuate: syn::punctuated::Punctuated<Expr, Comma>,
     pub sink: Expr,
 }
 
 impl Nanolog {
+    /// the types of the arguments written to the ring buffer, the format arguments followed by
+    /// the fields
+    pub fn arg_types(&self) -> Vec<NanologType> {
+        let fields = self.fields.iter().map(|f| f.ty);
+        self.fmt_specifiers.iter().copied().chain(fields).collect()
+    }
+
     pub fn get_log_type_suffix(&self) -> String {
-        self.fmt_specifiers
+        self.arg_types()
             .iter()
             .map(|s| s.to_type_string())
             .collect::<String>()
     }
 }
 
+/// a named argument of `nanolog!`, `name = value` for an `i64`, `name: u64 = value` or
+/// `name: f64 = value` (`i64` can be spelled out too)
+/// - fields aren't part of the format string, they are written (and decoded) as key value pairs
+///   after the message
+/// - the type of a field is needed before the types are known (build.rs generates the log sites
+///   from the source), so it isn't inferred. The value of a field without a type goes through
+///   `IntField`, so that passing a float says how to fix it
+pub struct NanologField {
+    pub name: String,
+    pub ty: NanologType,
+    pub value: Expr,
+}
+
+impl Parse for NanologField {
+    fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
+        let name: syn::Ident = input.parse()?;
+        let ty = if input.peek(syn::Token![:]) {
+            input.parse::<syn::Token![:]>()?;
+            let ty: syn::Ident = input.parse()?;
+            match ty.to_string().as_str() {
+                "i64" => Some(NanologType::Int),
+                "u64" => Some(NanologType::UInt),
+                "f64" => Some(NanologType::Float),
+                _ => {
+                    return Err(syn::Error::new(
+                        ty.span(),
+                        "fields are either i64, u64 or f64",
+                    ))
+                }
+            }
+        } else {
+            None
+        };
+        input.parse::<syn::Token![=]>()?;
+        let mut value: Expr = input.parse()?;
+        if ty.is_none() {
+            value = syn::parse_quote_spanned! {value.span()=>
+                ::nanolog_rs_common::IntField::int_field(#value)
+            };
+        }
+        Ok(NanologField {
+            name: name.to_string(),
+            ty: ty.unwrap_or(NanologType::Int),
+            value,
+        })
+    }
+}
+
 impl Parse for Nanolog {
     fn parse(input: syn::parse::ParseStream) -> syn::Result<Self> {
         let sink: syn::Expr = input.parse()?;
@@ -31,55 +114,121 @@ impl Parse for Nanolog {
         let fmt_literal: syn::LitStr = input.parse()?;
         let fmt_string = fmt_literal.value();
         let fmt_specifiers = find_format_specifiers(&fmt_string);
-        if input.is_empty() {
-            if fmt_specifiers.len() != 0 {
-                Err(syn::Error::new(
-                    fmt_literal.span(),
-                    format!("No arguments found. But format specifiers found {fmt_specifiers:?}",),
-                ))
-            } else {
-                Ok(Nanolog {
-                    fmt_literal: fmt_string,
-                    fmt_specifiers,
-                    punctuate: syn::punctuated::Punctuated::<Expr, Comma>::new(),
-                    sink,
-                })
-            }
-        } else {
+        let mut punctuate = syn::punctuated::Punctuated::<Expr, Comma>::new();
+        let mut fields: Vec<NanologField> = vec![];
+        while !input.is_empty() {
             input.parse::<syn::Token![,]>()?;
-            let punctuated =
-                syn::punctuated::Punctuated::<syn::Expr, syn::Token![,]>::parse_terminated(input)?;
-            if punctuated.len() == fmt_specifiers.len() {
-                Ok(Nanolog {
-                    fmt_literal: fmt_string,
-                    fmt_specifiers,
-                    punctuate: punctuated,
-                    sink,
-                })
-            } else {
-                Err(syn::Error::new(
-                    fmt_literal.span(),
-                    format!(
-                        "Number of format specifiers {fmt_specifiers:?} != Number of arguments {}",
-                        punctuated.len()
-                    ),
-                ))
+            if input.is_empty() {
+                break;
+            }
+            // `a::b` or `a == b` don't parse as a field and are taken as an argument
+            let fork = input.fork();
+            if let Ok(field) = fork.parse::<NanologField>() {
+                if fork.is_empty() || fork.peek(syn::Token![,]) {
+                    input.advance_to(&fork);
+                    if fields.iter().any(|f| f.name == field.name) {
+                        return Err(syn::Error::new(
+                            field.value.span(),
+                            format!("duplicate field {}", field.name),
+                        ));
+                    }
+                    fields.push(field);
+                    continue;
+                }
             }
+            let expr: Expr = input.parse()?;
+            if !fields.is_empty() {
+                return Err(syn::Error::new(
+                    expr.span(),
+                    "format arguments must come before the fields",
+                ));
+            }
+            punctuate.push(expr);
+        }
+        if punctuate.len() != fmt_specifiers.len() {
+            return Err(syn::Error::new(
+                fmt_literal.span(),
+                format!(
+                    "Number of format specifiers {fmt_specifiers:?} != Number of arguments {}",
+                    punctuate.len()
+                ),
+            ));
         }
+        punctuate.extend(fields.iter().map(|f| f.value.clone()));
+        Ok(Nanolog {
+            fmt_literal: fmt_string,
+            fmt_specifiers,
+            fields,
+            punctuate,
+            sink,
+        })
     }
 }
 
-#[derive(Debug)]
+/// the values of the `nanolog!` fields without a type, any integer
+/// - the field is an `i64`, values out of its range are clamped to it. `name: u64 = value` keeps
+///   all of the values of a `u64`
+#[diagnostic::on_unimplemented(
+    message = "a `nanolog!` field without a type is an integer, not `{Self}`",
+    label = "expected an integer",
+    note = "the type of a float field is spelled out: `name: f64 = value`"
+)]
+pub trait IntField {
+    fn int_field(self) -> i64;
+}
+
+macro_rules! int_fields {
+    ($($t:ty),*) => {$(
+        impl IntField for $t {
+            fn int_field(self) -> i64 {
+                i64::try_from(self).unwrap_or(if self > 0 { i64::MAX } else { i64::MIN })
+            }
+        }
+    )*};
+}
+
+int_fields!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NanologType {
     Int,
+    /// a `u64` field of `nanolog!`, written like an `Int` (and decoded as one, with the same bits),
+    /// the output formats show the values above `i64::MAX` as strings, see `format::field_values`
+    UInt,
     Float,
+    /// a string that was formatted at runtime, only used by the `log` crate facade (see
+    /// `facade`) and the tracing layer, `nanolog!` format strings can't take strings
+    Str,
 }
 
 impl NanologType {
-    fn to_type_string(&self) -> &str {
+    pub fn to_type_string(&self) -> &str {
         match self {
             NanologType::Int => "D",
+            NanologType::UInt => "U",
             NanologType::Float => "F",
+            NanologType::Str => "S",
+        }
+    }
+
+    pub fn from_type_char(c: char) -> Option<Self> {
+        match c {
+            'D' => Some(NanologType::Int),
+            'U' => Some(NanologType::UInt),
+            'F' => Some(NanologType::Float),
+            'S' => Some(NanologType::Str),
+            _ => None,
+        }
+    }
+
+    /// number of bytes the argument takes up in the ring buffer
+    /// - strings take up their length (`size` bytes) followed by the string
+    pub fn size(&self) -> usize {
+        match self {
+            NanologType::Int => std::mem::size_of::<i64>(),
+            NanologType::UInt => std::mem::size_of::<u64>(),
+            NanologType::Float => std::mem::size_of::<f64>(),
+            NanologType::Str => std::mem::size_of::<u64>(),
         }
     }
 }
@@ -131,10 +280,26 @@ pub fn get_monotonic_time_micros() -> u64 {
     (ts.tv_sec as u64) * 1_000_000 + (ts.tv_nsec as u64) / 1_000
 }
 
+/// reads the TSC, the fast path of the `Rdtsc` clock
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
 pub fn get_rdtsc_time() -> u64 {
     unsafe { _rdtsc() }
 }
 
+/// there is no TSC outside of x86_64, falls back to `CLOCK_MONOTONIC_RAW` in nanoseconds
+/// - the consumer calibrates the ticks per second like it does for the TSC
+#[cfg(not(target_arch = "x86_64"))]
+#[inline(always)]
+pub fn get_rdtsc_time() -> u64 {
+    let mut ts = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { clock_gettime(CLOCK_MONOTONIC_RAW, &mut ts) };
+    (ts.tv_sec as u64) * 1_000_000_000 + ts.tv_nsec as u64
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -172,6 +337,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_fields() {
+        let n = syn::parse_str::<syn::Macro>(
+            "nanolog!(sink, \"filled %d\", a::b, id = order.id, px: f64 = price, n: i64 = a == b, \
+             sent: u64 = c,)",
+        )
+        .unwrap()
+        .parse_body::<Nanolog>()
+        .unwrap();
+        assert_eq!(n.punctuate.len(), 5);
+        let fields = n.fields.iter().map(|f| (f.name.as_str(), f.ty));
+        assert_eq!(
+            fields.collect::<Vec<_>>(),
+            [
+                ("id", NanologType::Int),
+                ("px", NanologType::Float),
+                ("n", NanologType::Int),
+                ("sent", NanologType::UInt)
+            ]
+        );
+        assert_eq!(n.get_log_type_suffix(), "DDFDU");
+        // the value of a field without a type goes through IntField
+        assert!(matches!(n.fields[0].value, Expr::Call(_)));
+        assert!(matches!(n.fields[2].value, Expr::Binary(_)));
+    }
+
+    #[test]
+    fn int_fields() {
+        assert_eq!(IntField::int_field(5u8), 5);
+        assert_eq!(IntField::int_field(-5isize), -5);
+        assert_eq!(IntField::int_field(1u64 << 40), 1 << 40);
+        // clamped, `name: u64 = value` keeps them
+        assert_eq!(IntField::int_field(u64::MAX), i64::MAX);
+        assert_eq!(IntField::int_field(i128::MIN), i64::MIN);
+    }
+
+    #[test]
+    fn parse_fail_fields() {
+        for body in [
+            "sink, \"%d\", id = 1, a",
+            "sink, \"\", id = 1, id = 2",
+            "sink, \"\", id: u32 = 1",
+            "sink, \"\", id: usize = 1",
+        ] {
+            assert!(syn::parse_str::<syn::Macro>(&format!("nanolog!({body})"))
+                .unwrap()
+                .parse_body::<Nanolog>()
+                .is_err());
+        }
+    }
+
     #[test]
     fn parse_success_2_args() {
         assert!(
@@ -0,0 +1,231 @@
+use crate::decoder::{Decoder, Record};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::io;
+
+/// a record waiting for the records of the other streams, ordered by timestamp and then by the
+/// order it was read in (so the records of a stream keep their order)
+struct Pending {
+    seq: u64,
+    record: Record,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.record.timestamp, self.seq).cmp(&(other.record.timestamp, other.seq))
+    }
+}
+
+/// merges the records of all streams (threads) of a decoder into a single stream ordered by
+/// timestamp, as far as the window allows
+/// - the log reader goes over the ring buffers in turns, writing at most one chunk per stream
+///   each time, so a record is usually written at most two turns after a record (of any stream)
+///   with a later timestamp. A record is returned once the chunks of `window` turns (that many
+///   chunks per stream seen so far, 2 by default) were read after every record with a later or
+///   equal timestamp
+/// - records are only held back as long as that, so merging a file doesn't decode all of it
+///   first
+/// - the order is best effort: a record that shows up later than that (e.g. the consumer fell
+///   behind on a ring buffer, or files of several processes were concatenated) is returned once
+///   it's read, after records with later timestamps. These are counted by `out_of_order`, a
+///   larger window holds back more records to avoid them
+/// - errors are returned as soon as they are encountered, ahead of the records held back
+pub struct Merge<'a> {
+    decoder: Decoder<'a>,
+    heap: BinaryHeap<Reverse<Pending>>,
+    seq: u64,
+    streams: HashSet<u64>,
+    /// the number of turns a record is held back for
+    turns: usize,
+    /// the latest timestamp of each of the most recently read chunks
+    window: VecDeque<u64>,
+    /// no record read from now on has an earlier timestamp than this
+    watermark: Option<u64>,
+    /// the timestamp of the last returned record
+    last: Option<u64>,
+    out_of_order: u64,
+    done: bool,
+}
+
+impl<'a> Merge<'a> {
+    pub fn new(decoder: Decoder<'a>) -> Self {
+        Self {
+            decoder,
+            heap: BinaryHeap::new(),
+            seq: 0,
+            streams: HashSet::new(),
+            turns: 2,
+            window: VecDeque::new(),
+            watermark: None,
+            last: None,
+            out_of_order: 0,
+            done: false,
+        }
+    }
+
+    /// holds records back for `turns` turns of the log reader instead of 2
+    pub fn with_window(mut self, turns: usize) -> Self {
+        self.turns = turns;
+        self
+    }
+
+    /// the number of records returned so far with an earlier timestamp than a record returned
+    /// before them
+    pub fn out_of_order(&self) -> u64 {
+        self.out_of_order
+    }
+
+    /// the decoder, e.g. for its sites or to convert timestamps
+    /// - the decoder is ahead of the returned records, `Decoder::timestamp_nanos` converts them
+    ///   with a (slightly) later anchor, which is just as accurate
+    pub fn decoder(&self) -> &Decoder<'a> {
+        &self.decoder
+    }
+
+    fn push_chunk(&mut self, records: Vec<Record>) {
+        let Some(latest) = records.iter().map(|r| r.timestamp).max() else {
+            return;
+        };
+        self.streams.insert(records[0].stream);
+        for record in records {
+            self.heap.push(Reverse(Pending {
+                seq: self.seq,
+                record,
+            }));
+            self.seq += 1;
+        }
+        self.window.push_back(latest);
+        while self.window.len() > self.turns * self.streams.len() {
+            let latest = self.window.pop_front().unwrap();
+            self.watermark = self.watermark.max(Some(latest));
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.heap.peek() {
+                Some(Reverse(p)) if self.done || Some(p.record.timestamp) <= self.watermark => {
+                    let timestamp = p.record.timestamp;
+                    if self.last.is_some_and(|last| timestamp < last) {
+                        self.out_of_order += 1;
+                    }
+                    self.last = self.last.max(Some(timestamp));
+                    return self.heap.pop().map(|Reverse(p)| Ok(p.record));
+                }
+                None if self.done => return None,
+                _ => {}
+            }
+            let mut records = vec![];
+            let result = self.decoder.decode_next_chunk(&mut records);
+            self.push_chunk(records);
+            match result {
+                None => self.done = true,
+                Some(Err(e)) => return Some(Err(e)),
+                Some(Ok(())) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::Context;
+    use crate::metadata::LogSite;
+    use crate::record::Value;
+    use crate::sink::{Encoding, MemorySink, Sink};
+    use crate::NanologType;
+    use std::borrow::Cow;
+
+    const SITES: [LogSite; 1] = [LogSite {
+        fmt: Cow::Borrowed("%d"),
+        file: Cow::Borrowed("src/main.rs"),
+        line: 3,
+        args: Cow::Borrowed(&[NanologType::Int]),
+        fields: Cow::Borrowed(&[]),
+    }];
+
+    fn chunk(records: &[(u64, i64)]) -> Vec<u8> {
+        let mut chunk = vec![];
+        for (timestamp, v) in records {
+            chunk.extend_from_slice(&0u64.to_ne_bytes());
+            chunk.extend_from_slice(&timestamp.to_ne_bytes());
+            chunk.extend_from_slice(&v.to_ne_bytes());
+        }
+        chunk
+    }
+
+    #[test]
+    fn merge_streams() {
+        let mut sink = MemorySink::new(Encoding::Compressed);
+        let mut expected = vec![];
+        // three threads logging at different rates, each turn's chunk overlaps the other
+        // streams' chunks of the turn in time
+        for turn in 0..50u64 {
+            for (stream, step) in [(0u64, 1), (1, 3), (2, 50)] {
+                let records = (turn * 50..turn * 50 + 50)
+                    .step_by(step)
+                    .map(|t| (t * 10 + stream, t as i64))
+                    .collect::<Vec<_>>();
+                sink.write_chunk(stream, &SITES, &chunk(&records)).unwrap();
+                expected.extend(records.iter().map(|&(timestamp, v)| Record {
+                    stream,
+                    log_id: 0,
+                    timestamp,
+                    values: vec![Value::Int(v)],
+                    context: Context::default(),
+                }));
+            }
+            // an idle thread only shows up once
+            if turn == 10 {
+                sink.write_chunk(3, &SITES, &chunk(&[(turn * 500 + 3, -1)]))
+                    .unwrap();
+                expected.push(Record {
+                    stream: 3,
+                    log_id: 0,
+                    timestamp: turn * 500 + 3,
+                    values: vec![Value::Int(-1)],
+                    context: Context::default(),
+                });
+            }
+        }
+        expected.sort_by_key(|r| r.timestamp);
+
+        let contents = sink.contents();
+        let mut merge = Merge::new(Decoder::new(&contents).unwrap());
+        let merged = merge.by_ref().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(merged, expected);
+        assert_eq!(merge.out_of_order(), 0);
+
+        // without holding records back, the overlapping chunks come out of order
+        let mut merge = Merge::new(Decoder::new(&contents).unwrap()).with_window(0);
+        let mut merged = merge.by_ref().collect::<io::Result<Vec<_>>>().unwrap();
+        assert!(merge.out_of_order() > 0);
+        assert_ne!(merged, expected);
+        merged.sort_by_key(|r| r.timestamp);
+        assert_eq!(merged, expected);
+
+        // records are returned before the end of the input
+        let mut merge = Merge::new(Decoder::new(&contents).unwrap());
+        merge.next().unwrap().unwrap();
+        assert!(merge.heap.len() < expected.len() / 4);
+    }
+}
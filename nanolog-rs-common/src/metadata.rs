@@ -0,0 +1,261 @@
+use crate::compression::ChunkHeader;
+use crate::NanologType;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// log sites registered at runtime are written as chunks of this stream, which no ring buffer
+/// uses
+pub const SITE_STREAM: u64 = u64::MAX - 2;
+
+/// everything known at build time about a single `nanolog!` invocation, indexed by its log ID
+/// - the build script generates these as constants, so the fields are Cows: borrowed when
+///   generated, owned when deserialized by another process
+/// - sites can also be registered at runtime (see `SiteRegistry`), their log IDs follow the
+///   generated ones
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSite {
+    pub fmt: Cow<'static, str>,
+    pub file: Cow<'static, str>,
+    pub line: u32,
+    pub args: Cow<'static, [NanologType]>,
+    /// the names of the last `fields.len()` args, which aren't part of the format string (see
+    /// `NanologField`)
+    pub fields: Cow<'static, [Cow<'static, str>]>,
+}
+
+impl LogSite {
+    /// number of argument bytes that follow the log ID and timestamp of a record, `args` starts
+    /// with them (the length of strings is only known from the record)
+    /// - None if they don't fit in `args`
+    pub fn args_size(&self, args: &[u8]) -> Option<usize> {
+        let mut size = 0usize;
+        for arg in self.args.iter() {
+            if *arg == NanologType::Str {
+                let len = args.get(size..size.checked_add(8)?)?;
+                let len = u64::from_ne_bytes(len.try_into().unwrap());
+                size = size.checked_add(usize::try_from(len).ok()?)?;
+            }
+            size = size.checked_add(arg.size())?;
+        }
+        (size <= args.len()).then_some(size)
+    }
+
+    /// the level of the records of the site, if its format string starts with one ("INFO ..."),
+    /// as the sites of the `log` crate facade and the tracing layer do
+    pub fn level(&self) -> Option<&str> {
+        let level = self.fmt.split(' ').next()?;
+        ["ERROR", "WARN", "INFO", "DEBUG", "TRACE"]
+            .contains(&level)
+            .then_some(level)
+    }
+
+    /// the values of the format string and the values of the named fields of a record
+    pub fn split_values<'v, T>(&self, values: &'v [T]) -> (&'v [T], &'v [T]) {
+        values.split_at(values.len().saturating_sub(self.fields.len()))
+    }
+}
+
+/// layout (all integers are little endian):
+/// [num sites: u32] followed by [line: u32][file len: u32][file][fmt len: u32][fmt][num args: u32][args]
+/// [num fields: u32] and [name len: u32][name] for every field, for every site, where args are the
+/// type characters used in the log type suffix ("D", "U", "F", "S")
+pub fn serialize_log_sites(sites: &[LogSite]) -> Vec<u8> {
+    fn write_str(out: &mut Vec<u8>, s: &str) {
+        out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    let mut out = vec![];
+    out.extend_from_slice(&(sites.len() as u32).to_le_bytes());
+    for site in sites {
+        out.extend_from_slice(&site.line.to_le_bytes());
+        write_str(&mut out, &site.file);
+        write_str(&mut out, &site.fmt);
+        let args = site
+            .args
+            .iter()
+            .map(|a| a.to_type_string())
+            .collect::<String>();
+        write_str(&mut out, &args);
+        out.extend_from_slice(&(site.fields.len() as u32).to_le_bytes());
+        for name in site.fields.iter() {
+            write_str(&mut out, name);
+        }
+    }
+    out
+}
+
+/// identifies a table of log sites, e.g. to tell whether records were written by the same build
+pub fn sites_checksum(sites: &[LogSite]) -> u32 {
+    crate::checksum::crc32c(&serialize_log_sites(sites))
+}
+
+/// a site registered at runtime, framed as a chunk of `SITE_STREAM` holding [log ID: u64 (little
+/// endian)] followed by the site serialized like the header's metadata
+pub fn write_site_chunk(log_id: u64, site: &LogSite, out: &mut impl Write) -> io::Result<()> {
+    let mut payload = log_id.to_le_bytes().to_vec();
+    payload.extend(serialize_log_sites(std::slice::from_ref(site)));
+    ChunkHeader::new(SITE_STREAM, 0, &payload).write(out)?;
+    out.write_all(&payload)
+}
+
+/// the inverse of `write_site_chunk`, from the chunk's payload
+pub fn read_site_chunk(payload: &[u8]) -> io::Result<(u64, LogSite)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid site registration");
+    let (log_id, site) = payload.split_first_chunk::<8>().ok_or_else(invalid)?;
+    let mut sites = deserialize_log_sites(site)?;
+    if sites.len() != 1 {
+        return Err(invalid());
+    }
+    Ok((u64::from_le_bytes(*log_id), sites.pop().unwrap()))
+}
+
+/// the sites registered at runtime, for records whose call sites build.rs doesn't know about (e.g.
+/// `tracing` callsites)
+/// - a site must be registered before the first record of it is written to a ring buffer, and
+///   the log reader must `sync` after reading a ring buffer, so it knows the sites of everything it
+///   read
+pub struct SiteRegistry {
+    /// the log ID of the first registered site, the number of generated sites
+    first_id: u64,
+    sites: Mutex<Vec<LogSite>>,
+    len: AtomicUsize,
+}
+
+impl SiteRegistry {
+    pub const fn new(first_id: u64) -> Self {
+        Self {
+            first_id,
+            sites: Mutex::new(vec![]),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// returns the log ID of the site
+    pub fn register(&self, site: LogSite) -> u64 {
+        let mut sites = self.sites.lock().unwrap();
+        sites.push(site);
+        self.len.store(sites.len(), Ordering::Release);
+        self.first_id + sites.len() as u64 - 1
+    }
+
+    /// append the sites registered since the last call to `sites`, which starts with the
+    /// generated ones
+    pub fn sync(&self, sites: &mut Vec<LogSite>) {
+        let known = sites.len() - self.first_id as usize;
+        if self.len.load(Ordering::Acquire) > known {
+            sites.extend_from_slice(&self.sites.lock().unwrap()[known..]);
+        }
+    }
+}
+
+pub fn deserialize_log_sites(mut bytes: &[u8]) -> io::Result<Vec<LogSite>> {
+    fn invalid(msg: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+    }
+    fn read_u32(bytes: &mut &[u8]) -> io::Result<u32> {
+        let Some((int, rest)) = bytes.split_first_chunk::<4>() else {
+            return Err(invalid("truncated log site metadata"));
+        };
+        *bytes = rest;
+        Ok(u32::from_le_bytes(*int))
+    }
+    fn read_string(bytes: &mut &[u8]) -> io::Result<String> {
+        let len = read_u32(bytes)? as usize;
+        if bytes.len() < len {
+            return Err(invalid("truncated log site metadata"));
+        }
+        let (s, rest) = bytes.split_at(len);
+        *bytes = rest;
+        String::from_utf8(s.to_vec()).map_err(|_| invalid("log site metadata is not utf8"))
+    }
+
+    let n = read_u32(&mut bytes)?;
+    let mut sites = vec![];
+    for _ in 0..n {
+        let line = read_u32(&mut bytes)?;
+        let file = read_string(&mut bytes)?;
+        let fmt = read_string(&mut bytes)?;
+        let args = read_string(&mut bytes)?
+            .chars()
+            .map(|c| NanologType::from_type_char(c).ok_or_else(|| invalid("unknown argument type")))
+            .collect::<io::Result<Vec<_>>>()?;
+        let fields = (0..read_u32(&mut bytes)?)
+            .map(|_| read_string(&mut bytes).map(Cow::Owned))
+            .collect::<io::Result<Vec<_>>>()?;
+        if fields.len() > args.len() {
+            return Err(invalid("more fields than arguments"));
+        }
+        sites.push(LogSite {
+            fmt: fmt.into(),
+            file: file.into(),
+            line,
+            args: args.into(),
+            fields: fields.into(),
+        });
+    }
+    Ok(sites)
+}
+
+#[test]
+fn log_sites_round_trip() {
+    let sites = vec![
+        LogSite {
+            fmt: Cow::Borrowed("Hello, world!"),
+            file: Cow::Borrowed("src/main.rs"),
+            line: 1,
+            args: Cow::Borrowed(&[]),
+            fields: Cow::Borrowed(&[]),
+        },
+        LogSite {
+            fmt: Cow::Borrowed("%f filled"),
+            file: Cow::Borrowed("src/lib.rs"),
+            line: 42,
+            args: Cow::Borrowed(&[NanologType::Float, NanologType::Int]),
+            fields: Cow::Borrowed(&[Cow::Borrowed("id")]),
+        },
+    ];
+    let bytes = serialize_log_sites(&sites);
+    assert_eq!(deserialize_log_sites(&bytes).unwrap(), sites);
+    assert!(deserialize_log_sites(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn registered_sites() {
+    const GENERATED: [LogSite; 1] = [LogSite {
+        fmt: Cow::Borrowed("Hello, world!"),
+        file: Cow::Borrowed("src/main.rs"),
+        line: 1,
+        args: Cow::Borrowed(&[]),
+        fields: Cow::Borrowed(&[]),
+    }];
+    let registry = SiteRegistry::new(1);
+    let mut sites = GENERATED.to_vec();
+    registry.sync(&mut sites);
+    assert_eq!(sites.len(), 1);
+
+    let site = LogSite {
+        fmt: "INFO app: %s x=%d".into(),
+        file: "src/app.rs".into(),
+        line: 7,
+        args: vec![NanologType::Str, NanologType::Int].into(),
+        fields: Cow::Borrowed(&[]),
+    };
+    assert_eq!(registry.register(site.clone()), 1);
+    assert_eq!(registry.register(GENERATED[0].clone()), 2);
+    registry.sync(&mut sites);
+    assert_eq!(
+        sites,
+        [GENERATED[0].clone(), site.clone(), GENERATED[0].clone()]
+    );
+
+    let mut chunk = vec![];
+    write_site_chunk(1, &site, &mut chunk).unwrap();
+    let mut input = chunk.as_slice();
+    let header = ChunkHeader::read(&mut input).unwrap();
+    assert_eq!((header.stream, header.records), (SITE_STREAM, 0));
+    assert_eq!(read_site_chunk(input).unwrap(), (1, site));
+    assert!(read_site_chunk(&input[..7]).is_err());
+}
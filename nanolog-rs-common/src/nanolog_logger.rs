@@ -1,5 +1,9 @@
 use std::cell::UnsafeCell;
+use std::fs;
+use std::io;
 use std::marker::PhantomData;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::sync::atomic;
 
 struct IsPowerOf2<const N: usize>;
@@ -7,43 +11,160 @@ impl<const N: usize> IsPowerOf2<N> {
     const OK: () = assert!(N & (N - 1) == 0);
 }
 
-struct SharedRingBuf<const N: usize> {
+/// identifies a file as a nanolog ring buffer
+const RING_MAGIC: u64 = u64::from_le_bytes(*b"nanoring");
+const RING_HEADER_SIZE: usize = std::mem::size_of::<RingHeader>();
+
+/// repr(C) so that the layout of a ring buffer that lives outside of the process (in a file or in
+/// shared memory) is known to whoever reads it
+#[repr(C)]
+pub(crate) struct RingHeader {
+    magic: u64,
+    pub(crate) capacity: usize,
+    /// the `sites_checksum` of the log sites the records refer to, 0 if unknown
+    pub(crate) sites_checksum: u32,
+    pub(crate) head: atomic::AtomicUsize,
+    pub(crate) tail: atomic::AtomicUsize,
+}
+
+#[repr(C)]
+pub(crate) struct SharedRingBuf<const N: usize> {
+    header: RingHeader,
     arr: UnsafeCell<[u8; N]>,
-    head: atomic::AtomicUsize,
-    tail: atomic::AtomicUsize,
 }
 
 impl<const N: usize> SharedRingBuf<N> {
     fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
         let _ = IsPowerOf2::<N>::OK;
         Self {
+            header: RingHeader {
+                magic: RING_MAGIC,
+                capacity: N,
+                sites_checksum: 0,
+                head: 0.into(),
+                tail: 0.into(),
+            },
             arr: [0; N].into(),
-            head: 0.into(),
-            tail: 0.into(),
         }
     }
 
-    /// caller must guarantee that slices must not overlap with existing mutable slices
-    unsafe fn slice(&self, start: usize, len: usize) -> &[u8] {
+    /// map a freshly created file at `path` and place the ring buffer in it, so that committed
+    /// writes survive the process being killed
+    fn new_file_backed(path: &Path, sites_checksum: u32) -> io::Result<&'static Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.set_len(std::mem::size_of::<Self>() as u64)?;
+        let ptr = map_shared(file.as_raw_fd(), std::mem::size_of::<Self>())?;
+        Ok(unsafe { Self::from_mapping(ptr, sites_checksum) })
+    }
+
+    /// caller must guarantee that `ptr` points to a zero filled, writable mapping that is at least
+    /// size_of::<Self>() large and is never unmapped
+    pub(crate) unsafe fn from_mapping(ptr: *mut u8, sites_checksum: u32) -> &'static Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = IsPowerOf2::<N>::OK;
+        // zero filled means that head, tail and arr are already initialized
+        let rb = ptr.cast::<Self>();
         unsafe {
-            std::slice::from_raw_parts((self.arr.get().cast_const() as *const u8).add(start), len)
+            std::ptr::addr_of_mut!((*rb).header.magic).write(RING_MAGIC);
+            std::ptr::addr_of_mut!((*rb).header.capacity).write(N);
+            std::ptr::addr_of_mut!((*rb).header.sites_checksum).write(sites_checksum);
+            &*rb
         }
     }
 
     /// caller must guarantee that mutable slices are exclusive (no other slice overlaps with it)
+    #[allow(clippy::mut_from_ref)]
     unsafe fn slice_mut(&self, start: usize, len: usize) -> &mut [u8] {
         unsafe { std::slice::from_raw_parts_mut((self.arr.get() as *mut u8).add(start), len) }
     }
 }
 unsafe impl<const N: usize> Sync for SharedRingBuf<N> {}
 
+/// map `size` bytes of `fd` as shared memory. The mapping stays valid after `fd` is closed
+pub(crate) fn map_shared(fd: RawFd, size: usize) -> io::Result<*mut u8> {
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ptr.cast())
+}
+
+impl RingHeader {
+    /// caller must guarantee that `ptr` points to a mapping of `len` bytes that stays mapped for
+    /// the lifetime 'a
+    /// - returns the header and the ring buffer array following it
+    pub(crate) unsafe fn from_mapping<'a>(
+        ptr: *const u8,
+        len: usize,
+    ) -> io::Result<(&'a Self, *const u8)> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if len < RING_HEADER_SIZE || ptr.align_offset(std::mem::align_of::<Self>()) != 0 {
+            return Err(invalid("not a nanolog ring buffer"));
+        }
+        let header = unsafe { &*ptr.cast::<Self>() };
+        if header.magic != RING_MAGIC
+            || header.capacity == 0
+            || len != RING_HEADER_SIZE + header.capacity
+        {
+            return Err(invalid("not a nanolog ring buffer"));
+        }
+        Ok((header, unsafe { ptr.add(RING_HEADER_SIZE) }))
+    }
+}
+
+/// copy [reader_head, tail) out of the ring buffer array `arr` of size `capacity` into `buf`
+/// - caller must guarantee that `arr` is valid for `capacity` bytes and that the range is
+///   committed (no writer touches it)
+pub(crate) unsafe fn copy_committed(
+    arr: *const u8,
+    capacity: usize,
+    reader_head: usize,
+    tail: usize,
+    buf: &mut [u8],
+) {
+    let n = tail - reader_head;
+    let start = reader_head % capacity;
+
+    if start + n <= capacity {
+        buf[..n].copy_from_slice(unsafe { std::slice::from_raw_parts(arr.add(start), n) });
+    } else {
+        let n_temp = capacity - start;
+        buf[..n_temp]
+            .copy_from_slice(unsafe { std::slice::from_raw_parts(arr.add(start), n_temp) });
+        buf[n_temp..n].copy_from_slice(unsafe { std::slice::from_raw_parts(arr, n - n_temp) });
+    }
+}
+
 pub trait LogReader {
     fn read(&mut self, buf: &mut [u8]) -> usize;
+    /// like `read`, but stops at `end` (a `tail` seen earlier), so that catching up with a writer
+    /// that keeps writing ends
+    fn read_until(&mut self, buf: &mut [u8], end: usize) -> usize;
+    /// how far the writer has committed, always the end of a record
+    fn tail(&self) -> usize;
+    fn commit_read(&mut self);
+    /// whether the ring buffer outlives the process (see `commit_read`)
+    fn is_persistent(&self) -> bool;
 }
 
 pub struct SharedRingBufferReader<const N: usize> {
     rb: &'static SharedRingBuf<N>,
     reader_head: usize, // known
+    persistent: bool,
 }
 
 unsafe impl<const N: usize> Send for SharedRingBufferReader<N> {}
@@ -51,31 +172,55 @@ unsafe impl<const N: usize> Send for SharedRingBufferReader<N> {}
 impl<const N: usize> LogReader for SharedRingBufferReader<N> {
     /// - the buffer must be size N (this is because upto N bytes can be returned by read all)
     /// - the function returns the number of bytes read
+    /// - for in memory ring buffers the space is handed back to the writer right away, persistent
+    ///   ring buffers hold on to it until `commit_read`
     fn read(&mut self, buf: &mut [u8]) -> usize {
-        let tail = self.rb.tail.load(atomic::Ordering::Acquire); // other thread writes this value
-                                                                 // so I need to load it as acquire
+        self.read_until(buf, usize::MAX)
+    }
 
-        let n = tail - self.reader_head;
-        if n == 0 {
+    fn read_until(&mut self, buf: &mut [u8], end: usize) -> usize {
+        let tail = self.tail().min(end);
+        if tail <= self.reader_head {
             return 0;
         }
+        let n = tail - self.reader_head;
 
-        let start = self.reader_head % N;
-        let end = tail % N;
-
-        if start < end {
-            buf[..n].copy_from_slice(unsafe { self.rb.slice(start, n) });
-        } else {
-            let n_temp = N - start;
-            buf[..n_temp].copy_from_slice(unsafe { self.rb.slice(start, n_temp) });
-            buf[n_temp..n].copy_from_slice(unsafe { self.rb.slice(0, end) });
-        }
+        unsafe {
+            copy_committed(
+                self.rb.arr.get().cast_const().cast(),
+                N,
+                self.reader_head,
+                tail,
+                buf,
+            )
+        };
 
         // consumed all the way upto tail, so we can just store it directly
         self.reader_head = tail;
-        self.rb.head.store(tail, atomic::Ordering::Release);
+        if !self.persistent {
+            self.rb.header.head.store(tail, atomic::Ordering::Release);
+        }
         n
     }
+
+    /// hand the space of everything read so far back to the writer
+    /// - for persistent ring buffers this must only be called once the data that was read is
+    ///   durable, whatever lies between head and tail is what gets recovered after a crash
+    fn commit_read(&mut self) {
+        self.rb
+            .header
+            .head
+            .store(self.reader_head, atomic::Ordering::Release);
+    }
+
+    fn tail(&self) -> usize {
+        // other thread writes this value so I need to load it as acquire
+        self.rb.header.tail.load(atomic::Ordering::Acquire)
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.persistent
+    }
 }
 
 pub trait Logger {
@@ -83,6 +228,16 @@ pub trait Logger {
     fn commit_write(&mut self);
 }
 
+/// collects the records the way a chunk read from a ring buffer holds them, e.g. to add records to
+/// a chunk
+impl Logger for Vec<u8> {
+    fn write(&mut self, buf: &[u8]) {
+        self.extend_from_slice(buf);
+    }
+
+    fn commit_write(&mut self) {}
+}
+
 pub struct SharedRingBufferWriter<const N: usize, WaitStrategy> {
     rb: &'static SharedRingBuf<N>,
     writer_head: usize, // best conservative guess
@@ -104,7 +259,7 @@ impl<const N: usize> WithWaitStrategy for SharedRingBufferWriter<N, Spin> {
             if len <= remaining {
                 break;
             }
-            self.writer_head = self.rb.head.load(atomic::Ordering::Acquire);
+            self.writer_head = self.rb.header.head.load(atomic::Ordering::Acquire);
         }
     }
 }
@@ -112,7 +267,7 @@ impl<const N: usize> WithWaitStrategy for SharedRingBufferWriter<N, Spin> {
 pub struct Panic {}
 impl<const N: usize> WithWaitStrategy for SharedRingBufferWriter<N, Panic> {
     fn wait_to_write(&mut self, len: usize) {
-        let n = self.writer_tail - self.rb.head.load(atomic::Ordering::Acquire);
+        let n = self.writer_tail - self.rb.header.head.load(atomic::Ordering::Acquire);
         let remaining = N - n;
 
         if len > remaining {
@@ -156,6 +311,7 @@ where
     /// can read more data)
     fn commit_write(&mut self) {
         self.rb
+            .header
             .tail
             .store(self.writer_tail, atomic::Ordering::Release);
     }
@@ -164,8 +320,32 @@ where
 pub fn create_reader_writer_pair<const N: usize, W>(
 ) -> (SharedRingBufferReader<N>, SharedRingBufferWriter<N, W>) {
     let rb: &'static _ = Box::leak(Box::new(SharedRingBuf::new()));
+    reader_writer_pair(rb, false)
+}
+
+/// same as `create_reader_writer_pair`, but the ring buffer lives in a file created at `path`
+/// (e.g. under /dev/shm). If the process dies, the committed records that were not consumed yet
+/// can be retrieved with `read_orphaned_ring`
+/// - `sites_checksum` (see `metadata::sites_checksum`) identifies the log sites of the records, so
+///   that they aren't recovered by a build with different ones
+pub fn create_file_backed_reader_writer_pair<const N: usize, W>(
+    path: &Path,
+    sites_checksum: u32,
+) -> io::Result<(SharedRingBufferReader<N>, SharedRingBufferWriter<N, W>)> {
+    let rb = SharedRingBuf::new_file_backed(path, sites_checksum)?;
+    Ok(reader_writer_pair(rb, true))
+}
+
+fn reader_writer_pair<const N: usize, W>(
+    rb: &'static SharedRingBuf<N>,
+    persistent: bool,
+) -> (SharedRingBufferReader<N>, SharedRingBufferWriter<N, W>) {
     (
-        SharedRingBufferReader { rb, reader_head: 0 },
+        SharedRingBufferReader {
+            rb,
+            reader_head: 0,
+            persistent,
+        },
         SharedRingBufferWriter {
             rb,
             writer_tail: 0,
@@ -174,3 +354,129 @@ pub fn create_reader_writer_pair<const N: usize, W>(
         },
     )
 }
+
+/// caller must guarantee the same as for `SharedRingBuf::from_mapping`
+/// - the reader side is left to whoever maps the ring buffer next (e.g. another process)
+pub(crate) unsafe fn create_mapped_writer<const N: usize, W>(
+    ptr: *mut u8,
+    sites_checksum: u32,
+) -> SharedRingBufferWriter<N, W> {
+    reader_writer_pair(
+        unsafe { SharedRingBuf::from_mapping(ptr, sites_checksum) },
+        true,
+    )
+    .1
+}
+
+/// path of the `seq`th file backed ring buffer of the current process
+pub fn ring_file_path(dir: &Path, seq: usize) -> PathBuf {
+    dir.join(format!("{}.{seq}.ring", std::process::id()))
+}
+
+/// ring buffer files in `dir` whose process is no longer running
+pub fn orphaned_rings(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut output = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "ring") {
+            continue;
+        }
+        let Some(pid) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split('.').next())
+            .and_then(|pid| pid.parse::<libc::pid_t>().ok())
+        else {
+            continue;
+        };
+        if !process_is_alive(pid) {
+            output.push(path);
+        }
+    }
+    Ok(output)
+}
+
+pub fn process_is_alive(pid: libc::pid_t) -> bool {
+    // signal 0 only checks whether the signal could be sent
+    let sent = unsafe { libc::kill(pid, 0) } == 0;
+    sent || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// the records that were committed to the ring buffer file at `path` but never consumed
+/// - must only be used on ring buffers whose writer and reader are gone
+/// - fails with `ErrorKind::InvalidData` if the ring buffer was created with another
+///   `sites_checksum`, its log IDs would map to the wrong sites
+pub fn read_orphaned_ring(path: &Path, sites_checksum: u32) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let bytes = fs::read(path)?;
+    let (header, arr) = unsafe { RingHeader::from_mapping(bytes.as_ptr(), bytes.len())? };
+    if header.sites_checksum != sites_checksum {
+        return Err(invalid(
+            "the ring buffer was written by a build with different log sites",
+        ));
+    }
+    let capacity = header.capacity;
+    let head = header.head.load(atomic::Ordering::Relaxed);
+    let tail = header.tail.load(atomic::Ordering::Relaxed);
+    if tail < head || tail - head > capacity {
+        return Err(invalid("corrupted nanolog ring buffer header"));
+    }
+
+    let mut output = vec![0; tail - head];
+    unsafe { copy_committed(arr, capacity, head, tail, &mut output) };
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recover_file_backed_ring() {
+        let dir = std::env::temp_dir().join(format!("nanolog-ring-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = ring_file_path(&dir, 0);
+
+        let (mut reader, mut writer) =
+            create_file_backed_reader_writer_pair::<16, Panic>(&path, 42).unwrap();
+        let mut buf = [0; 16];
+        writer.write(&[1; 10]);
+        writer.commit_write();
+        assert_eq!(reader.read(&mut buf), 10);
+        reader.commit_read();
+
+        // wraps around the end of the ring buffer
+        writer.write(&[2, 3, 4, 5, 6, 7, 8, 9]);
+        writer.commit_write();
+        // never committed, so it must not be recovered
+        writer.write(&[10]);
+        assert_eq!(reader.read(&mut buf), 8);
+
+        // read but not committed, so it is still recovered
+        assert_eq!(
+            read_orphaned_ring(&path, 42).unwrap(),
+            vec![2, 3, 4, 5, 6, 7, 8, 9]
+        );
+        // another build
+        let err = read_orphaned_ring(&path, 43).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_until_tail() {
+        let (mut reader, mut writer) = create_reader_writer_pair::<16, Panic>();
+        let mut buf = [0; 16];
+        writer.write(&[1; 4]);
+        writer.commit_write();
+        let end = reader.tail();
+        // committed after the tail was taken, left for the next read
+        writer.write(&[2; 4]);
+        writer.commit_write();
+        assert_eq!(reader.read_until(&mut buf, end), 4);
+        assert_eq!(reader.read_until(&mut buf, end), 0);
+        assert_eq!(reader.read(&mut buf), 4);
+        assert_eq!(buf[..4], [2; 4]);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::metadata::LogSite;
+use crate::NanologType;
+
+/// the strings formatted at runtime (messages, context values) are cut to this many bytes, so that a
+/// record always fits a ring buffer
+pub const MAX_MESSAGE_LEN: usize = 4096;
+/// targets and keys are cut to this many bytes
+pub const MAX_TARGET_LEN: usize = 256;
+
+/// cut `s` to at most `max` bytes
+pub(crate) fn truncate(s: &str, max: usize) -> &str {
+    let mut len = s.len().min(max);
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    &s[..len]
+}
+
+/// a single record as written to a ring buffer by the generated `log` functions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawRecord<'a> {
+    pub log_id: u64,
+    pub timestamp: u64,
+    /// the argument struct of the log site, in native byte order
+    pub args: &'a [u8],
+}
+
+impl RawRecord<'_> {
+    pub fn values(&self, site: &LogSite) -> Vec<Value> {
+        let mut values = Vec::with_capacity(site.args.len());
+        let mut consumed = 0;
+        for arg in site.args.iter() {
+            let bytes: [u8; 8] = self.args[consumed..consumed + arg.size()]
+                .try_into()
+                .unwrap();
+            consumed += arg.size();
+            values.push(match arg {
+                NanologType::Int | NanologType::UInt => Value::Int(i64::from_ne_bytes(bytes)),
+                NanologType::Float => Value::Float(f64::from_ne_bytes(bytes)),
+                NanologType::Str => {
+                    let len = u64::from_ne_bytes(bytes) as usize;
+                    let s = String::from_utf8_lossy(&self.args[consumed..consumed + len]);
+                    consumed += len;
+                    Value::Str(s.into_owned())
+                }
+            });
+        }
+        values
+    }
+}
+
+/// the value of a single log argument
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+/// iterate over the records of a chunk read from a ring buffer
+/// - `sites` is indexed by log ID and determines the size of each record's arguments
+/// - stops at the first record with an unknown log ID (e.g. a site registered at runtime by a
+///   crashed process), the records after it can't be told apart
+/// - stops at a record cut short by the end of `buf` as well (e.g. a corrupted string length)
+pub fn raw_records<'a>(
+    sites: &'a [LogSite],
+    buf: &'a [u8],
+) -> impl Iterator<Item = RawRecord<'a>> + 'a {
+    let mut rest = buf;
+    std::iter::from_fn(move || {
+        let (log_id, after) = rest.split_first_chunk::<8>()?;
+        let (timestamp, after) = after.split_first_chunk::<8>()?;
+        let site = sites.get(u64::from_ne_bytes(*log_id) as usize)?;
+        let (args, after) = after.split_at(site.args_size(after)?);
+        rest = after;
+        Some(RawRecord {
+            log_id: u64::from_ne_bytes(*log_id),
+            timestamp: u64::from_ne_bytes(*timestamp),
+            args,
+        })
+    })
+}
+
+#[test]
+fn unknown_log_id() {
+    use std::borrow::Cow;
+
+    let sites = [LogSite {
+        fmt: Cow::Borrowed("%d"),
+        file: Cow::Borrowed("src/main.rs"),
+        line: 3,
+        args: Cow::Borrowed(&[NanologType::Int]),
+        fields: Cow::Borrowed(&[]),
+    }];
+    let mut buf = vec![];
+    for (log_id, timestamp) in [(0u64, 1u64), (1, 2), (0, 3)] {
+        buf.extend_from_slice(&log_id.to_ne_bytes());
+        buf.extend_from_slice(&timestamp.to_ne_bytes());
+        buf.extend_from_slice(&7i64.to_ne_bytes());
+    }
+    let mut records = raw_records(&sites, &buf);
+    assert_eq!(records.next().unwrap().timestamp, 1);
+    assert!(records.next().is_none());
+    assert!(records.next().is_none());
+}
+
+#[test]
+fn truncated_records() {
+    use std::borrow::Cow;
+
+    let sites = [LogSite {
+        fmt: Cow::Borrowed("%d %s"),
+        file: Cow::Borrowed("src/main.rs"),
+        line: 3,
+        args: Cow::Borrowed(&[NanologType::Int, NanologType::Str]),
+        fields: Cow::Borrowed(&[]),
+    }];
+    let mut buf = vec![];
+    for len in [2u64, 2, u64::MAX - 4] {
+        buf.extend_from_slice(&0u64.to_ne_bytes());
+        buf.extend_from_slice(&1u64.to_ne_bytes());
+        buf.extend_from_slice(&7i64.to_ne_bytes());
+        buf.extend_from_slice(&len.to_ne_bytes());
+        buf.extend_from_slice(b"ab");
+    }
+    // an oversized string length
+    assert_eq!(raw_records(&sites, &buf).count(), 2);
+    // cut in the middle of the header, of the arguments and of the string
+    for end in [44, 54, 67] {
+        assert_eq!(raw_records(&sites, &buf[..end]).count(), 1);
+    }
+}
@@ -0,0 +1,516 @@
+use crate::clock::ClockKind;
+use crate::metadata::{deserialize_log_sites, serialize_log_sites, sites_checksum, LogSite};
+use crate::nanolog_logger::{
+    copy_committed, create_mapped_writer, map_shared, process_is_alive, LogReader, RingHeader,
+    SharedRingBuf, SharedRingBufferWriter,
+};
+use crate::sink::Sink;
+use crate::thread::ThreadInfo;
+use std::ffi::CString;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// the maximum number of ring buffers a single process can register
+pub const MAX_RINGS: usize = 256;
+
+/// thread names are cut to this many bytes in the control block
+pub const MAX_THREAD_NAME: usize = 64;
+
+const CONTROL_MAGIC: u64 = u64::from_le_bytes(*b"nanoctl\0");
+
+/// where POSIX shared memory objects show up on linux
+const SHM_DIR: &str = "/dev/shm";
+
+/// registration protocol between an application and the collector:
+/// - the application creates the control block `/nanolog.<pid>.ctl` (with the clock its records
+///   are timestamped with), followed by its serialized log sites, and stores the magic last to
+///   publish it
+/// - for every ring buffer it claims a sequence number from `ring_count`, creates the ring buffer
+///   `/nanolog.<pid>.<seq>.ring`, fills in `threads[seq]` with the thread it belongs to and then
+///   marks `ready[seq]`
+/// - the collector attaches to ring buffers in sequence order once they are ready
+/// - once the application is gone and its ring buffers are drained, the collector unlinks
+///   everything
+#[repr(C)]
+struct ControlBlock {
+    magic: AtomicU64,
+    clock: u32,
+    metadata_len: usize,
+    ring_count: AtomicUsize,
+    ready: [AtomicBool; MAX_RINGS],
+    threads: [ThreadSlot; MAX_RINGS],
+}
+
+/// a `ThreadInfo` in the control block, published by `ready`
+#[repr(C)]
+struct ThreadSlot {
+    tid: AtomicU32,
+    name_len: AtomicU32,
+    name: [AtomicU8; MAX_THREAD_NAME],
+}
+
+impl ThreadSlot {
+    fn store(&self, thread: &ThreadInfo) {
+        let mut len = thread.name.len().min(MAX_THREAD_NAME);
+        while !thread.name.is_char_boundary(len) {
+            len -= 1;
+        }
+        for (slot, b) in self.name.iter().zip(&thread.name.as_bytes()[..len]) {
+            slot.store(*b, Ordering::Relaxed);
+        }
+        self.name_len.store(len as u32, Ordering::Relaxed);
+        self.tid.store(thread.tid, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> ThreadInfo {
+        let len = (self.name_len.load(Ordering::Relaxed) as usize).min(MAX_THREAD_NAME);
+        let name = self.name[..len]
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        ThreadInfo {
+            tid: self.tid.load(Ordering::Relaxed),
+            name: String::from_utf8_lossy(&name).into_owned(),
+        }
+    }
+}
+
+pub fn control_name(pid: libc::pid_t) -> String {
+    format!("/nanolog.{pid}.ctl")
+}
+
+pub fn ring_name(pid: libc::pid_t, seq: usize) -> String {
+    format!("/nanolog.{pid}.{seq}.ring")
+}
+
+/// pids of the processes that have a control block in shared memory
+pub fn registered_processes() -> io::Result<Vec<libc::pid_t>> {
+    let mut output = vec![];
+    for entry in std::fs::read_dir(SHM_DIR)? {
+        let name = entry?.file_name();
+        let Some(pid) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("nanolog."))
+            .and_then(|name| name.strip_suffix(".ctl"))
+            .and_then(|pid| pid.parse().ok())
+        else {
+            continue;
+        };
+        output.push(pid);
+    }
+    Ok(output)
+}
+
+fn shm_open(name: &str, create: bool) -> io::Result<libc::c_int> {
+    let name = CString::new(name).unwrap();
+    let flags = if create {
+        libc::O_RDWR | libc::O_CREAT | libc::O_EXCL
+    } else {
+        libc::O_RDWR
+    };
+    let fd = unsafe { libc::shm_open(name.as_ptr(), flags, 0o600) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+/// create a zero filled shared memory object of `size` bytes and map it
+fn shm_create(name: &str, size: usize) -> io::Result<*mut u8> {
+    let fd = shm_open(name, true)?;
+    let mapped = if unsafe { libc::ftruncate(fd, size as libc::off_t) } != 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        map_shared(fd, size)
+    };
+    unsafe { libc::close(fd) };
+    mapped
+}
+
+fn shm_unlink(name: &str) {
+    let name = CString::new(name).unwrap();
+    unsafe { libc::shm_unlink(name.as_ptr()) };
+}
+
+/// a shared memory object mapped by the collector, unmapped on drop
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn attach(name: &str) -> io::Result<Self> {
+        let fd = shm_open(name, false)?;
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        let mapped = if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            map_shared(fd, stat.st_size as usize).map(|ptr| Mapping {
+                ptr,
+                len: stat.st_size as usize,
+            })
+        };
+        unsafe { libc::close(fd) };
+        mapped
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// the application side of the registration protocol
+pub struct SharedMemoryRegistry {
+    pid: libc::pid_t,
+    control: &'static ControlBlock,
+    sites_checksum: u32,
+}
+
+impl SharedMemoryRegistry {
+    /// publish the control block of the current process
+    pub fn create(sites: &[LogSite], clock: ClockKind) -> io::Result<Self> {
+        let pid = std::process::id() as libc::pid_t;
+        let metadata = serialize_log_sites(sites);
+        let control_size = std::mem::size_of::<ControlBlock>();
+        // the mapping is never unmapped, the ring buffers registered in it live as long as the
+        // process
+        let ptr = shm_create(&control_name(pid), control_size + metadata.len())?;
+        let control = unsafe {
+            std::ptr::addr_of_mut!((*ptr.cast::<ControlBlock>()).clock).write(clock as u32);
+            std::ptr::addr_of_mut!((*ptr.cast::<ControlBlock>()).metadata_len)
+                .write(metadata.len());
+            std::slice::from_raw_parts_mut(ptr.add(control_size), metadata.len())
+                .copy_from_slice(&metadata);
+            &*ptr.cast::<ControlBlock>()
+        };
+        control.magic.store(CONTROL_MAGIC, Ordering::Release);
+        Ok(Self {
+            pid,
+            control,
+            sites_checksum: sites_checksum(sites),
+        })
+    }
+
+    /// create a ring buffer in shared memory and register it (and the calling thread) with the
+    /// collector
+    pub fn create_writer<const N: usize, W>(&self) -> io::Result<SharedRingBufferWriter<N, W>> {
+        let seq = self.control.ring_count.fetch_add(1, Ordering::AcqRel);
+        if seq >= MAX_RINGS {
+            return Err(io::Error::other("too many shared memory ring buffers"));
+        }
+        let ptr = shm_create(
+            &ring_name(self.pid, seq),
+            std::mem::size_of::<SharedRingBuf<N>>(),
+        )?;
+        let writer = unsafe { create_mapped_writer(ptr, self.sites_checksum) };
+        self.control.threads[seq].store(&ThreadInfo::current());
+        self.control.ready[seq].store(true, Ordering::Release);
+        Ok(writer)
+    }
+}
+
+/// the collector side of a ring buffer in shared memory
+/// - the application could write anything to the ring buffer's header, so only head and tail are
+///   read from it after attaching, and a tail that doesn't fit the ring buffer detaches it
+pub struct SharedMemoryRingReader {
+    mapping: Mapping,
+    arr: *const u8,
+    capacity: usize,
+    reader_head: usize,
+    detached: bool,
+}
+
+impl SharedMemoryRingReader {
+    fn attach(name: &str) -> io::Result<Self> {
+        let mapping = Mapping::attach(name)?;
+        let (header, arr) = unsafe { RingHeader::from_mapping(mapping.ptr, mapping.len)? };
+        // a restarted collector picks up where the previous one left off
+        let reader_head = header.head.load(Ordering::Acquire);
+        Ok(Self {
+            capacity: header.capacity,
+            arr,
+            mapping,
+            reader_head,
+            detached: false,
+        })
+    }
+
+    fn header(&self) -> &RingHeader {
+        // validated in attach, the mapping outlives self
+        unsafe { &*self.mapping.ptr.cast::<RingHeader>() }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// whether a corrupted tail was read, nothing is read from the ring buffer after that
+    pub fn is_detached(&self) -> bool {
+        self.detached
+    }
+}
+
+impl LogReader for SharedMemoryRingReader {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.read_until(buf, usize::MAX)
+    }
+
+    fn read_until(&mut self, buf: &mut [u8], end: usize) -> usize {
+        if self.detached {
+            return 0;
+        }
+        let tail = self.tail();
+        if tail < self.reader_head || tail - self.reader_head > self.capacity.min(buf.len()) {
+            self.detached = true;
+            return 0;
+        }
+        let tail = tail.min(end);
+        if tail <= self.reader_head {
+            return 0;
+        }
+        unsafe { copy_committed(self.arr, self.capacity, self.reader_head, tail, buf) };
+        let n = tail - self.reader_head;
+        self.reader_head = tail;
+        n
+    }
+
+    fn tail(&self) -> usize {
+        self.header().tail.load(Ordering::Acquire)
+    }
+
+    fn commit_read(&mut self) {
+        self.header()
+            .head
+            .store(self.reader_head, Ordering::Release);
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
+
+/// a process whose ring buffers are consumed by the collector
+pub struct AttachedProcess {
+    pid: libc::pid_t,
+    control: Mapping,
+    clock: ClockKind,
+    sites: Vec<LogSite>,
+    /// by sequence number, None until the ring buffer is ready
+    rings: Vec<Option<SharedMemoryRingReader>>,
+    buf: Vec<u8>,
+}
+
+/// what a poll of an `AttachedProcess` read
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Polled {
+    /// the number of bytes read across all ring buffers
+    pub bytes: usize,
+    /// the ring buffers (by sequence number) detached by this poll for a corrupted tail, nothing
+    /// more is read from them
+    pub detached: Vec<usize>,
+}
+
+impl AttachedProcess {
+    /// returns None if the process hasn't finished publishing its control block yet
+    pub fn attach(pid: libc::pid_t) -> io::Result<Option<Self>> {
+        let control = Mapping::attach(&control_name(pid))?;
+        let control_size = std::mem::size_of::<ControlBlock>();
+        if control.len < control_size {
+            return Ok(None);
+        }
+        let block = unsafe { &*control.ptr.cast::<ControlBlock>() };
+        if block.magic.load(Ordering::Acquire) != CONTROL_MAGIC {
+            return Ok(None);
+        }
+        if control.len != control_size + block.metadata_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupted nanolog control block",
+            ));
+        }
+        let metadata = unsafe {
+            std::slice::from_raw_parts(control.ptr.add(control_size), block.metadata_len)
+        };
+        let sites = deserialize_log_sites(metadata)?;
+        let clock = ClockKind::from_u32(block.clock).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown clock in the control block",
+            )
+        })?;
+        Ok(Some(Self {
+            pid,
+            control,
+            clock,
+            sites,
+            rings: vec![],
+            buf: vec![],
+        }))
+    }
+
+    pub fn pid(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// what the timestamps of the process's records are, see `Sink::set_clock`
+    pub fn clock(&self) -> ClockKind {
+        self.clock
+    }
+
+    pub fn is_alive(&self) -> bool {
+        process_is_alive(self.pid)
+    }
+
+    /// read whatever is available in each ring buffer once and write it to `sink`
+    /// - attaches to newly registered ring buffers first, registering their threads with `sink`.
+    ///   A ring buffer that isn't ready yet (or never will be, if the process died while setting
+    ///   it up) doesn't hold up the ones after it, it is retried on the next poll
+    /// - a ring buffer with a corrupted tail is detached (see `Polled::detached`), the others
+    ///   carry on
+    pub fn poll(&mut self, sink: &mut dyn Sink) -> io::Result<Polled> {
+        let block = unsafe { &*self.control.ptr.cast::<ControlBlock>() };
+        let registered = block.ring_count.load(Ordering::Acquire).min(MAX_RINGS);
+        self.rings
+            .resize_with(registered.max(self.rings.len()), || None);
+        for (seq, ring) in self.rings.iter_mut().enumerate() {
+            if ring.is_none() && block.ready[seq].load(Ordering::Acquire) {
+                *ring = Some(SharedMemoryRingReader::attach(&ring_name(self.pid, seq))?);
+                sink.register_thread(seq as u64, &block.threads[seq].load());
+            }
+        }
+
+        let mut polled = Polled::default();
+        for (seq, r) in self.rings.iter_mut().enumerate() {
+            let Some(r) = r.as_mut().filter(|r| !r.is_detached()) else {
+                continue;
+            };
+            self.buf.resize(r.capacity(), 0);
+            let n = r.read(&mut self.buf);
+            if r.is_detached() {
+                polled.detached.push(seq);
+            }
+            if n > 0 {
+                sink.write_chunk(seq as u64, &self.sites, &self.buf[..n])?;
+                sink.flush()?;
+                r.commit_read();
+            }
+            polled.bytes += n;
+        }
+        Ok(polled)
+    }
+
+    /// remove the shared memory objects of the process, once it is gone and everything is read
+    pub fn unlink(self) {
+        let block = unsafe { &*self.control.ptr.cast::<ControlBlock>() };
+        let registered = block.ring_count.load(Ordering::Acquire).min(MAX_RINGS);
+        for seq in 0..registered {
+            shm_unlink(&ring_name(self.pid, seq));
+        }
+        shm_unlink(&control_name(self.pid));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::StreamCompressor;
+    use crate::file_format::write_header;
+    use crate::nanolog_logger::{Logger, Panic};
+    use crate::sink::{Encoding, MemorySink};
+    use crate::NanologType;
+    use std::borrow::Cow;
+
+    #[test]
+    fn collect_from_shared_memory() {
+        const SITES: [LogSite; 1] = [LogSite {
+            fmt: Cow::Borrowed("%d"),
+            file: Cow::Borrowed("src/main.rs"),
+            line: 1,
+            args: Cow::Borrowed(&[NanologType::Int]),
+            fields: Cow::Borrowed(&[]),
+        }];
+        let registry = SharedMemoryRegistry::create(&SITES, ClockKind::MonotonicRaw).unwrap();
+        let mut writer = registry.create_writer::<64, Panic>().unwrap();
+        for bytes in [0u64.to_ne_bytes(), 3u64.to_ne_bytes(), 7u64.to_ne_bytes()] {
+            writer.write(&bytes);
+        }
+        writer.commit_write();
+
+        let mut process = AttachedProcess::attach(registry.pid).unwrap().unwrap();
+        assert_eq!(process.sites, SITES);
+        assert_eq!(process.clock(), ClockKind::MonotonicRaw);
+        let out = MemorySink::new(Encoding::Compressed);
+        assert_eq!(process.poll(&mut out.clone()).unwrap().bytes, 24);
+
+        let mut expected = vec![];
+        write_header(&mut expected, 0, ClockKind::Rdtsc, &SITES).unwrap();
+        ThreadInfo::current().write_chunk(0, &mut expected).unwrap();
+        StreamCompressor::default()
+            .compress_buf(
+                &mut expected,
+                0,
+                &SITES,
+                &[0u64, 3, 7].map(u64::to_ne_bytes).concat(),
+            )
+            .unwrap();
+        assert_eq!(out.contents(), expected);
+        assert_eq!(process.poll(&mut out.clone()).unwrap(), Polled::default());
+        process.unlink();
+    }
+
+    #[test]
+    fn misbehaving_process() {
+        let registry = SharedMemoryRegistry::create(&[], ClockKind::MonotonicRaw).unwrap();
+        let mut first = registry.create_writer::<64, Panic>().unwrap();
+        // the process died between claiming a ring buffer and marking it ready
+        registry.control.ring_count.fetch_add(1, Ordering::AcqRel);
+        let mut third = registry.create_writer::<64, Panic>().unwrap();
+        for writer in [&mut first, &mut third] {
+            writer.write(&[0; 16]);
+            writer.commit_write();
+        }
+
+        let mut process = AttachedProcess::attach(registry.pid).unwrap().unwrap();
+        let mut out = MemorySink::new(Encoding::Compressed);
+        assert_eq!(
+            process.poll(&mut out).unwrap(),
+            Polled {
+                bytes: 32,
+                detached: vec![]
+            }
+        );
+        assert!(process.rings[1].is_none());
+
+        // a tail beyond the ring buffer's capacity
+        let ring = process.rings[0].as_ref().unwrap();
+        ring.header()
+            .tail
+            .store(ring.reader_head + 65, Ordering::Release);
+        third.write(&[0; 16]);
+        third.commit_write();
+        assert_eq!(
+            process.poll(&mut out).unwrap(),
+            Polled {
+                bytes: 16,
+                detached: vec![0]
+            }
+        );
+        assert!(process.rings[0].as_ref().unwrap().is_detached());
+        // and one before what was read
+        let ring = process.rings[2].as_ref().unwrap();
+        ring.header()
+            .tail
+            .store(ring.reader_head - 1, Ordering::Release);
+        assert_eq!(
+            process.poll(&mut out).unwrap(),
+            Polled {
+                bytes: 0,
+                detached: vec![2]
+            }
+        );
+        assert!(process.rings[2].as_ref().unwrap().is_detached());
+        process.unlink();
+    }
+}
@@ -0,0 +1,1161 @@
+use crate::clock::{ClockAnchor, ClockKind};
+use crate::compression::StreamCompressor;
+use crate::context::{self, Context, CONTEXT_SITES};
+use crate::file_format::{read_header, write_header, FileHeader, FLAG_RECENT_IDS};
+use crate::format::{utc_date_time, utc_timestamp, write_line};
+use crate::index::{index_path, IndexWriter, INDEX_INTERVAL};
+use crate::metadata::{write_site_chunk, LogSite};
+use crate::record::raw_records;
+use crate::thread::ThreadInfo;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Stdout, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// where the log reader sends the records it reads from the ring buffers
+pub trait Sink: Send {
+    /// handle a chunk of records read from a ring buffer
+    /// - `stream` identifies the ring buffer, consecutive chunks of a stream are compressed
+    ///   relative to each other
+    /// - `sites` is indexed by log ID and describes the records in the chunk
+    fn write_chunk(&mut self, stream: u64, sites: &[LogSite], chunk: &[u8]) -> io::Result<()>;
+    /// pair the timestamps of the chunks from now on with the wall-clock time, the anchor is
+    /// written along with the next chunk
+    fn write_anchor(&mut self, anchor: &ClockAnchor);
+    /// the clock the timestamps of the chunks come from (`ClockKind::Rdtsc` by default), recorded
+    /// in the header, so it must be set before the first chunk
+    fn set_clock(&mut self, clock: ClockKind);
+    /// `stream` is the ring buffer of `thread`, the registration is written along with the next
+    /// chunk
+    fn register_thread(&mut self, stream: u64, thread: &ThreadInfo);
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// how a chunk of records is turned into bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// the compressed binary format, formatting is deferred to the decoder
+    Compressed,
+    /// one formatted line per record: `<timestamp> <file>:<line> <message>`, with the context
+    /// of the record (see `context`) like the decoder
+    Text,
+    /// the compressed binary format with every chunk lz4 compressed on top
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+/// an `Encoding` plus the state the compressed encoding keeps for each stream
+/// - sinks take anything that converts into an `Encoder`, so either an `Encoding` or an `Encoder`
+///   with options
+#[derive(Debug)]
+pub struct Encoder {
+    encoding: Encoding,
+    recent_ids: bool,
+    clock: ClockKind,
+    streams: HashMap<u64, StreamCompressor>,
+    /// the latest anchor, repeated wherever decoding can start (after a header, at the start of
+    /// an index section)
+    anchor: Option<ClockAnchor>,
+    anchor_pending: bool,
+    /// the registered threads by stream, repeated wherever decoding can start like the anchor
+    threads: BTreeMap<u64, ThreadInfo>,
+    threads_pending: BTreeSet<u64>,
+    /// the number of sites in the header, the sites after them were registered at runtime (see
+    /// `SiteRegistry`) and are written before the first chunk that comes with them
+    header_sites: Option<usize>,
+    sites_written: usize,
+    /// the context of each stream, to format text and to repeat it where decoding can start
+    contexts: HashMap<u64, Context>,
+    /// the streams with a context that wasn't repeated since `restart_streams`
+    contexts_pending: HashSet<u64>,
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            recent_ids: false,
+            clock: ClockKind::Rdtsc,
+            streams: HashMap::new(),
+            anchor: None,
+            anchor_pending: false,
+            threads: BTreeMap::new(),
+            threads_pending: BTreeSet::new(),
+            header_sites: None,
+            sites_written: 0,
+            contexts: HashMap::new(),
+            contexts_pending: HashSet::new(),
+        }
+    }
+
+    /// encode log IDs relative to the recently used ones of each stream, which takes the log ID of
+    /// the log sites of a hot loop down to a few bits of the record header
+    /// - has no effect on text
+    pub fn with_recent_ids(mut self) -> Self {
+        self.recent_ids = true;
+        self
+    }
+
+    fn flags(&self) -> u32 {
+        if self.recent_ids {
+            FLAG_RECENT_IDS
+        } else {
+            0
+        }
+    }
+
+    fn stream(&mut self, stream: u64) -> &mut StreamCompressor {
+        let recent_ids = self.recent_ids;
+        self.streams.entry(stream).or_insert_with(|| {
+            if recent_ids {
+                StreamCompressor::with_recent_ids()
+            } else {
+                StreamCompressor::default()
+            }
+        })
+    }
+
+    /// the header `write_header` writes, None for text
+    pub fn header(&self, sites: &[LogSite]) -> Option<FileHeader> {
+        let flags = match self.encoding {
+            Encoding::Compressed => self.flags(),
+            Encoding::Text => return None,
+            #[cfg(feature = "lz4")]
+            Encoding::Lz4 => crate::file_format::FLAG_LZ4 | self.flags(),
+        };
+        Some(FileHeader {
+            flags,
+            clock: self.clock,
+            sites: sites.to_vec(),
+        })
+    }
+
+    /// written once at the start of every stream, makes compressed streams self-describing
+    /// - the chunks after a header don't refer to anything before it
+    pub fn write_header(&mut self, out: &mut impl Write, sites: &[LogSite]) -> io::Result<()> {
+        self.resume_header(sites);
+        match self.header(sites) {
+            Some(header) => write_header(out, header.flags, header.clock, sites),
+            None => Ok(()),
+        }
+    }
+
+    /// like `write_header`, for a stream that already starts with the same header (see
+    /// `WriterSink::file`)
+    pub fn resume_header(&mut self, sites: &[LogSite]) {
+        self.header_sites = Some(sites.len());
+        self.restart_streams();
+    }
+
+    /// the next chunk of each stream starts with a sync record, so it doesn't depend on anything
+    /// written before
+    /// - the context of the stream is repeated ahead of its records (see `context::write_context`)
+    pub fn restart_streams(&mut self) {
+        self.streams.clear();
+        self.contexts_pending = self
+            .contexts
+            .iter()
+            .filter(|(_, context)| !context.is_empty())
+            .map(|(stream, _)| *stream)
+            .collect();
+        self.anchor_pending = self.anchor.is_some();
+        self.threads_pending = self.threads.keys().copied().collect();
+        self.sites_written = self.header_sites.unwrap_or_default();
+    }
+
+    /// see `Sink::set_clock`
+    pub fn set_clock(&mut self, clock: ClockKind) {
+        self.clock = clock;
+    }
+
+    /// written before the next chunk, text is formatted with UTC timestamps from now on
+    pub fn set_anchor(&mut self, anchor: ClockAnchor) {
+        self.anchor = Some(anchor);
+        self.anchor_pending = true;
+    }
+
+    /// written before the next chunk, text is formatted with the thread's name from now on
+    pub fn register_thread(&mut self, stream: u64, thread: ThreadInfo) {
+        self.threads.insert(stream, thread);
+        self.threads_pending.insert(stream);
+    }
+
+    /// the anchor, thread and site registrations that are due, each framed as a chunk
+    fn pending_chunks(&mut self, sites: &[LogSite]) -> io::Result<Vec<Vec<u8>>> {
+        let mut chunks = vec![];
+        if std::mem::take(&mut self.anchor_pending) {
+            if let Some(anchor) = self.anchor {
+                let mut chunk = vec![];
+                anchor.write_chunk(&mut chunk)?;
+                chunks.push(chunk);
+            }
+        }
+        for stream in std::mem::take(&mut self.threads_pending) {
+            let mut chunk = vec![];
+            self.threads[&stream].write_chunk(stream, &mut chunk)?;
+            chunks.push(chunk);
+        }
+        if self.header_sites.is_some() {
+            for (log_id, site) in sites.iter().enumerate().skip(self.sites_written) {
+                let mut chunk = vec![];
+                write_site_chunk(log_id as u64, site, &mut chunk)?;
+                chunks.push(chunk);
+            }
+            self.sites_written = self.sites_written.max(sites.len());
+        }
+        Ok(chunks)
+    }
+
+    /// `chunk` with the context of the stream repeated ahead of its records if it wasn't since
+    /// `restart_streams`, so that decoding from there has the context pushed before
+    fn replay_context<'c>(
+        &mut self,
+        stream: u64,
+        sites: &[LogSite],
+        chunk: &'c [u8],
+    ) -> Cow<'c, [u8]> {
+        if !self.contexts_pending.contains(&stream) {
+            return Cow::Borrowed(chunk);
+        }
+        let first_id = sites.iter().position(|s| *s == CONTEXT_SITES[0]);
+        let (Some(first_id), Some(first)) = (first_id, raw_records(sites, chunk).next()) else {
+            return Cow::Borrowed(chunk);
+        };
+        self.contexts_pending.remove(&stream);
+        let mut replayed = vec![];
+        context::write_context(
+            &mut replayed,
+            first_id as u64,
+            first.timestamp,
+            &self.contexts[&stream],
+        );
+        replayed.extend_from_slice(chunk);
+        Cow::Owned(replayed)
+    }
+
+    /// apply the context records of `chunk` to the context of the stream
+    fn track_context(&mut self, stream: u64, sites: &[LogSite], chunk: &[u8]) {
+        let context = self.contexts.entry(stream).or_default();
+        for record in raw_records(sites, chunk) {
+            let site = &sites[record.log_id as usize];
+            if !context::is_context_site(site) {
+                continue;
+            }
+            if let Some(pushed_or_popped) = context::apply(context, site, &record.values(site)) {
+                *context = pushed_or_popped;
+            }
+        }
+    }
+
+    pub fn encode(
+        &mut self,
+        out: &mut impl Write,
+        stream: u64,
+        sites: &[LogSite],
+        chunk: &[u8],
+    ) -> io::Result<()> {
+        match self.encoding {
+            Encoding::Compressed => {
+                for pending in self.pending_chunks(sites)? {
+                    out.write_all(&pending)?;
+                }
+                let replayed = self.replay_context(stream, sites, chunk);
+                self.track_context(stream, sites, chunk);
+                self.stream(stream)
+                    .compress_buf(out, stream, sites, &replayed)
+            }
+            Encoding::Text => {
+                let thread = self.threads.get(&stream).map(|t| t.label(stream));
+                let context = self.contexts.entry(stream).or_default();
+                for record in raw_records(sites, chunk) {
+                    let site = &sites[record.log_id as usize];
+                    let values = record.values(site);
+                    if let Some(pushed_or_popped) = context::apply(context, site, &values) {
+                        *context = pushed_or_popped;
+                        continue;
+                    }
+                    let thread = thread.as_deref();
+                    match self.clock.utc_nanos(self.anchor.as_ref(), record.timestamp) {
+                        Some(nanos) => {
+                            write_line(out, utc_timestamp(nanos), thread, site, &values, context)?
+                        }
+                        None => write_line(out, record.timestamp, thread, site, &values, context)?,
+                    }
+                }
+                Ok(())
+            }
+            #[cfg(feature = "lz4")]
+            Encoding::Lz4 => {
+                for pending in self.pending_chunks(sites)? {
+                    crate::block::write_block(out, &pending)?;
+                }
+                let replayed = self.replay_context(stream, sites, chunk);
+                self.track_context(stream, sites, chunk);
+                let mut compressed = vec![];
+                self.stream(stream)
+                    .compress_buf(&mut compressed, stream, sites, &replayed)?;
+                crate::block::write_block(out, &compressed)
+            }
+        }
+    }
+}
+
+impl From<Encoding> for Encoder {
+    fn from(encoding: Encoding) -> Self {
+        Self::new(encoding)
+    }
+}
+
+/// replaces the writer of a `WriterSink` with a new file, see `WriterSink::file`
+type StartOver<W> = Box<dyn FnOnce(&mut WriterSink<W>) -> io::Result<()> + Send>;
+
+/// encodes chunks into any writer
+pub struct WriterSink<W> {
+    writer: W,
+    encoder: Encoder,
+    header_written: bool,
+    /// only kept for indexed files
+    index: Option<IndexWriter>,
+    offset: u64,
+    buf: Vec<u8>,
+    /// for a file that isn't empty, the header it starts with (None if it can't be read)
+    existing: Option<(Option<FileHeader>, StartOver<W>)>,
+}
+
+impl<W: Write + Send> WriterSink<W> {
+    /// the header is written along with the first chunk
+    pub fn new(writer: W, encoding: impl Into<Encoder>) -> Self {
+        Self {
+            writer,
+            encoder: encoding.into(),
+            header_written: false,
+            index: None,
+            offset: 0,
+            buf: vec![],
+            existing: None,
+        }
+    }
+
+    /// called with the first chunk, before it is encoded into `out`
+    fn start(&mut self, sites: &[LogSite], out: Option<&mut Vec<u8>>) -> io::Result<()> {
+        self.header_written = true;
+        if let Some((existing, start_over)) = self.existing.take() {
+            match self.encoder.header(sites) {
+                // text has no header, it just goes on
+                None => return Ok(()),
+                Some(header) if existing.as_ref() == Some(&header) => {
+                    self.encoder.resume_header(sites);
+                    return Ok(());
+                }
+                Some(_) => start_over(self)?,
+            }
+        }
+        match out {
+            Some(out) => self.encoder.write_header(out, sites),
+            None => self.encoder.write_header(&mut self.writer, sites),
+        }
+    }
+}
+
+impl WriterSink<BufWriter<File>> {
+    /// appends to the file at `path`, creating it if needed
+    /// - a compressed file is only continued if it starts with the header this sink writes (same
+    ///   encoding, clock and log sites, e.g. the same build), otherwise it is moved to `<path>.<n>`
+    ///   (the first n that's free, along with its index) and the sink starts a new file
+    pub fn file(path: impl AsRef<Path>, encoding: impl Into<Encoder>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+        let existing = if file.metadata()?.len() > 0 {
+            Some(read_header(&mut BufReader::new(&file)).ok())
+        } else {
+            None
+        };
+        let start_over: StartOver<BufWriter<File>> = Box::new(move |sink| {
+            let aside = (1..)
+                .map(|n| {
+                    let mut aside = path.clone().into_os_string();
+                    aside.push(format!(".{n}"));
+                    PathBuf::from(aside)
+                })
+                .find(|aside| !aside.exists() && !index_path(aside).exists())
+                .unwrap();
+            std::fs::rename(&path, &aside)?;
+            sink.writer = BufWriter::new(File::create_new(&path)?);
+            sink.offset = 0;
+            if let Some(index) = sink.index.take() {
+                let interval = index.interval();
+                // flushes the last section of the old file
+                drop(index);
+                std::fs::rename(index_path(&path), index_path(&aside))?;
+                sink.index = Some(IndexWriter::open(index_path(&path), interval)?);
+            }
+            Ok(())
+        });
+        Ok(Self {
+            existing: existing.map(|header| (header, start_over)),
+            ..Self::new(BufWriter::new(file), encoding)
+        })
+    }
+
+    /// like `file`, and keeps a sparse index of the timestamps in the file in `<path>.idx`, which
+    /// lets the decoder find the records of a time range without decoding the whole file
+    pub fn indexed_file(path: impl AsRef<Path>, encoding: impl Into<Encoder>) -> io::Result<Self> {
+        let mut sink = Self::file(&path, encoding)?;
+        sink.offset = sink.writer.get_ref().metadata()?.len();
+        sink.index = Some(IndexWriter::open(index_path(&path), INDEX_INTERVAL)?);
+        Ok(sink)
+    }
+}
+
+impl WriterSink<Stdout> {
+    pub fn stdout() -> Self {
+        Self::new(io::stdout(), Encoding::Text)
+    }
+}
+
+impl WriterSink<BufWriter<UnixStream>> {
+    /// connects to a listening unix domain socket at `path`
+    pub fn unix_socket(path: impl AsRef<Path>, encoding: impl Into<Encoder>) -> io::Result<Self> {
+        Ok(Self::new(
+            BufWriter::new(UnixStream::connect(path)?),
+            encoding,
+        ))
+    }
+}
+
+impl<W: Write + Send> Sink for WriterSink<W> {
+    fn write_chunk(&mut self, stream: u64, sites: &[LogSite], chunk: &[u8]) -> io::Result<()> {
+        if self.index.is_none() {
+            if !self.header_written {
+                self.start(sites, None)?;
+            }
+            return self.encoder.encode(&mut self.writer, stream, sites, chunk);
+        }
+
+        // encoded separately to know the offsets
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        if !self.header_written {
+            self.start(sites, Some(&mut buf))?;
+        }
+        self.buf = buf;
+        let index = self.index.as_mut().unwrap();
+        if index.start_chunk(self.offset + self.buf.len() as u64)? {
+            self.encoder.restart_streams();
+        }
+        self.encoder.encode(&mut self.buf, stream, sites, chunk)?;
+        self.writer.write_all(&self.buf)?;
+        self.offset += self.buf.len() as u64;
+        index.end_chunk(self.offset, raw_records(sites, chunk).map(|r| r.timestamp));
+        Ok(())
+    }
+
+    fn write_anchor(&mut self, anchor: &ClockAnchor) {
+        self.encoder.set_anchor(*anchor);
+    }
+
+    fn register_thread(&mut self, stream: u64, thread: &ThreadInfo) {
+        self.encoder.register_thread(stream, thread.clone());
+    }
+
+    fn set_clock(&mut self, clock: ClockKind) {
+        self.encoder.set_clock(clock);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        match self.index.as_mut() {
+            Some(index) => index.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// keeps everything in memory, cloning it gives another handle to the same buffer (e.g. to inspect
+/// the output of a sink that was handed to the log reader in tests)
+#[derive(Clone)]
+pub struct MemorySink {
+    /// the encoder is shared as well, all handles write the same streams
+    inner: Arc<Mutex<(Vec<u8>, Encoder)>>,
+}
+
+impl MemorySink {
+    pub fn new(encoding: impl Into<Encoder>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new((vec![], encoding.into()))),
+        }
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.inner.lock().unwrap().0.clone()
+    }
+}
+
+impl Sink for MemorySink {
+    fn write_chunk(&mut self, stream: u64, sites: &[LogSite], chunk: &[u8]) -> io::Result<()> {
+        let (buf, encoder) = &mut *self.inner.lock().unwrap();
+        if buf.is_empty() {
+            encoder.write_header(buf, sites)?;
+        }
+        encoder.encode(buf, stream, sites, chunk)
+    }
+
+    fn write_anchor(&mut self, anchor: &ClockAnchor) {
+        self.inner.lock().unwrap().1.set_anchor(*anchor);
+    }
+
+    fn register_thread(&mut self, stream: u64, thread: &ThreadInfo) {
+        self.inner
+            .lock()
+            .unwrap()
+            .1
+            .register_thread(stream, thread.clone());
+    }
+
+    fn set_clock(&mut self, clock: ClockKind) {
+        self.inner.lock().unwrap().1.set_clock(clock);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// what happens to rotated files beyond the retention count
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    Delete,
+    /// gzip them in the background (requires `gzip` to be installed), the gzipped files beyond
+    /// the `keep` newest are deleted
+    Gzip {
+        keep: usize,
+    },
+}
+
+/// when `RotatingFileSink` starts a new file and how many it keeps around
+#[derive(Debug, Clone)]
+pub struct RotationPolicy {
+    pub max_bytes: Option<u64>,
+    pub interval: Option<Duration>,
+    /// the number of (uncompressed) files to keep, including the current one
+    pub keep: Option<usize>,
+    pub expiry: Expiry,
+}
+
+impl Default for RotationPolicy {
+    /// never rotates
+    fn default() -> Self {
+        Self {
+            max_bytes: None,
+            interval: None,
+            keep: None,
+            expiry: Expiry::Delete,
+        }
+    }
+}
+
+/// writes to `<prefix>.<utc time>.<seq>`, starting a new file (with a fresh header) once the current
+/// one reaches `max_bytes` or is older than `interval`
+/// - a chunk is never split across files, so files can end up slightly larger than `max_bytes`.
+///   The size of the chunk as read from the ring buffer stands in for its encoded size, which is
+///   only known once it's encoded relative to the file it goes into
+/// - rotation only happens when a chunk is written, a quiet file stays open past its interval
+/// - file names sort in the order they were created, retention relies on this (files from earlier
+///   runs with the same prefix count towards the retention count as well)
+/// - the seq continues after the highest one of the files with the same prefix, so another run (or
+///   another sink) on the same prefix doesn't collide with them
+pub struct RotatingFileSink {
+    prefix: PathBuf,
+    policy: RotationPolicy,
+    encoder: Encoder,
+    seq: usize,
+    file: Option<BufWriter<File>>,
+    opened_at: Instant,
+    written: u64,
+    /// the files being gzipped in the background
+    gzipping: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl RotatingFileSink {
+    /// the first file is created along with the first chunk
+    pub fn new(
+        prefix: impl Into<PathBuf>,
+        policy: RotationPolicy,
+        encoding: impl Into<Encoder>,
+    ) -> Self {
+        Self {
+            prefix: prefix.into(),
+            policy,
+            encoder: encoding.into(),
+            seq: 0,
+            file: None,
+            opened_at: Instant::now(),
+            written: 0,
+            gzipping: Arc::default(),
+        }
+    }
+
+    fn file_name_prefix(&self) -> String {
+        self.prefix
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    fn dir(&self) -> &Path {
+        match self.prefix.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    fn open(&mut self, sites: &[LogSite]) -> io::Result<()> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+        }
+        let unix_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (year, month, day, hour, minute, second) = utc_date_time(unix_secs);
+        self.seq = self.seq.max(self.next_seq()?);
+        let file = loop {
+            let name = format!(
+                "{}.{year:04}{month:02}{day:02}T{hour:02}{minute:02}{second:02}Z.{:06}",
+                self.file_name_prefix(),
+                self.seq
+            );
+            match File::create_new(self.dir().join(name)) {
+                Ok(file) => break file,
+                // another sink on the same prefix got there first
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => self.seq += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        let mut file = BufWriter::new(file);
+        self.encoder.write_header(&mut file, sites)?;
+
+        self.file = Some(file);
+        self.seq += 1;
+        self.opened_at = Instant::now();
+        self.written = 0;
+        self.apply_retention()
+    }
+
+    fn should_rotate(&self, len: u64) -> bool {
+        let too_big = self
+            .policy
+            .max_bytes
+            .is_some_and(|max| self.written > 0 && self.written + len > max);
+        let too_old = self
+            .policy
+            .interval
+            .is_some_and(|interval| self.opened_at.elapsed() >= interval);
+        too_big || too_old
+    }
+
+    /// the rotated files of this prefix (including the current one and the gzipped ones), oldest
+    /// first
+    fn rotated_files(&self) -> io::Result<Vec<PathBuf>> {
+        let prefix = format!("{}.", self.file_name_prefix());
+        let mut files = vec![];
+        for entry in std::fs::read_dir(self.dir())? {
+            let path = entry?.path();
+            let is_rotated = path.file_name().and_then(|n| n.to_str()).is_some_and(|n| {
+                let n = n.strip_suffix(".gz").unwrap_or(n);
+                n.strip_prefix(&prefix).is_some_and(|suffix| {
+                    suffix.ends_with(|c: char| c.is_ascii_digit()) && suffix.contains('T')
+                })
+            });
+            if is_rotated {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// the seq after the highest one of the rotated files
+    fn next_seq(&self) -> io::Result<usize> {
+        Ok(self
+            .rotated_files()?
+            .iter()
+            .filter_map(|path| {
+                let name = path.file_name()?.to_str()?;
+                let name = name.strip_suffix(".gz").unwrap_or(name);
+                name.rsplit_once('.')?.1.parse::<usize>().ok()
+            })
+            .max()
+            .map_or(0, |seq| seq + 1))
+    }
+
+    fn apply_retention(&self) -> io::Result<()> {
+        let Some(keep) = self.policy.keep else {
+            return Ok(());
+        };
+        let (gzipped, files): (Vec<_>, Vec<_>) = self
+            .rotated_files()?
+            .into_iter()
+            .partition(|path| path.extension().is_some_and(|ext| ext == "gz"));
+        let expired = &files[..files.len().saturating_sub(keep.max(1))];
+        let Expiry::Gzip { keep } = self.policy.expiry else {
+            for path in expired {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(());
+        };
+
+        let mut gzipping = self.gzipping.lock().unwrap();
+        // the expired files and the gzipped ones by their uncompressed name, oldest first. The
+        // half written output of a gzip in progress is counted as the file being compressed
+        let mut compressed = gzipped
+            .into_iter()
+            .map(|gz| (gz.with_extension(""), gz))
+            .filter(|(path, _)| !gzipping.contains(path))
+            .chain(expired.iter().map(|path| (path.clone(), path.clone())))
+            .collect::<Vec<_>>();
+        compressed.sort();
+        let excess = compressed.len().saturating_sub(keep);
+        for (i, (path, file)) in compressed.into_iter().enumerate() {
+            if gzipping.contains(&path) {
+                // deleted by a later rotation, once it is compressed
+                continue;
+            }
+            if i < excess {
+                std::fs::remove_file(file)?;
+            } else if path == file {
+                gzipping.insert(path.clone());
+                let gzipping = self.gzipping.clone();
+                // compressing can take a while, the log reader must not wait for it
+                std::thread::spawn(move || {
+                    if let Err(e) = Command::new("gzip").arg("-f").arg(&path).status() {
+                        eprintln!("failed to gzip {}: {e}", path.display());
+                    }
+                    gzipping.lock().unwrap().remove(&path);
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for RotatingFileSink {
+    fn write_chunk(&mut self, stream: u64, sites: &[LogSite], chunk: &[u8]) -> io::Result<()> {
+        if self.file.is_none() || self.should_rotate(chunk.len() as u64) {
+            self.open(sites)?;
+        }
+        let mut encoded = vec![];
+        self.encoder.encode(&mut encoded, stream, sites, chunk)?;
+        self.file.as_mut().unwrap().write_all(&encoded)?;
+        self.written += encoded.len() as u64;
+        Ok(())
+    }
+
+    fn write_anchor(&mut self, anchor: &ClockAnchor) {
+        self.encoder.set_anchor(*anchor);
+    }
+
+    fn register_thread(&mut self, stream: u64, thread: &ThreadInfo) {
+        self.encoder.register_thread(stream, thread.clone());
+    }
+
+    fn set_clock(&mut self, clock: ClockKind) {
+        self.encoder.set_clock(clock);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// sends every chunk to all of its sinks, each with its own encoding
+#[derive(Default)]
+pub struct FanOut {
+    sinks: Vec<Box<dyn Sink>>,
+}
+
+impl FanOut {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+}
+
+impl Sink for FanOut {
+    /// a failing sink doesn't keep the others from getting the chunk, the first error is returned
+    fn write_chunk(&mut self, stream: u64, sites: &[LogSite], chunk: &[u8]) -> io::Result<()> {
+        let mut result = Ok(());
+        for sink in self.sinks.iter_mut() {
+            let res = sink.write_chunk(stream, sites, chunk);
+            result = result.and(res);
+        }
+        result
+    }
+
+    fn write_anchor(&mut self, anchor: &ClockAnchor) {
+        for sink in self.sinks.iter_mut() {
+            sink.write_anchor(anchor);
+        }
+    }
+
+    fn register_thread(&mut self, stream: u64, thread: &ThreadInfo) {
+        for sink in self.sinks.iter_mut() {
+            sink.register_thread(stream, thread);
+        }
+    }
+
+    fn set_clock(&mut self, clock: ClockKind) {
+        for sink in self.sinks.iter_mut() {
+            sink.set_clock(clock);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for sink in self.sinks.iter_mut() {
+            let res = sink.flush();
+            result = result.and(res);
+        }
+        result
+    }
+}
+
+impl Sink for Box<dyn Sink> {
+    fn write_chunk(&mut self, stream: u64, sites: &[LogSite], chunk: &[u8]) -> io::Result<()> {
+        (**self).write_chunk(stream, sites, chunk)
+    }
+
+    fn write_anchor(&mut self, anchor: &ClockAnchor) {
+        (**self).write_anchor(anchor)
+    }
+
+    fn register_thread(&mut self, stream: u64, thread: &ThreadInfo) {
+        (**self).register_thread(stream, thread)
+    }
+
+    fn set_clock(&mut self, clock: ClockKind) {
+        (**self).set_clock(clock)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NanologType;
+    use std::borrow::Cow;
+
+    const SITES: [LogSite; 2] = [
+        LogSite {
+            fmt: Cow::Borrowed("Hello, world!"),
+            file: Cow::Borrowed("src/main.rs"),
+            line: 3,
+            args: Cow::Borrowed(&[]),
+            fields: Cow::Borrowed(&[]),
+        },
+        LogSite {
+            fmt: Cow::Borrowed("%f %d"),
+            file: Cow::Borrowed("src/main.rs"),
+            line: 4,
+            args: Cow::Borrowed(&[NanologType::Float, NanologType::Int]),
+            fields: Cow::Borrowed(&[]),
+        },
+    ];
+
+    fn chunk() -> Vec<u8> {
+        let mut chunk = vec![];
+        chunk.extend_from_slice(&0u64.to_ne_bytes());
+        chunk.extend_from_slice(&10u64.to_ne_bytes());
+        chunk.extend_from_slice(&1u64.to_ne_bytes());
+        chunk.extend_from_slice(&20u64.to_ne_bytes());
+        chunk.extend_from_slice(&1.5f64.to_ne_bytes());
+        chunk.extend_from_slice(&(-7i64).to_ne_bytes());
+        chunk
+    }
+
+    #[test]
+    fn fan_out_encodings() {
+        let text = MemorySink::new(Encoding::Text);
+        let compressed = MemorySink::new(Encoding::Compressed);
+        let mut sink = FanOut::new().with(text.clone()).with(compressed.clone());
+        sink.write_chunk(0, &SITES, &chunk()).unwrap();
+        sink.flush().unwrap();
+
+        assert_eq!(
+            String::from_utf8(text.contents()).unwrap(),
+            "10 src/main.rs:3 Hello, world!\n20 src/main.rs:4 1.500000 -7\n"
+        );
+        let mut expected = vec![];
+        write_header(&mut expected, 0, ClockKind::Rdtsc, &SITES).unwrap();
+        StreamCompressor::default()
+            .compress_buf(&mut expected, 0, &SITES, &chunk())
+            .unwrap();
+        assert_eq!(compressed.contents(), expected);
+    }
+
+    #[test]
+    fn indexed_file() {
+        use crate::decoder::Decoder;
+        use crate::index::{find_range, read_index};
+
+        let path = std::env::temp_dir().join(format!("nanolog-index-test-{}", std::process::id()));
+        let mut sink = WriterSink::file(&path, Encoding::Compressed).unwrap();
+        sink.index = Some(IndexWriter::open(index_path(&path), 200).unwrap());
+        // two streams with increasing timestamps, three records per chunk
+        for i in 0..100u64 {
+            let mut chunk = vec![];
+            for ts in i * 30..i * 30 + 30 {
+                if ts % 10 == i % 2 {
+                    chunk.extend_from_slice(&0u64.to_ne_bytes());
+                    chunk.extend_from_slice(&ts.to_ne_bytes());
+                }
+            }
+            sink.write_chunk(i % 2, &SITES, &chunk).unwrap();
+        }
+        drop(sink);
+
+        let input = std::fs::read(&path).unwrap();
+        let entries = read_index(index_path(&path)).unwrap();
+        assert!(entries.len() > 10);
+        let mut decoder = Decoder::new(&input).unwrap();
+        let (start, end) = find_range(
+            &entries,
+            decoder.offset() as u64,
+            input.len() as u64,
+            Some(1000),
+            Some(2000),
+        );
+        assert!(start > decoder.offset() as u64 && end < input.len() as u64);
+        decoder.seek(start as usize, end as usize);
+        let timestamps = decoder
+            .map(|r| r.unwrap().timestamp)
+            .filter(|ts| (1000..=2000).contains(ts))
+            .collect::<Vec<_>>();
+        let expected = (1000..=2000)
+            .filter(|ts| ts % 10 == ts / 30 % 2)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, expected);
+
+        std::fs::remove_file(index_path(&path)).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn appended_sessions() {
+        use crate::decoder::Decoder;
+
+        let dir = std::env::temp_dir().join(format!("nanolog-append-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("logs");
+        let timestamps = |path: &Path| {
+            let input = std::fs::read(path).unwrap();
+            Decoder::new(&input)
+                .unwrap()
+                .map(|r| r.unwrap().timestamp)
+                .collect::<Vec<_>>()
+        };
+        // two runs of the same build continue the file
+        for _ in 0..2 {
+            let mut sink = WriterSink::file(&path, Encoding::Compressed).unwrap();
+            sink.write_chunk(0, &SITES, &chunk()).unwrap();
+            sink.flush().unwrap();
+        }
+        assert_eq!(timestamps(&path), [10, 20, 10, 20]);
+
+        // another build (different sites) starts a new file
+        let mut sink = WriterSink::file(&path, Encoding::Compressed).unwrap();
+        let mut other = chunk()[16..].to_vec();
+        other[..8].copy_from_slice(&0u64.to_ne_bytes());
+        sink.write_chunk(0, &SITES[1..], &other).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(timestamps(&path), [20]);
+        assert_eq!(timestamps(&dir.join("logs.1")), [10, 20, 10, 20]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_and_retention() {
+        let dir =
+            std::env::temp_dir().join(format!("nanolog-rotation-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let policy = RotationPolicy {
+            max_bytes: Some(1),
+            keep: Some(2),
+            ..Default::default()
+        };
+        let mut sink = RotatingFileSink::new(dir.join("logs"), policy, Encoding::Text);
+        for _ in 0..4 {
+            sink.write_chunk(0, &SITES, &chunk()).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let files = sink.rotated_files().unwrap();
+        let names = files
+            .iter()
+            .map(|f| f.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 2);
+        assert!(names[0].starts_with("logs.") && names[0].ends_with(".000002"));
+        assert!(names[1].ends_with(".000003"));
+        for file in files {
+            assert!(std::fs::read_to_string(file)
+                .unwrap()
+                .ends_with("1.500000 -7\n"));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shared_prefix() {
+        let dir =
+            std::env::temp_dir().join(format!("nanolog-shared-prefix-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let policy = RotationPolicy {
+            max_bytes: Some(1),
+            ..Default::default()
+        };
+        // e.g. a restart within the same second, or two processes logging to the same place
+        let mut first = RotatingFileSink::new(dir.join("logs"), policy.clone(), Encoding::Text);
+        let mut second = RotatingFileSink::new(dir.join("logs"), policy, Encoding::Text);
+        for _ in 0..2 {
+            first.write_chunk(0, &SITES, &chunk()).unwrap();
+            second.write_chunk(0, &SITES, &chunk()).unwrap();
+        }
+        first.flush().unwrap();
+        second.flush().unwrap();
+
+        let files = first.rotated_files().unwrap();
+        let seqs = files
+            .iter()
+            .map(|f| f.to_str().unwrap().rsplit_once('.').unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, ["000000", "000001", "000002", "000003"]);
+        for file in files {
+            assert!(std::fs::read_to_string(file)
+                .unwrap()
+                .ends_with("1.500000 -7\n"));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_context() {
+        use crate::context::{write_pop, write_push};
+        use crate::decoder::Decoder;
+        use crate::format::write_line;
+
+        let mut sites = SITES.to_vec();
+        sites.extend(CONTEXT_SITES);
+        let hello = |chunk: &mut Vec<u8>, timestamp: u64| {
+            chunk.extend_from_slice(&0u64.to_ne_bytes());
+            chunk.extend_from_slice(&timestamp.to_ne_bytes());
+        };
+        // a scope opened in the chunk that starts the first file and closed in the third
+        let mut chunks = vec![vec![]; 3];
+        write_push(&mut chunks[0], 2, 10, "request", 7.into());
+        hello(&mut chunks[0], 11);
+        hello(&mut chunks[1], 20);
+        write_pop(&mut chunks[2], 2, 30);
+        hello(&mut chunks[2], 31);
+
+        for encoding in [Encoding::Text, Encoding::Compressed] {
+            let dir = std::env::temp_dir().join(format!(
+                "nanolog-rotated-context-test-{}-{encoding:?}",
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let policy = RotationPolicy {
+                max_bytes: Some(1),
+                ..Default::default()
+            };
+            let mut sink = RotatingFileSink::new(dir.join("logs"), policy, encoding);
+            for chunk in chunks.iter() {
+                sink.write_chunk(0, &sites, chunk).unwrap();
+            }
+            sink.flush().unwrap();
+
+            // every file has the context on its own, pushed once
+            let files = sink.rotated_files().unwrap();
+            let lines = files
+                .iter()
+                .map(|file| {
+                    let input = std::fs::read(file).unwrap();
+                    if encoding == Encoding::Text {
+                        return String::from_utf8(input).unwrap();
+                    }
+                    let mut out = vec![];
+                    for r in Decoder::new(&input).unwrap() {
+                        let r = r.unwrap();
+                        let site = &sites[r.log_id as usize];
+                        write_line(&mut out, r.timestamp, None, site, &r.values, &r.context)
+                            .unwrap();
+                    }
+                    String::from_utf8(out).unwrap()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                lines,
+                [
+                    "11 src/main.rs:3 Hello, world! request=7\n",
+                    "20 src/main.rs:3 Hello, world! request=7\n",
+                    "31 src/main.rs:3 Hello, world!\n",
+                ]
+            );
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn gzip_retention() {
+        let dir = std::env::temp_dir().join(format!("nanolog-gzip-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let policy = RotationPolicy {
+            max_bytes: Some(1),
+            keep: Some(2),
+            expiry: Expiry::Gzip { keep: 1 },
+            ..Default::default()
+        };
+        let mut sink = RotatingFileSink::new(dir.join("logs"), policy, Encoding::Text);
+        for _ in 0..5 {
+            sink.write_chunk(0, &SITES, &chunk()).unwrap();
+            while !sink.gzipping.lock().unwrap().is_empty() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        let suffixes = sink
+            .rotated_files()
+            .unwrap()
+            .iter()
+            .map(|f| {
+                f.to_str()
+                    .unwrap()
+                    .split_once(".0000")
+                    .unwrap()
+                    .1
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(suffixes, ["02.gz", "03", "04"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+use crate::compression::ChunkHeader;
+use std::io::{self, Write};
+
+/// thread registrations are written as chunks of this stream, which no ring buffer uses
+pub const THREAD_STREAM: u64 = u64::MAX - 1;
+
+/// the thread a ring buffer belongs to
+/// - registered with the sink (see `Sink::register_thread`) when the log reader picks up the ring
+///   buffer, the stream of the ring buffer is the thread's ID in the output
+/// - written as a chunk of `THREAD_STREAM` holding [stream: u64][tid: u32][name: utf-8] (little
+///   endian), with a record count of 0
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ThreadInfo {
+    /// the OS thread ID
+    pub tid: u32,
+    /// empty for unnamed threads
+    pub name: String,
+}
+
+impl ThreadInfo {
+    /// the OS thread ID and name of the calling thread
+    pub fn current() -> Self {
+        Self {
+            tid: unsafe { libc::gettid() } as u32,
+            name: std::thread::current()
+                .name()
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+
+    /// how the decoder refers to the thread: its name, or the stream for unnamed threads
+    pub fn label(&self, stream: u64) -> String {
+        if self.name.is_empty() {
+            stream.to_string()
+        } else {
+            self.name.clone()
+        }
+    }
+
+    /// the registration of the thread of `stream`, framed as a chunk
+    pub fn write_chunk(&self, stream: u64, out: &mut impl Write) -> io::Result<()> {
+        let mut payload = Vec::with_capacity(12 + self.name.len());
+        payload.extend_from_slice(&stream.to_le_bytes());
+        payload.extend_from_slice(&self.tid.to_le_bytes());
+        payload.extend_from_slice(self.name.as_bytes());
+        ChunkHeader::new(THREAD_STREAM, 0, &payload).write(out)?;
+        out.write_all(&payload)
+    }
+
+    /// the inverse of `write_chunk`, from the chunk's payload, returns the stream and the thread
+    pub fn from_payload(payload: &[u8]) -> io::Result<(u64, Self)> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+        if payload.len() < 12 {
+            return Err(invalid("truncated thread registration"));
+        }
+        let stream = u64::from_le_bytes(payload[..8].try_into().unwrap());
+        let tid = u32::from_le_bytes(payload[8..12].try_into().unwrap());
+        let name = std::str::from_utf8(&payload[12..])
+            .map_err(|_| invalid("thread name isn't utf-8"))?
+            .to_string();
+        Ok((stream, Self { tid, name }))
+    }
+}
+
+#[test]
+fn thread_registration() {
+    let thread = std::thread::Builder::new()
+        .name("T1".to_string())
+        .spawn(ThreadInfo::current)
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(thread.name, "T1");
+    assert_ne!(thread.tid, ThreadInfo::current().tid);
+    assert_eq!(thread.label(3), "T1");
+    assert_eq!(ThreadInfo::default().label(3), "3");
+
+    let mut chunk = vec![];
+    thread.write_chunk(3, &mut chunk).unwrap();
+    let mut input = chunk.as_slice();
+    let header = ChunkHeader::read(&mut input).unwrap();
+    assert_eq!((header.stream, header.records), (THREAD_STREAM, 0));
+    assert!(header.verify(input));
+    assert_eq!(ThreadInfo::from_payload(input).unwrap(), (3, thread));
+    assert!(ThreadInfo::from_payload(&input[..11]).is_err());
+}
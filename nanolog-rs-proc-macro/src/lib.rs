@@ -1,5 +1,3 @@
-use nanolog_rs_common;
-use proc_macro::{self, TokenStream};
 use quote::quote;
 use syn::parse_macro_input;
 
@@ -0,0 +1,484 @@
+/// sets up the `nanolog_internal` module `nanolog!` expands to, with ring buffers of `$rb_size`
+/// bytes, the `$type` wait strategy and records timestamped by `$clock` (one of the
+/// `nanolog_rs_common::clock::Clock` types, `Rdtsc` if omitted)
+/// - must be invoked at the crate root of every binary (or bench) that uses `nanolog!`, the log
+///   sites are generated by build.rs from the `nanolog!` invocations in `src/` and `benches/`
+/// - `tracing_layer` is only there when the invoking crate has a `tracing` feature that enables
+///   `nanolog-rs-common/tracing`, `LogFacade` and `setup_log_facade` when it has a `log` feature
+///   that enables `nanolog-rs-common/log`
+#[macro_export]
+macro_rules! setup_nanolog {
+    ($rb_size:expr,$type:path) => {
+        $crate::setup_nanolog!($rb_size, $type, ::nanolog_rs_common::clock::Rdtsc);
+    };
+    ($rb_size:expr,$type:path,$clock:path) => {
+        // not every binary uses everything
+        #[allow(dead_code)]
+        mod nanolog_internal {
+            include!(concat!(env!("OUT_DIR"), "/source_files.rs"));
+
+            use ::nanolog_rs_common::nanolog_logger::LogReader as _;
+
+            pub type Logger =
+                ::nanolog_rs_common::nanolog_logger::SharedRingBufferWriter<$rb_size, $type>;
+            pub type LogReader =
+                ::nanolog_rs_common::nanolog_logger::SharedRingBufferReader<$rb_size>;
+            pub type LogClock = $clock;
+            const CLOCK: ::nanolog_rs_common::clock::ClockKind =
+                <LogClock as ::nanolog_rs_common::clock::Clock>::KIND;
+
+            /// hands the reader of every new ring buffer (and the thread writing to it) to the
+            /// consumer
+            pub static LOGGER_SENDER: ::std::sync::OnceLock<
+                ::std::sync::mpsc::Sender<(LogReader, ::nanolog_rs_common::thread::ThreadInfo)>,
+            > = ::std::sync::OnceLock::new();
+
+            /// the consumer is shared with the crash handlers so they can drain it
+            pub static LOG_CONSUMER: ::std::sync::Mutex<Option<LogConsumer>> =
+                ::std::sync::Mutex::new(None);
+
+            /// when set, ring buffers are backed by files in this directory
+            pub static RING_DIR: ::std::sync::OnceLock<::std::path::PathBuf> =
+                ::std::sync::OnceLock::new();
+
+            static RING_SEQ: ::std::sync::atomic::AtomicUsize =
+                ::std::sync::atomic::AtomicUsize::new(0);
+
+            /// when set, ring buffers live in shared memory and are consumed by nanolog-collector
+            static SHM_REGISTRY: ::std::sync::OnceLock<
+                ::nanolog_rs_common::shm::SharedMemoryRegistry,
+            > = ::std::sync::OnceLock::new();
+
+            /// the sites registered at runtime (by the tracing layer), they come after `LOG_SITES`
+            pub static SITE_REGISTRY: ::nanolog_rs_common::metadata::SiteRegistry =
+                ::nanolog_rs_common::metadata::SiteRegistry::new(RUNTIME_LOG_ID);
+
+            /// the `sites_checksum` of `LOG_SITES`, so that ring buffer files are only recovered by
+            /// the build that wrote them
+            fn log_sites_checksum() -> u32 {
+                static CHECKSUM: ::std::sync::OnceLock<u32> = ::std::sync::OnceLock::new();
+                *CHECKSUM.get_or_init(|| ::nanolog_rs_common::metadata::sites_checksum(&LOG_SITES))
+            }
+
+            pub fn setup_logger() -> Logger {
+                if let Some(registry) = SHM_REGISTRY.get() {
+                    // the collector attaches to the ring buffer, there is no reader to hand over
+                    return registry
+                        .create_writer()
+                        .expect("failed to create shared memory ring buffer");
+                }
+                let (log_reader, logger) = match RING_DIR.get() {
+                    Some(dir) => {
+                        let seq = RING_SEQ.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed);
+                        let path = ::nanolog_rs_common::nanolog_logger::ring_file_path(dir, seq);
+                        ::nanolog_rs_common::nanolog_logger::create_file_backed_reader_writer_pair(
+                            &path,
+                            log_sites_checksum(),
+                        )
+                        .expect("failed to create file backed ring buffer")
+                    }
+                    None => ::nanolog_rs_common::nanolog_logger::create_reader_writer_pair(),
+                };
+                {
+                    LOGGER_SENDER
+                        .get()
+                        .expect("log reader channel must be set up at init time")
+                        .send((
+                            log_reader,
+                            ::nanolog_rs_common::thread::ThreadInfo::current(),
+                        ))
+                        .unwrap();
+                }
+                logger
+            }
+
+            /// owns the reader side of every ring buffer and the sink the logs are written to
+            pub struct LogConsumer {
+                receiver: ::std::sync::mpsc::Receiver<(
+                    LogReader,
+                    ::nanolog_rs_common::thread::ThreadInfo,
+                )>,
+                readers: Vec<LogReader>,
+                /// `LOG_SITES` followed by the sites registered at runtime so far
+                sites: Vec<::nanolog_rs_common::metadata::LogSite>,
+                sink: Box<dyn ::nanolog_rs_common::sink::Sink>,
+                buf: Box<[u8]>,
+                /// pairs the timestamps with the wall-clock time every once in a while
+                clock: ::nanolog_rs_common::clock::AnchorClock,
+            }
+
+            impl LogConsumer {
+                /// read whatever is available in each ring buffer once
+                /// - picks up the readers of newly set up loggers first, registering their threads
+                ///   with the sink
+                /// - returns the number of bytes read across all ring buffers
+                pub fn poll(&mut self) -> usize {
+                    self.register_readers();
+                    self.read_all(None)
+                }
+
+                fn register_readers(&mut self) {
+                    for (reader, thread) in self.receiver.try_iter() {
+                        self.sink
+                            .register_thread(self.readers.len() as u64, &thread);
+                        self.readers.push(reader);
+                    }
+                }
+
+                /// read each ring buffer once, up to `ends` (by stream) if given
+                fn read_all(&mut self, ends: Option<&[usize]>) -> usize {
+                    if let Some(anchor) = self.clock.poll() {
+                        self.sink.write_anchor(&anchor);
+                    }
+
+                    let mut total = 0;
+                    for (stream, r) in self.readers.iter_mut().enumerate() {
+                        let n = match ends {
+                            Some(ends) => r.read_until(&mut self.buf, ends[stream]),
+                            None => r.read(&mut self.buf),
+                        };
+                        if n > 0 {
+                            // the sites of the records were registered before they were written
+                            SITE_REGISTRY.sync(&mut self.sites);
+                            self.sink
+                                .write_chunk(stream as u64, &self.sites, &self.buf[..n])
+                                .unwrap();
+                            if r.is_persistent() {
+                                self.sink.flush().unwrap();
+                            }
+                            r.commit_read();
+                        }
+                        total += n;
+                    }
+                    total
+                }
+
+                /// read every ring buffer up to where it was when `drain` was called, then flush
+                /// the sink
+                /// - the records written meanwhile are left to the next poll, so draining ends
+                ///   while other threads keep logging
+                pub fn drain(&mut self) {
+                    self.register_readers();
+                    let ends = self.readers.iter().map(|r| r.tail()).collect::<Vec<_>>();
+                    while self.read_all(Some(&ends)) > 0 {}
+                    self.sink.flush().unwrap();
+                }
+            }
+
+            /// must be called once, before any logger is set up
+            /// - calibrates TSC clocks, which takes `nanolog_rs_common::clock::CALIBRATION_TIME`
+            pub fn setup_consumer(mut sink: impl ::nanolog_rs_common::sink::Sink + 'static) {
+                sink.set_clock(CLOCK);
+                let (sender, receiver) = ::std::sync::mpsc::channel();
+                LOGGER_SENDER
+                    .set(sender)
+                    .expect("nanolog consumer must only be set up once");
+                *LOG_CONSUMER.lock().unwrap() = Some(LogConsumer {
+                    receiver,
+                    readers: vec![],
+                    sites: LOG_SITES.to_vec(),
+                    sink: Box::new(sink),
+                    buf: vec![0; $rb_size].into_boxed_slice(),
+                    clock: ::nanolog_rs_common::clock::AnchorClock::calibrate(CLOCK),
+                });
+            }
+
+            /// back the ring buffers of all loggers set up from now on by files in `dir`, so that
+            /// their committed records survive the process being killed
+            pub fn setup_persistent_rings(
+                dir: impl Into<::std::path::PathBuf>,
+            ) -> ::std::io::Result<()> {
+                let dir = dir.into();
+                ::std::fs::create_dir_all(&dir)?;
+                RING_DIR
+                    .set(dir)
+                    .expect("persistent rings must only be set up once");
+                Ok(())
+            }
+
+            /// hand the ring buffers of all loggers set up from now on to nanolog-collector (a
+            /// separate process) instead of consuming them in this process
+            /// - the collector only knows `LOG_SITES`, so this doesn't go with `tracing_layer`
+            pub fn setup_shared_memory_rings() -> ::std::io::Result<()> {
+                let registry =
+                    ::nanolog_rs_common::shm::SharedMemoryRegistry::create(&LOG_SITES, CLOCK)?;
+                if SHM_REGISTRY.set(registry).is_err() {
+                    panic!("shared memory rings must only be set up once");
+                }
+                Ok(())
+            }
+
+            /// write the records left behind in the ring buffers of crashed processes to `sink` and
+            /// delete their files
+            /// - returns the number of ring buffers recovered
+            /// - the ring buffers of a build with other log sites are skipped (and left in place),
+            ///   their records can't be decoded with `LOG_SITES`
+            pub fn recover_orphaned_rings(
+                dir: &::std::path::Path,
+                sink: &mut dyn ::nanolog_rs_common::sink::Sink,
+            ) -> ::std::io::Result<usize> {
+                let rings = ::nanolog_rs_common::nanolog_logger::orphaned_rings(dir)?;
+                if !rings.is_empty() {
+                    sink.set_clock(CLOCK);
+                    // the clocks keep counting across processes (until a reboot)
+                    if let Some(anchor) =
+                        ::nanolog_rs_common::clock::AnchorClock::calibrate(CLOCK).poll()
+                    {
+                        sink.write_anchor(&anchor);
+                    }
+                }
+                let mut recovered = 0;
+                for path in rings.iter() {
+                    let buf = match ::nanolog_rs_common::nanolog_logger::read_orphaned_ring(
+                        path,
+                        log_sites_checksum(),
+                    ) {
+                        Ok(buf) => buf,
+                        Err(e) if e.kind() == ::std::io::ErrorKind::InvalidData => {
+                            eprintln!("skipping ring buffer {}: {e}", path.display());
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    if !buf.is_empty() {
+                        sink.write_chunk(recovered as u64, &LOG_SITES, &buf)?;
+                    }
+                    sink.flush()?;
+                    ::std::fs::remove_file(path)?;
+                    recovered += 1;
+                }
+                Ok(recovered)
+            }
+
+            ::std::thread_local! {
+                /// the ring buffer of the records of each thread that don't come from `nanolog!`
+                /// (the `log` crate facade and the tracing layer), set up with its first record
+                static RUNTIME_LOGGER: ::std::cell::RefCell<Option<Logger>> =
+                    const { ::std::cell::RefCell::new(None) };
+                /// the buffer the `log` crate records are formatted into
+                #[cfg(feature = "log")]
+                static FACADE_BUF: ::std::cell::RefCell<String> =
+                    const { ::std::cell::RefCell::new(String::new()) };
+                /// the context pushed on the thread's loggers, repeated on the runtime logger when
+                /// it's set up
+                #[cfg(any(feature = "log", feature = "tracing"))]
+                static THREAD_CONTEXT: ::std::cell::RefCell<
+                    Vec<(String, ::nanolog_rs_common::record::Value)>,
+                > = const { ::std::cell::RefCell::new(Vec::new()) };
+            }
+
+            /// run `f` with the runtime logger of the calling thread
+            /// - a record written while writing another one (e.g. by a `Debug` impl of one of its
+            ///   arguments) or while the thread exits is dropped
+            fn with_runtime_logger(
+                f: &mut dyn FnMut(&mut dyn ::nanolog_rs_common::nanolog_logger::Logger),
+            ) {
+                let _ = RUNTIME_LOGGER.try_with(|logger| {
+                    if let Ok(mut logger) = logger.try_borrow_mut() {
+                        f(logger.get_or_insert_with(setup_runtime_logger));
+                    }
+                });
+            }
+
+            /// `setup_logger`, with the context pushed on the thread so far
+            fn setup_runtime_logger() -> Logger {
+                let mut logger = setup_logger();
+                #[cfg(any(feature = "log", feature = "tracing"))]
+                let _ = THREAD_CONTEXT.try_with(|context| {
+                    let context = context.borrow();
+                    if !context.is_empty() {
+                        let timestamp = <LogClock as ::nanolog_rs_common::clock::Clock>::now();
+                        ::nanolog_rs_common::context::write_context(
+                            &mut logger,
+                            CONTEXT_LOG_ID,
+                            timestamp,
+                            &context,
+                        );
+                    }
+                });
+                logger
+            }
+
+            /// run `f` with the runtime logger of the calling thread if it has one
+            #[cfg(any(feature = "log", feature = "tracing"))]
+            fn with_existing_runtime_logger(
+                f: impl FnOnce(&mut dyn ::nanolog_rs_common::nanolog_logger::Logger),
+            ) {
+                let _ = RUNTIME_LOGGER.try_with(|logger| {
+                    if let Ok(mut logger) = logger.try_borrow_mut() {
+                        if let Some(logger) = logger.as_mut() {
+                            f(logger);
+                        }
+                    }
+                });
+            }
+
+            /// routes the records of the `log` crate (e.g. of dependencies) into nanolog, see
+            /// `setup_log_facade`
+            #[cfg(feature = "log")]
+            pub struct LogFacade;
+
+            #[cfg(feature = "log")]
+            impl ::nanolog_rs_common::facade::log::Log for LogFacade {
+                fn enabled(&self, _: &::nanolog_rs_common::facade::log::Metadata) -> bool {
+                    true
+                }
+
+                fn log(&self, record: &::nanolog_rs_common::facade::log::Record) {
+                    let timestamp = <LogClock as ::nanolog_rs_common::clock::Clock>::now();
+                    with_runtime_logger(&mut |logger| {
+                        let _ = FACADE_BUF.try_with(|buf| {
+                            ::nanolog_rs_common::facade::write_record(
+                                logger,
+                                FACADE_LOG_ID,
+                                timestamp,
+                                record,
+                                &mut buf.borrow_mut(),
+                            );
+                        });
+                    });
+                }
+
+                fn flush(&self) {}
+            }
+
+            #[cfg(feature = "log")]
+            static LOG_FACADE: LogFacade = LogFacade;
+
+            /// make nanolog the logger of the `log` crate, for the records up to `level`
+            /// - every thread logging through the `log` crate gets a ring buffer of its own with
+            ///   its first record (like `setup_logger`), so the consumer (or the shared memory
+            ///   rings) must be set up first
+            /// - the records are pre-formatted, they cost a lot more than `nanolog!`
+            #[cfg(feature = "log")]
+            pub fn setup_log_facade(
+                level: ::nanolog_rs_common::facade::log::LevelFilter,
+            ) -> Result<(), ::nanolog_rs_common::facade::log::SetLoggerError> {
+                ::nanolog_rs_common::facade::log::set_logger(&LOG_FACADE)?;
+                ::nanolog_rs_common::facade::log::set_max_level(level);
+                Ok(())
+            }
+
+            /// a `tracing_subscriber::Layer` writing the events and the spans of `tracing` to the
+            /// ring buffers, see `nanolog_rs_common::layer::NanologLayer`
+            /// - like the `log` crate facade, threads get a ring buffer with their first record
+            #[cfg(feature = "tracing")]
+            pub fn tracing_layer() -> ::nanolog_rs_common::layer::NanologLayer {
+                assert!(
+                    SHM_REGISTRY.get().is_none(),
+                    "the tracing layer doesn't work with shared memory rings"
+                );
+                ::nanolog_rs_common::layer::NanologLayer::new(
+                    &SITE_REGISTRY,
+                    <LogClock as ::nanolog_rs_common::clock::Clock>::now,
+                    with_runtime_logger,
+                )
+            }
+
+            /// push `key=value` onto the context of the records of `logger`, the decoder attaches
+            /// it to every record written to `logger` until the matching `pop_context`
+            /// - the context is written once, as a record of its own (and repeated wherever
+            ///   decoding can start, see `Encoder::restart_streams`)
+            /// - the records of the `log` crate facade and of the tracing layer on the calling
+            ///   thread get it as well, it's pushed onto the thread's runtime logger too (once it
+            ///   has one, the context so far is repeated when it's set up). So a logger is
+            ///   expected to stay on the thread that pushes onto it
+            pub fn push_context<'a>(
+                logger: &mut Logger,
+                key: &str,
+                value: impl Into<::nanolog_rs_common::context::ContextValue<'a>>,
+            ) {
+                let timestamp = <LogClock as ::nanolog_rs_common::clock::Clock>::now();
+                let value = value.into();
+                ::nanolog_rs_common::context::write_push(
+                    logger,
+                    CONTEXT_LOG_ID,
+                    timestamp,
+                    key,
+                    value,
+                );
+                #[cfg(any(feature = "log", feature = "tracing"))]
+                {
+                    let _ = THREAD_CONTEXT.try_with(|context| {
+                        context.borrow_mut().push((key.to_string(), value.into()));
+                    });
+                    with_existing_runtime_logger(|logger| {
+                        ::nanolog_rs_common::context::write_push(
+                            logger,
+                            CONTEXT_LOG_ID,
+                            timestamp,
+                            key,
+                            value,
+                        );
+                    });
+                }
+            }
+
+            /// pop the most recently pushed entry off the context of `logger` (and of the
+            /// thread's runtime logger)
+            pub fn pop_context(logger: &mut Logger) {
+                let timestamp = <LogClock as ::nanolog_rs_common::clock::Clock>::now();
+                ::nanolog_rs_common::context::write_pop(logger, CONTEXT_LOG_ID, timestamp);
+                #[cfg(any(feature = "log", feature = "tracing"))]
+                {
+                    let _ = THREAD_CONTEXT.try_with(|context| context.borrow_mut().pop());
+                    with_existing_runtime_logger(|logger| {
+                        ::nanolog_rs_common::context::write_pop(logger, CONTEXT_LOG_ID, timestamp);
+                    });
+                }
+            }
+
+            /// pops the entry `with_context` pushed, also when `f` panics
+            struct PopContext<'a>(&'a mut Logger);
+
+            impl Drop for PopContext<'_> {
+                fn drop(&mut self) {
+                    pop_context(self.0);
+                }
+            }
+
+            /// run `f` with `key=value` pushed onto the context of `logger`
+            pub fn with_context<'a, R>(
+                logger: &mut Logger,
+                key: &str,
+                value: impl Into<::nanolog_rs_common::context::ContextValue<'a>>,
+                f: impl FnOnce(&mut Logger) -> R,
+            ) -> R {
+                push_context(logger, key, value);
+                let guard = PopContext(logger);
+                f(guard.0)
+            }
+
+            fn drain_on_crash() {
+                // the consumer thread only holds the lock for a single poll, which takes well under
+                // a second. If it holds it for longer (e.g. it is the thread that panicked while
+                // holding it), give up rather than hang the dying process
+                let deadline = ::std::time::Instant::now() + ::std::time::Duration::from_secs(1);
+                while ::std::time::Instant::now() < deadline {
+                    let mut consumer = match LOG_CONSUMER.try_lock() {
+                        Ok(consumer) => consumer,
+                        Err(::std::sync::TryLockError::Poisoned(p)) => p.into_inner(),
+                        Err(::std::sync::TryLockError::WouldBlock) => {
+                            ::std::thread::sleep(::std::time::Duration::from_millis(1));
+                            continue;
+                        }
+                    };
+                    if let Some(consumer) = consumer.as_mut() {
+                        consumer.drain();
+                    }
+                    return;
+                }
+            }
+
+            /// drain all ring buffers and flush the output when a thread panics or the process
+            /// receives SIGTERM or SIGINT
+            /// - the drain on a signal runs on a separate thread, not in the signal handler
+            /// - SIGSEGV, SIGBUS and SIGABRT aren't handled. With `setup_persistent_rings` the
+            ///   records committed before them are recovered on the next start
+            pub fn install_crash_handlers() -> ::std::io::Result<()> {
+                ::nanolog_rs_common::crash::install_panic_hook(drain_on_crash);
+                ::nanolog_rs_common::crash::install_signal_handlers(drain_on_crash)
+            }
+        }
+    };
+}
@@ -1,36 +1,11 @@
-use nanolog_rs_common::nanolog_logger::LogReader;
+use nanolog_rs::setup_nanolog;
+#[cfg(feature = "log")]
+use nanolog_rs_common::facade::log;
+use nanolog_rs_common::sink::{Encoding, WriterSink};
 use nanolog_rs_proc_macro::nanolog;
-use std::time::Duration;
 
-macro_rules! setup_nanolog {
-    ($rb_size:expr,$type:path) => {
-        mod nanolog_internal {
-            include!(concat!(env!("OUT_DIR"), "/source_files.rs"));
-
-            pub type Logger =
-                ::nanolog_rs_common::nanolog_logger::SharedRingBufferWriter<$rb_size, $type>;
-            pub type LogReader =
-                ::nanolog_rs_common::nanolog_logger::SharedRingBufferReader<$rb_size>;
-
-            pub static LOGGER_SENDER: ::std::sync::OnceLock<::std::sync::mpsc::Sender<LogReader>> =
-                ::std::sync::OnceLock::new();
-
-            pub fn setup_logger() -> Logger {
-                let (log_reader, logger) =
-                    ::nanolog_rs_common::nanolog_logger::create_reader_writer_pair();
-                {
-                    LOGGER_SENDER
-                        .get()
-                        .expect("log reader channel must be set up at init time")
-                        .send(log_reader)
-                        .unwrap();
-                }
-                logger
-            }
-        }
-    };
-}
 const RINGBUF_SIZE: usize = 1024 * 1024;
+const RING_DIR: &str = "/dev/shm/nanolog-rs";
 
 setup_nanolog!(
     { crate::RINGBUF_SIZE },
@@ -54,23 +29,43 @@ pub fn create_thread(
 
 fn main() {
     // startup code
-    let start_instant = std::time::Instant::now();
-    let (logger_sender, logger_receiver) = std::sync::mpsc::channel();
-    nanolog_internal::LOGGER_SENDER.set(logger_sender).unwrap();
+    // with NANOLOG_COLLECTOR set, the logs are consumed by a separately running nanolog-collector
+    let collector = std::env::var_os("NANOLOG_COLLECTOR").is_some();
+    if collector {
+        nanolog_internal::setup_shared_memory_rings().unwrap();
+    } else {
+        // continues the file of earlier runs of the same build
+        let mut sink = WriterSink::file("logs", Encoding::Compressed).unwrap();
+
+        let ring_dir = std::path::Path::new(RING_DIR);
+        if ring_dir.is_dir() {
+            let n = nanolog_internal::recover_orphaned_rings(ring_dir, &mut sink).unwrap();
+            println!("recovered {n} ring buffers");
+        }
+        nanolog_internal::setup_persistent_rings(ring_dir).unwrap();
+        nanolog_internal::setup_consumer(sink);
+        nanolog_internal::install_crash_handlers().unwrap();
+    }
+    // the logs of dependencies end up in the same file
+    #[cfg(feature = "log")]
+    {
+        nanolog_internal::setup_log_facade(log::LevelFilter::Info).unwrap();
+        log::info!("logging {} records per thread", 100_000_000);
+    }
 
     let t1 = create_thread("T1", vec![13], |mut logger| {
         let a = 1.1;
-        let b = 1;
+        // tags every record of the thread, the decoder appends it as session=1
+        nanolog_internal::push_context(&mut logger, "session", 1);
 
         for x in 0..100_000_000 {
-            nanolog!(&mut logger, "[T1] Hello, world!");
-            // nanolog!(&mut logger, "[T1] Hello, world! %f %d", a, x);
+            nanolog!(&mut logger, "Hello, world! %f %d", a, x);
+            // nanolog!(&mut logger, "Hello, world!");
             // std::thread::sleep(std::time::Duration::from_nanos(1))
         }
     });
     let t2 = create_thread("T2", vec![14], |mut logger| {
         let a = 1.1;
-        let b = 1;
 
         // TODO:
         // Further improvements:
@@ -80,37 +75,37 @@ fn main() {
         //   - tokio::main equivalent
 
         for x in 0..100_000_000 {
-            nanolog!(&mut logger, "[T2] Hello, world!");
-            // nanolog!(&mut logger, "[T2] Hello, world! %f %d", a, x);
+            // named fields, decoded as key value pairs (see nanolog-decoder --format)
+            nanolog!(&mut logger, "order filled", id = x, px: f64 = a, seq: u64 = x);
             // std::thread::sleep(std::time::Duration::from_nanos(1))
         }
     });
     ::affinity::set_thread_affinity([15]).unwrap();
 
-    let mut readers = vec![];
-    let mut log_reader_buf = [0; RINGBUF_SIZE];
-
-    const NUM_THREADS: usize = 2;
-    // 2 threads
-    for _ in 0..NUM_THREADS {
-        readers.push(logger_receiver.recv().unwrap());
-        println!("new!");
-    }
-
-    let mut out =
-        std::io::BufWriter::with_capacity(4 * RINGBUF_SIZE, std::fs::File::create("logs").unwrap());
     let start = std::time::Instant::now();
+    if collector {
+        t1.join().unwrap();
+        t2.join().unwrap();
+        println!("{:?}", std::time::Instant::now().duration_since(start));
+        return;
+    }
     loop {
-        for r in readers.iter_mut() {
-            let n = r.read(&mut log_reader_buf);
-            if n > 0 {
-                nanolog_internal::decode_buf(&mut out, &start_instant, &log_reader_buf[..n]);
-            }
-        }
+        nanolog_internal::LOG_CONSUMER
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .poll();
         if t1.is_finished() || t2.is_finished() {
             println!("problem!");
             break;
         }
     }
+    nanolog_internal::LOG_CONSUMER
+        .lock()
+        .unwrap()
+        .as_mut()
+        .unwrap()
+        .drain();
     println!("{:?}", std::time::Instant::now().duration_since(start));
 }